@@ -0,0 +1,49 @@
+use crate::error::Result;
+use crate::qbit::{InterfaceBinding, PortStatus, PortUpdateResult, QbitClient};
+use async_trait::async_trait;
+
+/// A torrent client the forwarded port is pushed to. The daemon loops only
+/// go through this trait, so every backend shares them.
+#[async_trait]
+pub trait TorrentClient: Send + Sync {
+    /// Sets the listen port, binding to `bind_interface` where supported,
+    /// and reads it back to verify the change.
+    async fn set_listen_port(
+        &self,
+        port: u16,
+        bind_interface: Option<&str>,
+    ) -> Result<PortUpdateResult>;
+
+    /// Reads the listen port with the `random_port` and `upnp` flags.
+    async fn get_port_status(&self) -> Result<PortStatus>;
+
+    /// Pins the client to `interface` without touching the listen port.
+    async fn apply_interface_binding(&self, interface: &str) -> Result<InterfaceBinding>;
+
+    /// Restricts the client to `interface` while the VPN tunnel is down,
+    /// returning the interface actually bound.
+    async fn apply_safe_binding(&self, interface: &str) -> Result<String>;
+}
+
+#[async_trait]
+impl TorrentClient for QbitClient {
+    async fn set_listen_port(
+        &self,
+        port: u16,
+        bind_interface: Option<&str>,
+    ) -> Result<PortUpdateResult> {
+        QbitClient::set_listen_port(self, port, bind_interface).await
+    }
+
+    async fn get_port_status(&self) -> Result<PortStatus> {
+        QbitClient::get_port_status(self).await
+    }
+
+    async fn apply_interface_binding(&self, interface: &str) -> Result<InterfaceBinding> {
+        QbitClient::apply_interface_binding(self, interface).await
+    }
+
+    async fn apply_safe_binding(&self, interface: &str) -> Result<String> {
+        QbitClient::apply_safe_binding(self, interface).await
+    }
+}
@@ -0,0 +1,297 @@
+#[cfg(feature = "metrics")]
+use crate::metrics_server::metric_name;
+use std::collections::VecDeque;
+use std::future;
+use std::time::Duration;
+use tokio::time::{self, Instant};
+use tracing::{debug, warn};
+
+/// Rate-limits applies to qBittorrent shared by both daemon strategies.
+/// Applies closer together than `min_interval` are deferred, and ports that
+/// arrive while waiting replace the pending one so only the latest is sent.
+#[derive(Debug)]
+pub struct ApplyCoalescer {
+    min_interval: Duration,
+    last_apply: Option<Instant>,
+    /// Port waiting for the minimum interval to pass.
+    pending: Option<u16>,
+    coalesced: u64,
+    dropped: u64,
+}
+
+impl ApplyCoalescer {
+    pub fn new(min_interval: Duration) -> Self {
+        ApplyCoalescer {
+            min_interval,
+            last_apply: None,
+            pending: None,
+            coalesced: 0,
+            dropped: 0,
+        }
+    }
+
+    /// Queues `port` for [`next_port`](Self::next_port), replacing a port
+    /// still waiting for the minimum interval to pass.
+    pub fn push(&mut self, port: u16) {
+        match self.pending.replace(port) {
+            Some(previous) => self.record_dropped(previous, port),
+            None if self.deadline().is_some() => {
+                self.coalesced += 1;
+                #[cfg(feature = "metrics")]
+                metrics::counter!(metric_name("applies_coalesced_total")).increment(1);
+                debug!("deferring apply of port {port} until the minimum interval has passed");
+            }
+            None => {}
+        }
+    }
+
+    /// Waits out the rest of the minimum interval since the previous apply and
+    /// returns the queued port; the apply is counted as starting on return.
+    /// Never resolves while nothing is queued. Cancel-safe, so it can be a
+    /// `select!` branch next to the channel feeding [`push`](Self::push).
+    pub async fn next_port(&mut self) -> u16 {
+        let Some(port) = self.pending else {
+            return future::pending().await;
+        };
+        if let Some(deadline) = self.deadline() {
+            time::sleep_until(deadline).await;
+        }
+        self.pending = None;
+        self.last_apply = Some(Instant::now());
+        port
+    }
+
+    /// Queues `port` and waits until it may be applied.
+    pub async fn apply_port_coalesced(&mut self, port: u16) -> u16 {
+        self.push(port);
+        self.next_port().await
+    }
+
+    /// When the minimum interval since the previous apply ends, if it has not
+    /// yet.
+    fn deadline(&self) -> Option<Instant> {
+        self.last_apply
+            .map(|last| last + self.min_interval)
+            .filter(|&deadline| Instant::now() < deadline)
+    }
+
+    /// Applies deferred because they came too soon after the previous one.
+    #[cfg(test)]
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced
+    }
+
+    /// Requested ports superseded by a newer one before being applied.
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn record_dropped(&mut self, port: u16, next: u16) {
+        self.dropped += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!(metric_name("applies_dropped_total")).increment(1);
+        debug!("port {port} superseded by {next} before it was applied");
+    }
+}
+
+/// Anti-flap guard: refuses to go back to one of the last `capacity` ports
+/// within `window` of it being replaced, for gateways that briefly hand back
+/// an old port while a VPN reconnects.
+#[derive(Debug)]
+pub struct FlapGuard {
+    capacity: usize,
+    window: Duration,
+    current: Option<u16>,
+    /// Previously applied ports with the time they were replaced, newest first.
+    replaced: VecDeque<(u16, Instant)>,
+}
+
+impl FlapGuard {
+    /// A `capacity` of 0 disables the guard.
+    pub fn new(capacity: usize, window: Duration) -> Self {
+        FlapGuard {
+            capacity,
+            window,
+            current: None,
+            replaced: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Returns `true`, logging it, when `port` must not be applied because it
+    /// was replaced less than `window` ago. The current port never is.
+    pub fn suppresses(&self, port: u16) -> bool {
+        if self.current == Some(port) {
+            return false;
+        }
+        let recently_replaced = self
+            .replaced
+            .iter()
+            .any(|&(old, at)| old == port && at.elapsed() < self.window);
+        if recently_replaced {
+            warn!(
+                "ignoring port {port}: it was replaced less than {:?} ago (anti-flap guard)",
+                self.window
+            );
+            #[cfg(feature = "metrics")]
+            metrics::counter!(metric_name("flap_suppressed_total")).increment(1);
+        }
+        recently_replaced
+    }
+
+    /// Records `port` as the port now applied.
+    pub fn record_applied(&mut self, port: u16) {
+        if self.capacity == 0 || self.current == Some(port) {
+            self.current = Some(port);
+            return;
+        }
+        self.replaced.retain(|&(old, _)| old != port);
+        if let Some(previous) = self.current.replace(port) {
+            self.replaced.push_front((previous, Instant::now()));
+            self.replaced.truncate(self.capacity);
+        }
+    }
+}
+
+/// Notices another program changing the listen port: a port read before an
+/// update or read back after it that is neither the port being set nor the
+/// one set the cycle before. Nothing is reported until a port has been
+/// verified once.
+#[derive(Debug, Default)]
+pub struct ExternalChangeDetector {
+    last_applied: Option<u16>,
+}
+
+impl ExternalChangeDetector {
+    /// Checks the `previous` port read before setting `requested` and the
+    /// `detected` port read back afterwards, returning `true`, logging it,
+    /// when either is a port this tool never set.
+    pub fn observe(&mut self, requested: u16, previous: Option<u16>, detected: u16) -> bool {
+        // Still showing the last port means nothing else touched it; after
+        // the update that means qBittorrent did not take the new one, which
+        // verification already reports.
+        let last = self.last_applied;
+        let foreign = |port: u16| port != requested && last.is_some_and(|last| last != port);
+        if detected == requested {
+            self.last_applied = Some(requested);
+        }
+        let changed = match previous {
+            Some(port) if foreign(port) => {
+                warn!("external modification detected: port changed to {port} outside this tool since the last update");
+                true
+            }
+            _ if foreign(detected) => {
+                warn!(
+                    "external modification detected: port changed to {detected} outside this tool"
+                );
+                true
+            }
+            _ => false,
+        };
+        #[cfg(feature = "metrics")]
+        if changed {
+            metrics::counter!(metric_name("external_changes_total")).increment(1);
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApplyCoalescer, ExternalChangeDetector, FlapGuard};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn first_apply_is_immediate() {
+        let mut coalescer = ApplyCoalescer::new(Duration::from_secs(5));
+        assert_eq!(coalescer.apply_port_coalesced(51820).await, 51820);
+        assert_eq!(coalescer.coalesced(), 0);
+    }
+
+    #[tokio::test]
+    async fn rapid_triggers_apply_only_the_latest_port() {
+        let mut coalescer = ApplyCoalescer::new(Duration::from_millis(100));
+        coalescer.apply_port_coalesced(51820).await;
+
+        let start = tokio::time::Instant::now();
+        coalescer.push(51821);
+        coalescer.push(51822);
+        coalescer.push(51823);
+        assert_eq!(coalescer.next_port().await, 51823);
+        assert!(start.elapsed() >= Duration::from_millis(90));
+        assert_eq!(coalescer.coalesced(), 1);
+        assert_eq!(coalescer.dropped(), 2);
+    }
+
+    #[tokio::test]
+    async fn waiting_can_be_cancelled_without_losing_the_port() {
+        let mut coalescer = ApplyCoalescer::new(Duration::from_millis(100));
+        coalescer.apply_port_coalesced(51820).await;
+
+        coalescer.push(51821);
+        let cancelled =
+            tokio::time::timeout(Duration::from_millis(10), coalescer.next_port()).await;
+        assert!(cancelled.is_err());
+        assert_eq!(coalescer.next_port().await, 51821);
+    }
+
+    #[tokio::test]
+    async fn zero_interval_never_defers() {
+        let mut coalescer = ApplyCoalescer::new(Duration::ZERO);
+        coalescer.apply_port_coalesced(51820).await;
+        coalescer.apply_port_coalesced(51821).await;
+        assert_eq!(coalescer.coalesced(), 0);
+    }
+
+    #[test]
+    fn flap_guard_suppresses_recently_replaced_ports() {
+        let mut guard = FlapGuard::new(2, Duration::from_secs(60));
+        guard.record_applied(51820);
+        guard.record_applied(51821);
+        assert!(!guard.suppresses(51821));
+        assert!(guard.suppresses(51820));
+        assert!(!guard.suppresses(51822));
+    }
+
+    #[test]
+    fn external_changes_need_a_port_this_tool_never_set() {
+        let mut detector = ExternalChangeDetector::default();
+        assert!(
+            !detector.observe(51820, None, 40000),
+            "nothing verified yet"
+        );
+        assert!(!detector.observe(51820, None, 51820));
+        assert!(!detector.observe(51821, None, 51820), "previous port kept");
+        assert!(detector.observe(51821, None, 40000));
+        assert!(!detector.observe(51821, None, 51821));
+    }
+
+    #[test]
+    fn external_changes_between_cycles_are_detected() {
+        let mut detector = ExternalChangeDetector::default();
+        assert!(
+            !detector.observe(51820, Some(6881), 51820),
+            "nothing verified yet"
+        );
+        assert!(!detector.observe(51820, Some(51820), 51820));
+        // Another program set 40000 before the next cycle put ours back.
+        assert!(detector.observe(51820, Some(40000), 51820));
+        assert!(
+            !detector.observe(51821, Some(51820), 51821),
+            "our own change"
+        );
+    }
+
+    #[test]
+    fn flap_guard_allows_old_ports_after_the_window() {
+        let mut guard = FlapGuard::new(2, Duration::ZERO);
+        guard.record_applied(51820);
+        guard.record_applied(51821);
+        assert!(!guard.suppresses(51820));
+
+        let mut disabled = FlapGuard::new(0, Duration::from_secs(60));
+        disabled.record_applied(51820);
+        disabled.record_applied(51821);
+        assert!(!disabled.suppresses(51820));
+    }
+}
@@ -1,43 +1,272 @@
 use crate::error::{ConfigError, Result};
 #[cfg(target_os = "linux")]
 use directories::BaseDirs;
-use serde::Deserialize;
+use regex_automata::meta::Regex;
+use reqwest::header::{self, HeaderMap, HeaderName, HeaderValue};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 use std::{
-    env, fs,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env, fmt, fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     path::{Path, PathBuf},
+    time::Duration,
 };
 use tracing::debug;
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
-    pub qbittorrent: QbittorrentConfig,
+    #[serde(default)]
+    pub client: ClientConfig,
+    /// Required unless `client.kind` selects another client.
+    #[serde(default, deserialize_with = "one_or_many_instances")]
+    pub qbittorrent: Vec<QbittorrentConfig>,
+    #[serde(default)]
+    pub deluge: Option<DelugeConfig>,
+    #[serde(default)]
+    pub transmission: Option<TransmissionConfig>,
     pub protonvpn: ProtonVpnConfig,
     pub portmap: PortMapConfig,
     #[serde(default)]
     pub net: NetConfig,
     #[serde(default)]
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
     pub metrics: MetricsConfig,
     #[serde(default)]
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
     pub health: HealthConfig,
+    #[serde(default)]
+    pub events: EventsConfig,
+    #[serde(default)]
+    pub daemon: DaemonConfig,
+    #[serde(default)]
+    pub post_change_hooks: Vec<HookConfig>,
     #[serde(skip)]
     source: Option<PathBuf>,
+    #[serde(skip)]
+    sources: BTreeMap<String, ValueSource>,
+    #[serde(skip)]
+    profile: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub kind: ClientKind,
+}
+
+/// Torrent client the forwarded port is applied to.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientKind {
+    /// Every `[qbittorrent]` instance, through the Web API.
+    #[default]
+    Qbittorrent,
+    /// The `[deluge]` Web UI, through its JSON-RPC endpoint.
+    Deluge,
+    /// The `[transmission]` daemon, through its RPC endpoint.
+    Transmission,
+}
+
+impl ClientKind {
+    /// Name used in config keys, logs and reports.
+    pub fn label(self) -> &'static str {
+        match self {
+            ClientKind::Qbittorrent => "qbittorrent",
+            ClientKind::Deluge => "deluge",
+            ClientKind::Transmission => "transmission",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct QbittorrentConfig {
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub name: Option<String>,
+    /// Left empty to build the URL from the service env vars below.
+    #[serde(default)]
     pub base_url: String,
     pub username: String,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        serialize_with = "redacted"
+    )]
+    pub password: Option<String>,
+    /// Extra port-type preferences set to the same port as `listen_port`.
+    #[serde(default)]
+    pub also_set_keys: Vec<String>,
+    /// Per-request HTTP timeout; 0 disables it.
+    #[serde(default = "QbittorrentConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub http_version: HttpVersion,
+    /// PEM file with an extra CA trusted for HTTPS, e.g. a private CA in
+    /// front of a reverse proxy.
+    #[serde(default, deserialize_with = "empty_string_as_none_path")]
+    pub tls_ca_cert: Option<PathBuf>,
+    /// Skip certificate verification entirely; only for explicit opt-in.
+    #[serde(default)]
+    pub tls_accept_invalid_certs: bool,
+    /// Login attempts repeated on connection errors and 5xx responses.
+    #[serde(default = "QbittorrentConfig::default_login_retries")]
+    pub login_retries: u32,
+    /// Delay before the first login retry, doubled for each later one.
+    #[serde(default = "QbittorrentConfig::default_login_retry_delay_secs")]
+    pub login_retry_delay_secs: u64,
+    /// How long logins are held back once qBittorrent has banned this
+    /// address; match qBittorrent's own ban duration.
+    #[serde(default = "QbittorrentConfig::default_ban_cooldown_secs")]
+    pub ban_cooldown_secs: u64,
+    /// Force `random_port` and `upnp` off again when qBittorrent still
+    /// reports them enabled after an update.
+    #[serde(default = "QbittorrentConfig::default_enforce_port_settings")]
+    pub enforce_port_settings: bool,
+    /// Send `random_port = false` with every update; when off, qBittorrent's
+    /// own setting is left alone.
+    #[serde(default = "QbittorrentConfig::default_manage_random_port")]
+    pub manage_random_port: bool,
+    /// Send `upnp = false` with every update; when off, qBittorrent's own
+    /// setting is left alone, e.g. to keep UPnP for the LAN.
+    #[serde(default = "QbittorrentConfig::default_manage_upnp")]
+    pub manage_upnp: bool,
+    /// After a verified update, ask `reachability_check_url` whether the
+    /// port accepts connections from outside.
+    #[serde(default)]
+    pub verify_reachable: bool,
+    /// External port checker for `verify_reachable`, with a `{port}`
+    /// placeholder. It must answer `1` for an open port and `0` otherwise.
+    #[serde(default = "QbittorrentConfig::default_reachability_check_url")]
+    pub reachability_check_url: String,
+    /// Skip updates while `net.bind_interface` is missing from qBittorrent's
+    /// interface list, so a port is not applied with the VPN down.
+    #[serde(default)]
+    pub only_if_interface_present: bool,
+    /// Replaces the default `qb-port-sync` User-Agent, for proxies that
+    /// filter on it.
     #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub user_agent: Option<String>,
+    /// Headers sent with every request, e.g. a reverse proxy's own
+    /// `Authorization`. Values are redacted when the config is printed.
+    #[serde(default, serialize_with = "redacted_headers")]
+    pub extra_headers: Option<HashMap<String, String>>,
+    #[serde(default = "QbittorrentConfig::default_service_host_env")]
+    pub service_host_env: String,
+    #[serde(default = "QbittorrentConfig::default_service_port_env")]
+    pub service_port_env: String,
+}
+
+/// Deluge Web UI used when `client.kind = "deluge"`. The Web UI must be
+/// connected, or able to connect, to a Deluge daemon.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DelugeConfig {
+    #[serde(default = "DelugeConfig::default_base_url")]
+    pub base_url: String,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        serialize_with = "redacted"
+    )]
     pub password: Option<String>,
+    /// Per-request HTTP timeout; 0 disables it.
+    #[serde(default = "DelugeConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+/// Transmission RPC endpoint used when `client.kind = "transmission"`.
+/// Credentials are only needed when RPC authentication is enabled.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TransmissionConfig {
+    #[serde(default = "TransmissionConfig::default_base_url")]
+    pub base_url: String,
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub username: Option<String>,
+    #[serde(
+        default,
+        deserialize_with = "empty_string_as_none",
+        serialize_with = "redacted"
+    )]
+    pub password: Option<String>,
+    /// Per-request HTTP timeout; 0 disables it.
+    #[serde(default = "TransmissionConfig::default_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ProtonVpnConfig {
     #[serde(default, deserialize_with = "empty_string_as_none_path")]
     pub forwarded_port_path: Option<PathBuf>,
+    /// Further candidate locations, tried after `forwarded_port_path` and
+    /// before the ProtonVPN default; the first that exists is used.
+    #[serde(default)]
+    pub forwarded_port_paths: Vec<PathBuf>,
+    #[serde(default)]
+    pub port_file_format: PortFileFormat,
+    #[serde(default)]
+    pub watch_mode: WatchMode,
+    #[serde(default = "ProtonVpnConfig::default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// How long the watcher waits for the existing file to be read before
+    /// arming without it.
+    #[serde(default = "ProtonVpnConfig::default_initial_read_timeout_secs")]
+    pub initial_read_timeout_secs: u64,
+    /// Larger forwarded-port files are refused instead of read; a valid one
+    /// is only a few bytes.
+    #[serde(default = "ProtonVpnConfig::default_max_file_bytes")]
+    pub max_file_bytes: u64,
+    /// Apply the port already in the file when the daemon starts; when off,
+    /// only later changes are applied.
+    #[serde(default = "ProtonVpnConfig::default_apply_on_start")]
+    pub apply_on_start: bool,
+    /// Wait after a change notification before reading the file, so the
+    /// writer can finish; an unparseable read is retried once after it.
+    #[serde(default = "ProtonVpnConfig::default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Control-server endpoint polled by the `http` strategy, e.g. Gluetun's
+    /// `http://127.0.0.1:8000/v1/openvpn/portforwarded`.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub control_server_url: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// How the file strategy notices changes to the forwarded-port file.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchMode {
+    /// Filesystem notifications (inotify, FSEvents, ...).
+    #[default]
+    Inotify,
+    /// Re-read the file every `poll_interval_secs`, for filesystems that
+    /// never deliver notifications.
+    Poll,
+}
+
+/// HTTP version used to talk to qBittorrent (or a proxy in front of it).
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HttpVersion {
+    /// HTTP/1.1, or HTTP/2 when negotiated via ALPN over HTTPS.
+    #[default]
+    Auto,
+    /// HTTP/1.1 only, for proxies that mis-negotiate HTTP/2.
+    Http1,
+    /// HTTP/2 without negotiation, for h2c endpoints.
+    Http2,
+}
+
+/// How the forwarded-port file is parsed.
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PortFileFormat {
+    /// Bare integer first, then a JSON object with a `port` field.
+    #[default]
+    Auto,
+    /// Bare integer only.
+    Plain,
+    /// JSON object with a `port` field only (e.g. Gluetun).
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PortMapConfig {
     #[serde(default)]
     pub internal_port: u16,
@@ -45,35 +274,170 @@ pub struct PortMapConfig {
     pub protocol: PortProtocol,
     #[serde(default = "PortMapConfig::default_refresh_secs")]
     pub refresh_secs: u64,
+    /// Mapping lifetime requested from the gateway; unset requests
+    /// `refresh_secs`. When set, `refresh_secs` still caps the cycle delay.
+    #[serde(default)]
+    pub lease_secs: Option<u64>,
+    /// Floor for the TTL-derived refresh delay.
+    #[serde(default = "PortMapConfig::default_min_refresh_secs")]
+    pub min_refresh_secs: u64,
+    /// Ceiling for the TTL-derived refresh delay.
+    #[serde(default = "PortMapConfig::default_max_refresh_secs")]
+    pub max_refresh_secs: u64,
+    /// Longest retry delay after consecutive failed cycles; the delay starts
+    /// at `refresh_secs` and doubles per failure up to this.
+    #[serde(default = "PortMapConfig::default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
     #[serde(default = "PortMapConfig::default_autodiscover")]
     pub autodiscover_gateway: bool,
+    /// How long the daemon reuses an autodiscovered gateway before looking
+    /// again; 0 keeps it until a mapping through it fails.
+    #[serde(default)]
+    pub gateway_cache_secs: u64,
+    /// Gateway addresses tried in order until one grants a mapping; a
+    /// single string is accepted too.
+    #[serde(default, deserialize_with = "one_or_many_strings")]
+    pub gateway: Vec<String>,
+    /// Local address PCP requests are sent on behalf of; unset finds the
+    /// address the route to the gateway uses.
     #[serde(default, deserialize_with = "empty_string_as_none")]
-    pub gateway: Option<String>,
+    pub client_ip: Option<String>,
+    /// Extra PCP/NAT-PMP attempts per cycle, with exponential backoff.
+    #[serde(default = "PortMapConfig::default_max_retries")]
+    pub max_retries: u32,
+    /// Up to this many random seconds are added to each refresh delay so a
+    /// fleet started together drifts apart; 0 disables it.
+    #[serde(default)]
+    pub refresh_jitter_secs: u64,
+    /// Query the gateway's public IPv4 address each cycle (NAT-PMP opcode 0).
+    #[serde(default)]
+    pub external_ip: bool,
+    /// External ports a mapping may hand back; any other fails the cycle
+    /// instead of reaching qBittorrent.
+    #[serde(default)]
+    pub acceptable_range: PortRange,
+    /// Fail the cycle when the gateway grants an external port other than
+    /// the nonzero `internal_port` asked for, instead of applying it.
+    #[serde(default)]
+    pub require_exact_external: bool,
+}
+
+/// Inclusive range of port numbers.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PortRange {
+    #[serde(default = "PortRange::default_min")]
+    pub min: u16,
+    #[serde(default = "PortRange::default_max")]
+    pub max: u16,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct NetConfig {
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub bind_interface: Option<String>,
+    #[serde(default = "NetConfig::default_retry_interface_id")]
+    pub retry_interface_id: bool,
+    #[serde(default)]
+    pub leak_protect_on_startup: bool,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
 pub struct MetricsConfig {
     #[serde(default)]
     pub enabled: bool,
     #[serde(default)]
     pub port: u16,
+    /// Prefix of every metric name, joined to the rest with `_`.
+    #[serde(default = "MetricsConfig::default_namespace")]
+    pub namespace: String,
+    /// Address the metrics/health server listens on; all IPv4 addresses
+    /// when unset.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub bind_address: Option<String>,
+    /// File the final metrics are written to when the daemon shuts down, in
+    /// the node_exporter textfile-collector format.
+    #[serde(default, deserialize_with = "empty_string_as_none_path")]
+    pub textfile_path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default)]
+#[cfg_attr(not(feature = "metrics"), allow(dead_code))]
 pub struct HealthConfig {
     #[serde(default)]
     pub enabled: bool,
     #[serde(default)]
     pub port: u16,
+    /// Serve only `/healthz` and `/readyz`, without the Prometheus recorder.
+    #[serde(default)]
+    pub standalone: bool,
+    /// Report unready when the last port update is older than this. Unset
+    /// means twice `portmap.refresh_secs` for the port-mapping daemon and
+    /// no limit for the file daemon, which only updates on change; 0
+    /// disables the check.
+    #[serde(default)]
+    pub max_staleness_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EventsConfig {
+    #[serde(default = "EventsConfig::default_capacity")]
+    pub capacity: usize,
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DaemonConfig {
+    /// Exit after this many failed cycles in a row so a supervisor can
+    /// restart the process; 0 keeps retrying forever.
+    #[serde(default)]
+    pub max_consecutive_failures: u32,
+    /// Minimum seconds between applies to qBittorrent; triggers arriving
+    /// sooner are coalesced into one apply of the latest port. 0 disables it.
+    #[serde(default)]
+    pub min_apply_interval_secs: u64,
+    /// JSON file holding the last verified port, re-applied at startup.
+    #[serde(default, deserialize_with = "empty_string_as_none_path")]
+    pub state_file: Option<PathBuf>,
+    /// Number of replaced ports the anti-flap guard remembers; 0 disables it.
+    #[serde(default)]
+    pub ignore_recent_ports: usize,
+    /// How long a replaced port stays blocked by the anti-flap guard.
+    #[serde(default = "DaemonConfig::default_recent_port_window_secs")]
+    pub recent_port_window_secs: u64,
+    /// Seconds to wait before the first sync, in both `--once` and daemon
+    /// modes, so several instances started together do not collide.
+    #[serde(default)]
+    pub startup_delay_secs: u64,
+    /// File locked exclusively while running, so a second copy pointed at
+    /// the same setup exits instead of fighting over the port.
+    #[serde(default, deserialize_with = "empty_string_as_none_path")]
+    pub lock_file: Option<PathBuf>,
+    /// File log output is also written to, rotated daily. Needs the
+    /// `file-log` feature.
+    #[serde(default, deserialize_with = "empty_string_as_none_path")]
+    pub log_file: Option<PathBuf>,
+}
+
+/// Action run after a forwarded port has been applied and verified.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HookConfig {
+    /// Shell command; `{port}` is replaced with the forwarded port.
+    Command { command: String },
+    /// File that receives the forwarded port followed by a newline.
+    File { path: PathBuf },
+}
+
+impl HookConfig {
+    pub fn describe(&self) -> String {
+        match self {
+            HookConfig::Command { command } => format!("command `{command}`"),
+            HookConfig::File { path } => format!("file {}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "UPPERCASE")]
 #[allow(clippy::upper_case_acronyms)]
 pub enum PortProtocol {
@@ -82,28 +446,1053 @@ pub enum PortProtocol {
     BOTH,
 }
 
+/// Port-type qBittorrent preferences `also_set_keys` may name. Anything
+/// else (e.g. `web_ui_port`) could lock the user out, so it is rejected.
+pub const ALSO_SET_KEY_ALLOWLIST: &[&str] = &["announce_port", "dht_port", "embedded_tracker_port"];
+
+/// Placeholder values for the settings without a default, so they show up
+/// in `--dump-schema`; everything left out comes from the serde defaults.
+/// Add new optional settings here.
+const EXAMPLE_PLACEHOLDERS: &str = r#"
+[qbittorrent]
+name = "main"
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "changeme"
+also_set_keys = ["dht_port"]
+tls_ca_cert = "/etc/qb-port-sync/ca.pem"
+user_agent = "qb-port-sync"
+extra_headers = { Authorization = "Basic ..." }
+
+[deluge]
+password = "deluge"
+
+[transmission]
+username = "transmission"
+password = "changeme"
+
+[protonvpn]
+forwarded_port_path = "/tmp/gluetun/forwarded_port"
+forwarded_port_paths = ["/run/user/1000/Proton/VPN/forwarded_port"]
+control_server_url = "http://127.0.0.1:8000/v1/openvpn/portforwarded"
+
+[portmap]
+gateway = ["10.2.0.1"]
+client_ip = "10.2.0.2"
+
+[net]
+bind_interface = "tun0"
+
+[metrics]
+bind_address = "127.0.0.1"
+textfile_path = "/var/lib/node_exporter/qb_port_sync.prom"
+
+[health]
+max_staleness_secs = 600
+
+[daemon]
+state_file = "/var/lib/qb-port-sync/state.json"
+lock_file = "/run/qb-port-sync/lock"
+log_file = "/var/log/qb-port-sync/qb-port-sync.log"
+
+[[post_change_hooks]]
+type = "command"
+command = "echo {port}"
+"#;
+
+/// Tables that stay active in `--dump-schema` output; the others only
+/// apply to another client or are opt-in, so they are commented out whole.
+const EXAMPLE_ACTIVE_TABLES: &[&str] = &[
+    "client",
+    "qbittorrent",
+    "protonvpn",
+    "portmap",
+    "net",
+    "metrics",
+    "health",
+    "events",
+    "daemon",
+];
+
+/// Keys without a default, left active in the `[qbittorrent]` table.
+const EXAMPLE_REQUIRED_KEYS: &[&str] = &["base_url", "username"];
+
+/// Prefix of the environment variables that override configuration values.
+const ENV_PREFIX: &str = "QB_PORT_SYNC";
+
+/// Values that may be overridden from the environment, each read from
+/// `QB_PORT_SYNC_<SECTION>_<FIELD>` (`QB` for `qbittorrent`). The password is
+/// left out on purpose: `QB_PORT_SYNC_QB_PASSWORD` stays a fallback used
+/// only when the config sets no password.
+const ENV_OVERRIDES: &[(&str, &str, EnvKind)] = &[
+    ("client", "kind", EnvKind::Str),
+    ("qbittorrent", "name", EnvKind::Str),
+    ("qbittorrent", "base_url", EnvKind::Str),
+    ("qbittorrent", "username", EnvKind::Str),
+    ("qbittorrent", "also_set_keys", EnvKind::List),
+    ("qbittorrent", "timeout_secs", EnvKind::Int),
+    ("qbittorrent", "http_version", EnvKind::Str),
+    ("qbittorrent", "tls_ca_cert", EnvKind::Str),
+    ("qbittorrent", "tls_accept_invalid_certs", EnvKind::Bool),
+    ("qbittorrent", "user_agent", EnvKind::Str),
+    ("qbittorrent", "login_retries", EnvKind::Int),
+    ("qbittorrent", "login_retry_delay_secs", EnvKind::Int),
+    ("qbittorrent", "ban_cooldown_secs", EnvKind::Int),
+    ("qbittorrent", "enforce_port_settings", EnvKind::Bool),
+    ("qbittorrent", "manage_random_port", EnvKind::Bool),
+    ("qbittorrent", "manage_upnp", EnvKind::Bool),
+    ("qbittorrent", "verify_reachable", EnvKind::Bool),
+    ("qbittorrent", "reachability_check_url", EnvKind::Str),
+    ("qbittorrent", "only_if_interface_present", EnvKind::Bool),
+    ("qbittorrent", "service_host_env", EnvKind::Str),
+    ("qbittorrent", "service_port_env", EnvKind::Str),
+    ("deluge", "base_url", EnvKind::Str),
+    ("deluge", "timeout_secs", EnvKind::Int),
+    ("transmission", "base_url", EnvKind::Str),
+    ("transmission", "username", EnvKind::Str),
+    ("transmission", "timeout_secs", EnvKind::Int),
+    ("protonvpn", "forwarded_port_path", EnvKind::Str),
+    ("protonvpn", "forwarded_port_paths", EnvKind::List),
+    ("protonvpn", "port_file_format", EnvKind::Str),
+    ("protonvpn", "watch_mode", EnvKind::Str),
+    ("protonvpn", "poll_interval_secs", EnvKind::Int),
+    ("protonvpn", "initial_read_timeout_secs", EnvKind::Int),
+    ("protonvpn", "max_file_bytes", EnvKind::Int),
+    ("protonvpn", "apply_on_start", EnvKind::Bool),
+    ("protonvpn", "debounce_ms", EnvKind::Int),
+    ("protonvpn", "control_server_url", EnvKind::Str),
+    ("portmap", "internal_port", EnvKind::Int),
+    ("portmap", "protocol", EnvKind::Str),
+    ("portmap", "refresh_secs", EnvKind::Int),
+    ("portmap", "lease_secs", EnvKind::Int),
+    ("portmap", "min_refresh_secs", EnvKind::Int),
+    ("portmap", "max_refresh_secs", EnvKind::Int),
+    ("portmap", "max_backoff_secs", EnvKind::Int),
+    ("portmap", "gateway_cache_secs", EnvKind::Int),
+    ("portmap", "autodiscover_gateway", EnvKind::Bool),
+    ("portmap", "gateway", EnvKind::List),
+    ("portmap", "client_ip", EnvKind::Str),
+    ("portmap", "max_retries", EnvKind::Int),
+    ("portmap", "refresh_jitter_secs", EnvKind::Int),
+    ("portmap", "external_ip", EnvKind::Bool),
+    ("portmap", "require_exact_external", EnvKind::Bool),
+    ("net", "bind_interface", EnvKind::Str),
+    ("net", "retry_interface_id", EnvKind::Bool),
+    ("net", "leak_protect_on_startup", EnvKind::Bool),
+    ("metrics", "enabled", EnvKind::Bool),
+    ("metrics", "port", EnvKind::Int),
+    ("metrics", "namespace", EnvKind::Str),
+    ("metrics", "bind_address", EnvKind::Str),
+    ("metrics", "textfile_path", EnvKind::Str),
+    ("health", "enabled", EnvKind::Bool),
+    ("health", "port", EnvKind::Int),
+    ("health", "standalone", EnvKind::Bool),
+    ("health", "max_staleness_secs", EnvKind::Int),
+    ("events", "capacity", EnvKind::Int),
+    ("daemon", "max_consecutive_failures", EnvKind::Int),
+    ("daemon", "min_apply_interval_secs", EnvKind::Int),
+    ("daemon", "state_file", EnvKind::Str),
+    ("daemon", "ignore_recent_ports", EnvKind::Int),
+    ("daemon", "recent_port_window_secs", EnvKind::Int),
+    ("daemon", "startup_delay_secs", EnvKind::Int),
+    ("daemon", "lock_file", EnvKind::Str),
+    ("daemon", "log_file", EnvKind::Str),
+];
+
+/// How an environment override is converted into a TOML value.
+#[derive(Debug, Clone, Copy)]
+enum EnvKind {
+    Str,
+    Int,
+    Bool,
+    /// Comma-separated strings.
+    List,
+}
+
+/// Layer a configuration value was taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueSource {
+    Default,
+    File,
+    Profile,
+    Env,
+    Cli,
+}
+
+impl fmt::Display for ValueSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            ValueSource::Default => "default",
+            ValueSource::File => "file",
+            ValueSource::Profile => "profile",
+            ValueSource::Env => "env",
+            ValueSource::Cli => "cli",
+        };
+        f.write_str(label)
+    }
+}
+
+/// A single resolved configuration value and the layer it came from.
+#[derive(Debug, Clone)]
+pub struct EffectiveEntry {
+    pub key: String,
+    pub value: String,
+    pub source: ValueSource,
+}
+
+/// Keys that differ between the running configuration and a reloaded one.
+#[derive(Debug, Clone, Default)]
+pub struct ReloadDiff {
+    /// Changes the running daemon took over.
+    pub applied: Vec<String>,
+    /// Changes ignored until the process restarts.
+    pub restart_required: Vec<String>,
+}
+
+/// Keys a running daemon picks up on reload: the forwarded-port source and
+/// strategy inputs, the port-mapping settings and the bound interface.
+const RELOADABLE_KEYS: &[&str] = &["protonvpn.", "portmap.", "net.bind_interface"];
+
 impl Config {
+    #[allow(dead_code)]
     pub fn load(cli_path: Option<PathBuf>) -> Result<Self> {
+        Self::load_with_profile(cli_path, None)
+    }
+
+    /// Loads the configuration and deep-merges the named `[profiles.<name>]`
+    /// table over the base. Falls back to `QB_PORT_SYNC_PROFILE` when no
+    /// profile is passed explicitly.
+    pub fn load_with_profile(cli_path: Option<PathBuf>, profile: Option<&str>) -> Result<Self> {
         let path = find_config(cli_path)?;
-        let raw = fs::read_to_string(&path)?;
-        let mut cfg: Config = toml::from_str(&raw)?;
-        cfg.source = Some(path.clone());
+        let mut table: toml::Value = match &path {
+            Some(path) => toml::from_str(&fs::read_to_string(path)?)?,
+            None => toml::toml! {
+                [protonvpn]
+                [portmap]
+            }
+            .into(),
+        };
+
+        let profiles = table.as_table_mut().and_then(|t| t.remove("profiles"));
+        let profile = profile
+            .map(str::to_string)
+            .or_else(|| env::var("QB_PORT_SYNC_PROFILE").ok())
+            .filter(|p| !p.trim().is_empty());
+        let overlay = match profile.as_deref() {
+            Some(name) => Some(
+                profiles
+                    .as_ref()
+                    .and_then(|p| p.get(name))
+                    .cloned()
+                    .ok_or_else(|| ConfigError::UnknownProfile(name.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let base = table.clone();
+        if let Some(overlay) = overlay.clone() {
+            merge_toml(&mut table, overlay);
+        }
+        let env_keys = apply_env_overrides(&mut table)?;
+
+        let mut cfg: Config = table.try_into()?;
+        cfg.source = path;
+        cfg.profile = profile;
+        cfg.record_sources(&base, ValueSource::File);
+        if let Some(overlay) = overlay.as_ref() {
+            cfg.record_sources(overlay, ValueSource::Profile);
+        }
+        for key in env_keys {
+            cfg.sources.insert(key, ValueSource::Env);
+        }
+        cfg.record_env_sources();
         cfg.post_process();
+        cfg.validate()?;
         Ok(cfg)
     }
 
+    /// Replaces `portmap.refresh_secs` for this run with a command-line value.
+    pub fn override_refresh_secs(&mut self, secs: u64) {
+        self.portmap.refresh_secs = secs;
+        self.sources
+            .insert("portmap.refresh_secs".to_string(), ValueSource::Cli);
+    }
+
+    /// Replaces `daemon.startup_delay_secs` for this run with a command-line value.
+    pub fn override_startup_delay_secs(&mut self, secs: u64) {
+        self.daemon.startup_delay_secs = secs;
+        self.sources
+            .insert("daemon.startup_delay_secs".to_string(), ValueSource::Cli);
+    }
+
+    /// Replaces `portmap.protocol` for this run with a command-line value.
+    pub fn override_protocol(&mut self, protocol: PortProtocol) {
+        self.portmap.protocol = protocol;
+        self.sources
+            .insert("portmap.protocol".to_string(), ValueSource::Cli);
+    }
+
+    /// Name of the profile merged over the base configuration, if any.
+    pub fn active_profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    /// Returns the layer the given dotted key was resolved from.
+    pub fn value_source(&self, key: &str) -> ValueSource {
+        self.sources
+            .get(key)
+            .copied()
+            .unwrap_or(ValueSource::Default)
+    }
+
+    /// Lists every known configuration value with its effective setting and origin.
+    pub fn effective_entries(&self) -> Vec<EffectiveEntry> {
+        self.effective_values()
+            .into_iter()
+            .map(|(key, value)| EffectiveEntry {
+                source: self.value_source(&key),
+                key,
+                value,
+            })
+            .collect()
+    }
+
+    /// Renders the effective configuration as an aligned key/value/source table.
+    pub fn render_effective(&self) -> String {
+        let entries = self.effective_entries();
+        let key_width = entries.iter().map(|e| e.key.len()).max().unwrap_or(0);
+        let value_width = entries.iter().map(|e| e.value.len()).max().unwrap_or(0);
+
+        let mut out = format!("{:key_width$}  {:value_width$}  SOURCE\n", "KEY", "VALUE",);
+        for entry in entries {
+            out.push_str(&format!(
+                "{:key_width$}  {:value_width$}  {}\n",
+                entry.key, entry.value, entry.source,
+            ));
+        }
+        out
+    }
+
+    /// Copy with derived values filled in: each instance's base URL and
+    /// password fallback, and the default forwarded-port path. Passwords
+    /// still serialize as `<redacted>`.
+    pub fn resolved(&self) -> Config {
+        let mut resolved = self.clone();
+        for instance in &mut resolved.qbittorrent {
+            if let Ok(url) = instance.resolved_base_url() {
+                instance.base_url = url.to_string();
+            }
+            instance.password = instance.resolved_password().ok();
+        }
+        if let Some(deluge) = resolved.deluge.as_mut() {
+            deluge.password = deluge.resolved_password().ok();
+        }
+        if let Some(transmission) = resolved.transmission.as_mut() {
+            transmission.password = transmission.resolved_password();
+        }
+        resolved.protonvpn.forwarded_port_path = self.resolved_forwarded_port_path();
+        resolved
+    }
+
+    /// Every setting at its default, plus placeholders for the optional ones.
+    pub fn example() -> Config {
+        toml::from_str(EXAMPLE_PLACEHOLDERS).expect("example placeholders deserialize")
+    }
+
+    /// [`Config::example`] as TOML for `--dump-schema`: each setting is
+    /// commented out at its default except the few without one.
+    pub fn render_example() -> Result<String> {
+        let rendered = toml::to_string_pretty(&Config::example())?;
+        let mut out = String::from(
+            "# qb-port-sync configuration. Commented settings show their default, or an\n\
+             # example for those without one; uncomment what you need. Secrets print as\n\
+             # \"<redacted>\": set them here or through the environment.\n\n",
+        );
+        let mut table = "";
+        for line in rendered.lines() {
+            let trimmed = line.trim();
+            let active = if trimmed.starts_with('[') {
+                table = trimmed.trim_matches(|c| c == '[' || c == ']');
+                EXAMPLE_ACTIVE_TABLES.contains(&table)
+            } else {
+                let key = trimmed.split('=').next().unwrap_or_default().trim();
+                table == "qbittorrent" && EXAMPLE_REQUIRED_KEYS.contains(&key)
+            };
+            if active || trimmed.is_empty() {
+                out.push_str(line);
+            } else {
+                out.push_str("# ");
+                out.push_str(line);
+            }
+            out.push('\n');
+        }
+        Ok(out)
+    }
+
     #[allow(dead_code)]
     pub fn source_path(&self) -> Option<&Path> {
         self.source.as_deref()
     }
 
-    pub fn qbittorrent_password(&self) -> Result<String> {
-        if let Some(pass) = self
+    pub fn bind_interface(&self) -> Option<&str> {
+        self.net.bind_interface.as_deref()
+    }
+
+    pub fn min_apply_interval(&self) -> Duration {
+        Duration::from_secs(self.daemon.min_apply_interval_secs)
+    }
+
+    pub fn recent_port_window(&self) -> Duration {
+        Duration::from_secs(self.daemon.recent_port_window_secs)
+    }
+
+    /// Delay before the next port-mapping cycle: half the granted TTL,
+    /// clamped to `min_refresh_secs..=max_refresh_secs` but never past three
+    /// quarters of the TTL, or `refresh_secs` without one. A `--refresh-secs`
+    /// override also caps the TTL-derived delay so short test cycles happen
+    /// even when the gateway grants a long lease, and so does `lease_secs`,
+    /// which decouples the lease from the cadence.
+    pub fn portmap_refresh_delay(&self, ttl: Option<Duration>) -> Duration {
+        let refresh = Duration::from_secs(self.portmap.refresh_secs);
+        let delay = ttl
+            .map(|ttl| {
+                (ttl / 2)
+                    .clamp(
+                        Duration::from_secs(self.portmap.min_refresh_secs),
+                        Duration::from_secs(self.portmap.max_refresh_secs),
+                    )
+                    .min(ttl * 3 / 4)
+            })
+            .unwrap_or(refresh);
+        if self.value_source("portmap.refresh_secs") == ValueSource::Cli
+            || self.portmap.lease_secs.is_some()
+        {
+            delay.min(refresh)
+        } else {
+            delay
+        }
+    }
+
+    /// Delay before retrying after `failures` consecutive failed
+    /// port-mapping cycles: `refresh_secs`, doubled for each further failure
+    /// up to `max_backoff_secs`. A ceiling at or below `refresh_secs`
+    /// disables the backoff.
+    pub fn portmap_error_delay(&self, failures: u32) -> Duration {
+        let refresh = Duration::from_secs(self.portmap.refresh_secs);
+        let ceiling = Duration::from_secs(self.portmap.max_backoff_secs).max(refresh);
+        let factor = 1u32
+            .checked_shl(failures.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        refresh.saturating_mul(factor).min(ceiling)
+    }
+
+    /// Lifetime of the daemon's cached autodiscovered gateway; `None` keeps
+    /// it until a mapping fails.
+    pub fn gateway_cache_ttl(&self) -> Option<Duration> {
+        (self.portmap.gateway_cache_secs > 0)
+            .then(|| Duration::from_secs(self.portmap.gateway_cache_secs))
+    }
+
+    /// Staleness limit for readiness. Without `health.max_staleness_secs`
+    /// it allows two of `cycle_delay`, the wait the portmap daemon scheduled
+    /// before its next cycle; strategies that only update on change pass
+    /// `None` and get no limit.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn health_max_staleness(&self, cycle_delay: Option<Duration>) -> Option<Duration> {
+        match self.health.max_staleness_secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => cycle_delay.map(|delay| delay.saturating_mul(2)),
+        }
+    }
+
+    /// `/healthz` of a daemon started with this config, for `qb-port-sync
+    /// healthcheck`: the health port, else the metrics port, on loopback
+    /// unless the server binds one specific address.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn health_check_url(&self) -> Result<Url> {
+        let port = if self.health.enabled && self.health.port > 0 {
+            self.health.port
+        } else if self.metrics.enabled && self.metrics.port > 0 {
+            self.metrics.port
+        } else {
+            return Err(ConfigError::HealthEndpointDisabled.into());
+        };
+        let ip = match self.metrics.bind_ip()? {
+            IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ip => ip,
+        };
+        let raw = format!("http://{}/healthz", SocketAddr::new(ip, port));
+        Ok(Url::parse(&raw)?)
+    }
+
+    /// Forwarded-port file locations in the order they are tried:
+    /// `forwarded_port_path`, then `forwarded_port_paths`, then the
+    /// ProtonVPN default on Linux.
+    pub fn forwarded_port_candidates(&self) -> Vec<PathBuf> {
+        #[allow(unused_mut)]
+        let mut candidates: Vec<PathBuf> = self
+            .protonvpn
+            .forwarded_port_path
+            .iter()
+            .chain(&self.protonvpn.forwarded_port_paths)
+            .cloned()
+            .collect();
+        #[cfg(target_os = "linux")]
+        candidates.push(linux_default_forwarded_port_path());
+        candidates
+    }
+
+    /// The first candidate file that exists; when none does yet, the first
+    /// whose directory exists, so the watcher can wait for it there, and
+    /// failing that the first candidate.
+    pub fn resolved_forwarded_port_path(&self) -> Option<PathBuf> {
+        let candidates = self.forwarded_port_candidates();
+        candidates
+            .iter()
+            .find(|path| path.exists())
+            .or_else(|| {
+                candidates
+                    .iter()
+                    .find(|path| path.parent().is_some_and(Path::exists))
+            })
+            .or(candidates.first())
+            .cloned()
+    }
+
+    /// Takes the reloadable settings over from `fresh` and reports what
+    /// changed. Everything else keeps its running value, so the returned
+    /// `restart_required` keys only take effect after a restart.
+    pub fn apply_reload(&mut self, fresh: Config) -> ReloadDiff {
+        let current: BTreeMap<String, String> = self.effective_values().into_iter().collect();
+        let reloaded: BTreeMap<String, String> = fresh.effective_values().into_iter().collect();
+        let mut diff = ReloadDiff::default();
+        let keys: BTreeSet<&String> = current.keys().chain(reloaded.keys()).collect();
+        for key in keys {
+            let live = RELOADABLE_KEYS.iter().any(|prefix| key.starts_with(prefix));
+            if live {
+                match fresh.sources.get(key) {
+                    Some(source) => self.sources.insert(key.clone(), *source),
+                    None => self.sources.remove(key),
+                };
+            }
+            if current.get(key) == reloaded.get(key) {
+                continue;
+            }
+            if live {
+                diff.applied.push(key.clone());
+            } else {
+                diff.restart_required.push(key.clone());
+            }
+        }
+        if self.post_change_hooks != fresh.post_change_hooks {
+            diff.restart_required.push("post_change_hooks".to_string());
+        }
+        self.protonvpn = fresh.protonvpn;
+        self.portmap = fresh.portmap;
+        self.net.bind_interface = fresh.net.bind_interface;
+        diff
+    }
+
+    fn effective_values(&self) -> Vec<(String, String)> {
+        fn quoted(value: Option<&str>) -> String {
+            value
+                .map(|v| format!("{v:?}"))
+                .unwrap_or_else(|| "<unset>".to_string())
+        }
+
+        let mut values = vec![(
+            "client.kind".to_string(),
+            self.client.kind.label().to_string(),
+        )];
+        for (prefix, instance) in self.instance_prefixes().into_iter().zip(&self.qbittorrent) {
+            let password = match instance.resolved_password() {
+                Ok(_) => "<redacted>".to_string(),
+                Err(_) => "<unset>".to_string(),
+            };
+            let base_url = match instance.resolved_base_url() {
+                Ok(url) => quoted(Some(url.as_str())),
+                Err(_) => quoted(None),
+            };
+            values.extend([
+                (format!("{prefix}.name"), quoted(instance.name.as_deref())),
+                (format!("{prefix}.base_url"), base_url),
+                (
+                    format!("{prefix}.username"),
+                    quoted(Some(&instance.username)),
+                ),
+                (format!("{prefix}.password"), password),
+                (
+                    format!("{prefix}.also_set_keys"),
+                    format!("{:?}", instance.also_set_keys),
+                ),
+                (
+                    format!("{prefix}.timeout_secs"),
+                    instance.timeout_secs.to_string(),
+                ),
+                (
+                    format!("{prefix}.http_version"),
+                    format!("{:?}", instance.http_version).to_lowercase(),
+                ),
+                (
+                    format!("{prefix}.tls_ca_cert"),
+                    quoted(instance.tls_ca_cert.as_deref().and_then(Path::to_str)),
+                ),
+                (
+                    format!("{prefix}.tls_accept_invalid_certs"),
+                    instance.tls_accept_invalid_certs.to_string(),
+                ),
+                (
+                    format!("{prefix}.login_retries"),
+                    instance.login_retries.to_string(),
+                ),
+                (
+                    format!("{prefix}.login_retry_delay_secs"),
+                    instance.login_retry_delay_secs.to_string(),
+                ),
+                (
+                    format!("{prefix}.ban_cooldown_secs"),
+                    instance.ban_cooldown_secs.to_string(),
+                ),
+                (
+                    format!("{prefix}.enforce_port_settings"),
+                    instance.enforce_port_settings.to_string(),
+                ),
+                (
+                    format!("{prefix}.manage_random_port"),
+                    instance.manage_random_port.to_string(),
+                ),
+                (
+                    format!("{prefix}.manage_upnp"),
+                    instance.manage_upnp.to_string(),
+                ),
+                (
+                    format!("{prefix}.verify_reachable"),
+                    instance.verify_reachable.to_string(),
+                ),
+                (
+                    format!("{prefix}.reachability_check_url"),
+                    quoted(Some(&instance.reachability_check_url)),
+                ),
+                (
+                    format!("{prefix}.only_if_interface_present"),
+                    instance.only_if_interface_present.to_string(),
+                ),
+                (
+                    format!("{prefix}.user_agent"),
+                    quoted(instance.user_agent.as_deref()),
+                ),
+                (
+                    format!("{prefix}.extra_headers"),
+                    format!("{:?}", instance.extra_header_names()),
+                ),
+                (
+                    format!("{prefix}.service_host_env"),
+                    quoted(Some(&instance.service_host_env)),
+                ),
+                (
+                    format!("{prefix}.service_port_env"),
+                    quoted(Some(&instance.service_port_env)),
+                ),
+            ]);
+        }
+
+        if let Some(deluge) = &self.deluge {
+            let password = match deluge.resolved_password() {
+                Ok(_) => "<redacted>".to_string(),
+                Err(_) => "<unset>".to_string(),
+            };
+            values.extend([
+                (
+                    "deluge.base_url".to_string(),
+                    quoted(Some(&deluge.base_url)),
+                ),
+                ("deluge.password".to_string(), password),
+                (
+                    "deluge.timeout_secs".to_string(),
+                    deluge.timeout_secs.to_string(),
+                ),
+            ]);
+        }
+        if let Some(transmission) = &self.transmission {
+            let password = match transmission.resolved_password() {
+                Some(_) => "<redacted>".to_string(),
+                None => "<unset>".to_string(),
+            };
+            values.extend([
+                (
+                    "transmission.base_url".to_string(),
+                    quoted(Some(&transmission.base_url)),
+                ),
+                (
+                    "transmission.username".to_string(),
+                    quoted(transmission.username.as_deref()),
+                ),
+                ("transmission.password".to_string(), password),
+                (
+                    "transmission.timeout_secs".to_string(),
+                    transmission.timeout_secs.to_string(),
+                ),
+            ]);
+        }
+
+        let forwarded_port_path = self.resolved_forwarded_port_path();
+        let rest = [
+            (
+                "protonvpn.forwarded_port_path",
+                quoted(forwarded_port_path.as_deref().and_then(Path::to_str)),
+            ),
+            (
+                "protonvpn.forwarded_port_paths",
+                format!("{:?}", self.protonvpn.forwarded_port_paths),
+            ),
+            (
+                "protonvpn.port_file_format",
+                format!("{:?}", self.protonvpn.port_file_format).to_lowercase(),
+            ),
+            (
+                "protonvpn.watch_mode",
+                format!("{:?}", self.protonvpn.watch_mode).to_lowercase(),
+            ),
+            (
+                "protonvpn.poll_interval_secs",
+                self.protonvpn.poll_interval_secs.to_string(),
+            ),
+            (
+                "protonvpn.initial_read_timeout_secs",
+                self.protonvpn.initial_read_timeout_secs.to_string(),
+            ),
+            (
+                "protonvpn.max_file_bytes",
+                self.protonvpn.max_file_bytes.to_string(),
+            ),
+            (
+                "protonvpn.apply_on_start",
+                self.protonvpn.apply_on_start.to_string(),
+            ),
+            (
+                "protonvpn.debounce_ms",
+                self.protonvpn.debounce_ms.to_string(),
+            ),
+            (
+                "protonvpn.control_server_url",
+                quoted(self.protonvpn.control_server_url.as_deref()),
+            ),
+            (
+                "portmap.internal_port",
+                self.portmap.internal_port.to_string(),
+            ),
+            ("portmap.protocol", format!("{:?}", self.portmap.protocol)),
+            (
+                "portmap.refresh_secs",
+                self.portmap.refresh_secs.to_string(),
+            ),
+            (
+                "portmap.lease_secs",
+                self.portmap
+                    .lease_secs
+                    .map_or_else(|| "<unset>".to_string(), |secs| secs.to_string()),
+            ),
+            (
+                "portmap.min_refresh_secs",
+                self.portmap.min_refresh_secs.to_string(),
+            ),
+            (
+                "portmap.max_refresh_secs",
+                self.portmap.max_refresh_secs.to_string(),
+            ),
+            (
+                "portmap.max_backoff_secs",
+                self.portmap.max_backoff_secs.to_string(),
+            ),
+            (
+                "portmap.autodiscover_gateway",
+                self.portmap.autodiscover_gateway.to_string(),
+            ),
+            (
+                "portmap.gateway_cache_secs",
+                self.portmap.gateway_cache_secs.to_string(),
+            ),
+            (
+                "portmap.gateway",
+                match self.portmap.gateway.as_slice() {
+                    [] => quoted(None),
+                    [gateway] => quoted(Some(gateway)),
+                    gateways => format!("{gateways:?}"),
+                },
+            ),
+            (
+                "portmap.client_ip",
+                quoted(self.portmap.client_ip.as_deref()),
+            ),
+            ("portmap.max_retries", self.portmap.max_retries.to_string()),
+            (
+                "portmap.refresh_jitter_secs",
+                self.portmap.refresh_jitter_secs.to_string(),
+            ),
+            ("portmap.external_ip", self.portmap.external_ip.to_string()),
+            (
+                "portmap.acceptable_range",
+                format!(
+                    "{{ min = {}, max = {} }}",
+                    self.portmap.acceptable_range.min, self.portmap.acceptable_range.max
+                ),
+            ),
+            (
+                "portmap.require_exact_external",
+                self.portmap.require_exact_external.to_string(),
+            ),
+            (
+                "net.bind_interface",
+                quoted(self.net.bind_interface.as_deref()),
+            ),
+            (
+                "net.retry_interface_id",
+                self.net.retry_interface_id.to_string(),
+            ),
+            (
+                "net.leak_protect_on_startup",
+                self.net.leak_protect_on_startup.to_string(),
+            ),
+            ("metrics.enabled", self.metrics.enabled.to_string()),
+            ("metrics.port", self.metrics.port.to_string()),
+            ("metrics.namespace", quoted(Some(&self.metrics.namespace))),
+            (
+                "metrics.bind_address",
+                quoted(self.metrics.bind_address.as_deref()),
+            ),
+            (
+                "metrics.textfile_path",
+                quoted(self.metrics.textfile_path.as_deref().and_then(Path::to_str)),
+            ),
+            ("health.enabled", self.health.enabled.to_string()),
+            ("health.port", self.health.port.to_string()),
+            ("health.standalone", self.health.standalone.to_string()),
+            (
+                "health.max_staleness_secs",
+                self.health
+                    .max_staleness_secs
+                    .map_or_else(|| "<unset>".to_string(), |secs| secs.to_string()),
+            ),
+            ("events.capacity", self.events.capacity.to_string()),
+            (
+                "daemon.max_consecutive_failures",
+                self.daemon.max_consecutive_failures.to_string(),
+            ),
+            (
+                "daemon.min_apply_interval_secs",
+                self.daemon.min_apply_interval_secs.to_string(),
+            ),
+            (
+                "daemon.state_file",
+                quoted(self.daemon.state_file.as_deref().and_then(Path::to_str)),
+            ),
+            (
+                "daemon.lock_file",
+                quoted(self.daemon.lock_file.as_deref().and_then(Path::to_str)),
+            ),
+            (
+                "daemon.log_file",
+                quoted(self.daemon.log_file.as_deref().and_then(Path::to_str)),
+            ),
+            (
+                "daemon.ignore_recent_ports",
+                self.daemon.ignore_recent_ports.to_string(),
+            ),
+            (
+                "daemon.recent_port_window_secs",
+                self.daemon.recent_port_window_secs.to_string(),
+            ),
+            (
+                "daemon.startup_delay_secs",
+                self.daemon.startup_delay_secs.to_string(),
+            ),
+            (
+                "post_change_hooks",
+                format!("{} configured", self.post_change_hooks.len()),
+            ),
+        ];
+        values.extend(rest.into_iter().map(|(k, v)| (k.to_string(), v)));
+        values
+    }
+
+    /// Key prefix for each qBittorrent instance: plain `qbittorrent` for a
+    /// single table, `qbittorrent[N]` when several are configured.
+    fn instance_prefixes(&self) -> Vec<String> {
+        if self.qbittorrent.len() == 1 {
+            return vec!["qbittorrent".to_string()];
+        }
+        (0..self.qbittorrent.len())
+            .map(|idx| format!("qbittorrent[{idx}]"))
+            .collect()
+    }
+
+    fn record_sources(&mut self, table: &toml::Value, layer: ValueSource) {
+        for (key, _) in self.effective_values() {
+            let present = lookup_key(table, &key)
+                .map(|v| v.as_str().map(|s| !s.trim().is_empty()).unwrap_or(true))
+                .unwrap_or(false);
+            if present {
+                self.sources.insert(key, layer);
+            }
+        }
+    }
+
+    fn record_env_sources(&mut self) {
+        let env_password = env::var("QB_PORT_SYNC_QB_PASSWORD")
+            .map(|p| !p.trim().is_empty())
+            .unwrap_or(false);
+        let prefixes = self.instance_prefixes();
+        for (prefix, instance) in prefixes.iter().zip(self.qbittorrent.clone()) {
+            let key = format!("{prefix}.password");
+            if env_password && self.value_source(&key) == ValueSource::Default {
+                self.sources.insert(key, ValueSource::Env);
+            }
+            if instance.base_url.trim().is_empty() && instance.resolved_base_url().is_ok() {
+                self.sources
+                    .insert(format!("{prefix}.base_url"), ValueSource::Env);
+            }
+        }
+        let env_deluge_password = env::var("QB_PORT_SYNC_DELUGE_PASSWORD")
+            .map(|p| !p.trim().is_empty())
+            .unwrap_or(false);
+        if env_deluge_password
+            && self.deluge.is_some()
+            && self.value_source("deluge.password") == ValueSource::Default
+        {
+            self.sources
+                .insert("deluge.password".to_string(), ValueSource::Env);
+        }
+        let env_transmission_password = env::var("QB_PORT_SYNC_TRANSMISSION_PASSWORD")
+            .map(|p| !p.trim().is_empty())
+            .unwrap_or(false);
+        if env_transmission_password
+            && self.transmission.is_some()
+            && self.value_source("transmission.password") == ValueSource::Default
+        {
+            self.sources
+                .insert("transmission.password".to_string(), ValueSource::Env);
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        let kind = self.client.kind;
+        let section_present = match kind {
+            ClientKind::Qbittorrent => !self.qbittorrent.is_empty(),
+            ClientKind::Deluge => self.deluge.is_some(),
+            ClientKind::Transmission => self.transmission.is_some(),
+        };
+        if !section_present {
+            return Err(ConfigError::MissingClientSection(kind.label()).into());
+        }
+        if kind != ClientKind::Qbittorrent {
+            if self.net.bind_interface.is_some() {
+                return Err(
+                    ConfigError::UnsupportedByClient("net.bind_interface", kind.label()).into(),
+                );
+            }
+            if self.net.leak_protect_on_startup {
+                return Err(ConfigError::UnsupportedByClient(
+                    "net.leak_protect_on_startup",
+                    kind.label(),
+                )
+                .into());
+            }
+        }
+        if let Some(interface) = self.net.bind_interface.as_deref() {
+            InterfacePattern::parse(interface)?;
+        }
+        for instance in &self.qbittorrent {
+            if instance.only_if_interface_present && self.net.bind_interface.is_none() {
+                return Err(ConfigError::InterfaceGuardWithoutInterface.into());
+            }
+            instance.extra_header_map()?;
+            if let Some(user_agent) = instance.user_agent.as_deref() {
+                HeaderValue::from_str(user_agent).map_err(|err| {
+                    ConfigError::InvalidHeader(header::USER_AGENT.to_string(), err.to_string())
+                })?;
+            }
+            if let Some(key) = instance
+                .also_set_keys
+                .iter()
+                .find(|key| !ALSO_SET_KEY_ALLOWLIST.contains(&key.as_str()))
+            {
+                return Err(ConfigError::UnsupportedPreferenceKey(key.clone()).into());
+            }
+        }
+        if self.portmap.lease_secs == Some(0) {
+            return Err(ConfigError::ZeroLeaseSecs.into());
+        }
+        if self.portmap.lease_secs() > u64::from(u32::MAX) {
+            return Err(ConfigError::LeaseSecsTooLong(self.portmap.lease_secs()).into());
+        }
+        if self.portmap.min_refresh_secs > self.portmap.max_refresh_secs {
+            return Err(ConfigError::InvalidRefreshBounds(
+                self.portmap.min_refresh_secs,
+                self.portmap.max_refresh_secs,
+            )
+            .into());
+        }
+        let range = self.portmap.acceptable_range;
+        if range.min == 0 || range.min > range.max {
+            return Err(ConfigError::InvalidAcceptableRange(range.min, range.max).into());
+        }
+        if let Some(client_ip) = self.portmap.client_ip_addr()? {
+            // Autodiscovered gateways are checked when the mapping is made.
+            for gateway in &self.portmap.gateway {
+                if let Ok(gateway) = gateway.parse::<IpAddr>() {
+                    ensure_same_family(client_ip, gateway)?;
+                }
+            }
+        }
+        self.metrics.bind_ip()?;
+        if !is_metric_name_segment(&self.metrics.namespace) {
+            return Err(
+                ConfigError::InvalidMetricsNamespace(self.metrics.namespace.clone()).into(),
+            );
+        }
+        Ok(())
+    }
+
+    fn post_process(&mut self) {
+        let source_dir = self.source.as_ref().and_then(|p| p.parent());
+        let ca_certs = self
             .qbittorrent
-            .password
-            .as_deref()
-            .filter(|p| !p.trim().is_empty())
+            .iter_mut()
+            .map(|instance| instance.tls_ca_cert.as_mut());
+        for path in [
+            self.protonvpn.forwarded_port_path.as_mut(),
+            self.daemon.state_file.as_mut(),
+            self.daemon.lock_file.as_mut(),
+            self.daemon.log_file.as_mut(),
+            self.metrics.textfile_path.as_mut(),
+        ]
+        .into_iter()
+        .chain(ca_certs)
+        .flatten()
+        .chain(self.protonvpn.forwarded_port_paths.iter_mut())
         {
+            if path.is_relative() {
+                if let Some(source) = source_dir {
+                    let relative = path.clone();
+                    *path = source.join(relative);
+                }
+            }
+        }
+    }
+}
+
+impl QbittorrentConfig {
+    /// Checker to ask after a verified update, when `verify_reachable` is set.
+    pub fn reachability_check(&self) -> Option<String> {
+        self.verify_reachable
+            .then(|| self.reachability_check_url.clone())
+    }
+
+    /// Password from the config, falling back to `QB_PORT_SYNC_QB_PASSWORD`.
+    pub fn resolved_password(&self) -> Result<String> {
+        if let Some(pass) = self.password.as_deref().filter(|p| !p.trim().is_empty()) {
             return Ok(pass.to_string());
         }
         if let Ok(env_pass) = env::var("QB_PORT_SYNC_QB_PASSWORD") {
@@ -114,34 +1503,293 @@ impl Config {
         Err(ConfigError::MissingQbPassword.into())
     }
 
-    pub fn bind_interface(&self) -> Option<&str> {
-        self.net.bind_interface.as_deref()
+    /// The configured `base_url`, or `http://{host}:{port}` built from the
+    /// Kubernetes-style service env vars when `base_url` is empty.
+    pub fn resolved_base_url(&self) -> Result<Url> {
+        let raw = if self.base_url.trim().is_empty() {
+            let host = env::var(&self.service_host_env)
+                .ok()
+                .filter(|h| !h.trim().is_empty());
+            let port = env::var(&self.service_port_env)
+                .ok()
+                .filter(|p| !p.trim().is_empty());
+            let (Some(host), Some(port)) = (host, port) else {
+                return Err(ConfigError::MissingBaseUrl(
+                    self.service_host_env.clone(),
+                    self.service_port_env.clone(),
+                )
+                .into());
+            };
+            let port: u16 = port.trim().parse().map_err(|_| {
+                ConfigError::InvalidBaseUrl(format!("{}={port}", self.service_port_env))
+            })?;
+            let host = host.trim();
+            if host.contains(':') {
+                format!("http://[{host}]:{port}")
+            } else {
+                format!("http://{host}:{port}")
+            }
+        } else {
+            self.base_url.clone()
+        };
+        Url::parse(&raw).map_err(|err| ConfigError::InvalidBaseUrl(format!("{raw}: {err}")).into())
     }
 
-    pub fn resolved_forwarded_port_path(&self) -> Option<PathBuf> {
-        if let Some(path) = self.protonvpn.forwarded_port_path.clone() {
-            return Some(path);
+    /// Name used in logs and reports: the configured `name`, else the base URL.
+    pub fn label(&self) -> &str {
+        match self.name.as_deref() {
+            Some(name) => name,
+            None if self.base_url.trim().is_empty() => &self.service_host_env,
+            None => &self.base_url,
         }
+    }
 
-        #[cfg(target_os = "linux")]
-        {
-            return Some(linux_default_forwarded_port_path());
+    /// `extra_headers` as a header map. Invalid names or values, and the
+    /// Referer and Origin headers qb-port-sync sets itself, are refused.
+    pub fn extra_header_map(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in self.extra_headers.iter().flatten() {
+            let invalid = |reason: String| ConfigError::InvalidHeader(name.clone(), reason);
+            let header_name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|err| invalid(err.to_string()))?;
+            if header_name == header::REFERER || header_name == header::ORIGIN {
+                return Err(invalid("it is always set to the base URL".to_string()).into());
+            }
+            let header_value =
+                HeaderValue::from_str(value).map_err(|err| invalid(err.to_string()))?;
+            headers.insert(header_name, header_value);
         }
+        Ok(headers)
+    }
 
-        None
+    /// Sorted `extra_headers` names, shown instead of their values.
+    fn extra_header_names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self
+            .extra_headers
+            .iter()
+            .flatten()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort_unstable();
+        names
     }
 
-    fn post_process(&mut self) {
-        if let Some(path) = self.protonvpn.forwarded_port_path.as_mut() {
-            if path.is_relative() {
-                if let Some(source) = self.source.as_ref().and_then(|p| p.parent()) {
-                    {
-                        let relative = path.clone();
-                        *path = source.join(relative);
-                    }
-                }
+    /// HTTP timeout for qBittorrent requests, `None` when `timeout_secs = 0`.
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+
+    const fn default_login_retries() -> u32 {
+        5
+    }
+
+    const fn default_login_retry_delay_secs() -> u64 {
+        1
+    }
+
+    const fn default_ban_cooldown_secs() -> u64 {
+        3600
+    }
+
+    const fn default_enforce_port_settings() -> bool {
+        true
+    }
+
+    const fn default_manage_random_port() -> bool {
+        true
+    }
+
+    const fn default_manage_upnp() -> bool {
+        true
+    }
+
+    fn default_reachability_check_url() -> String {
+        "https://portcheck.transmissionbt.com/{port}".to_string()
+    }
+
+    const fn default_timeout_secs() -> u64 {
+        15
+    }
+
+    fn default_service_host_env() -> String {
+        "QBITTORRENT_SERVICE_HOST".to_string()
+    }
+
+    fn default_service_port_env() -> String {
+        "QBITTORRENT_SERVICE_PORT".to_string()
+    }
+}
+
+impl DelugeConfig {
+    /// The configured password, else `QB_PORT_SYNC_DELUGE_PASSWORD`.
+    pub fn resolved_password(&self) -> Result<String> {
+        if let Some(pass) = self.password.as_deref().filter(|p| !p.trim().is_empty()) {
+            return Ok(pass.to_string());
+        }
+        if let Ok(env_pass) = env::var("QB_PORT_SYNC_DELUGE_PASSWORD") {
+            if !env_pass.trim().is_empty() {
+                return Ok(env_pass);
             }
         }
+        Err(ConfigError::MissingDelugePassword.into())
+    }
+
+    pub fn resolved_base_url(&self) -> Result<Url> {
+        Url::parse(&self.base_url)
+            .map_err(|err| ConfigError::InvalidBaseUrl(format!("{}: {err}", self.base_url)).into())
+    }
+
+    /// HTTP timeout for Deluge requests, `None` when `timeout_secs = 0`.
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+
+    fn default_base_url() -> String {
+        "http://127.0.0.1:8112".to_string()
+    }
+
+    const fn default_timeout_secs() -> u64 {
+        15
+    }
+}
+
+impl TransmissionConfig {
+    /// The configured password, else `QB_PORT_SYNC_TRANSMISSION_PASSWORD`;
+    /// `None` when RPC authentication is not used.
+    pub fn resolved_password(&self) -> Option<String> {
+        self.password
+            .clone()
+            .or_else(|| env::var("QB_PORT_SYNC_TRANSMISSION_PASSWORD").ok())
+            .filter(|pass| !pass.trim().is_empty())
+    }
+
+    /// Basic-auth credentials, when a username is set. A username without a
+    /// password is a configuration error.
+    pub fn credentials(&self) -> Result<Option<(String, String)>> {
+        let Some(username) = self.username.clone() else {
+            return Ok(None);
+        };
+        let password = self
+            .resolved_password()
+            .ok_or(ConfigError::MissingTransmissionPassword)?;
+        Ok(Some((username, password)))
+    }
+
+    pub fn resolved_base_url(&self) -> Result<Url> {
+        Url::parse(&self.base_url)
+            .map_err(|err| ConfigError::InvalidBaseUrl(format!("{}: {err}", self.base_url)).into())
+    }
+
+    /// HTTP timeout for Transmission requests, `None` when `timeout_secs = 0`.
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+
+    fn default_base_url() -> String {
+        "http://127.0.0.1:9091/transmission/rpc".to_string()
+    }
+
+    const fn default_timeout_secs() -> u64 {
+        15
+    }
+}
+
+impl ProtonVpnConfig {
+    /// The `http` strategy's endpoint; required for that strategy.
+    pub fn resolved_control_server_url(&self) -> Result<Url> {
+        let raw = self
+            .control_server_url
+            .as_deref()
+            .ok_or(ConfigError::MissingControlServerUrl)?;
+        Url::parse(raw)
+            .map_err(|err| ConfigError::InvalidControlServerUrl(format!("{raw}: {err}")).into())
+    }
+
+    const fn default_poll_interval_secs() -> u64 {
+        5
+    }
+
+    const fn default_initial_read_timeout_secs() -> u64 {
+        10
+    }
+
+    const fn default_max_file_bytes() -> u64 {
+        4096
+    }
+
+    const fn default_apply_on_start() -> bool {
+        true
+    }
+
+    const fn default_debounce_ms() -> u64 {
+        250
+    }
+}
+
+impl Default for ProtonVpnConfig {
+    fn default() -> Self {
+        ProtonVpnConfig {
+            forwarded_port_path: None,
+            forwarded_port_paths: Vec::new(),
+            port_file_format: PortFileFormat::default(),
+            watch_mode: WatchMode::default(),
+            poll_interval_secs: Self::default_poll_interval_secs(),
+            initial_read_timeout_secs: Self::default_initial_read_timeout_secs(),
+            max_file_bytes: Self::default_max_file_bytes(),
+            apply_on_start: Self::default_apply_on_start(),
+            debounce_ms: Self::default_debounce_ms(),
+            control_server_url: None,
+        }
+    }
+}
+
+impl MetricsConfig {
+    fn default_namespace() -> String {
+        "qb_port_sync".to_string()
+    }
+
+    /// Parsed `bind_address`, defaulting to `0.0.0.0`. Shared by the
+    /// standalone health server.
+    pub fn bind_ip(&self) -> Result<IpAddr> {
+        match self.bind_address.as_deref().map(str::trim) {
+            Some(raw) => raw
+                .parse()
+                .map_err(|_| ConfigError::InvalidBindAddress(raw.to_string()).into()),
+            None => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        }
+    }
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        MetricsConfig {
+            enabled: false,
+            port: 0,
+            namespace: Self::default_namespace(),
+            bind_address: None,
+            textfile_path: None,
+        }
+    }
+}
+
+impl DaemonConfig {
+    const fn default_recent_port_window_secs() -> u64 {
+        120
+    }
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        DaemonConfig {
+            max_consecutive_failures: 0,
+            min_apply_interval_secs: 0,
+            state_file: None,
+            ignore_recent_ports: 0,
+            recent_port_window_secs: Self::default_recent_port_window_secs(),
+            startup_delay_secs: 0,
+            lock_file: None,
+            log_file: None,
+        }
     }
 }
 
@@ -154,14 +1802,178 @@ impl PortMapConfig {
         300
     }
 
+    const fn default_min_refresh_secs() -> u64 {
+        60
+    }
+
+    const fn default_max_refresh_secs() -> u64 {
+        3600
+    }
+
+    const fn default_max_backoff_secs() -> u64 {
+        1800
+    }
+
     const fn default_autodiscover() -> bool {
         true
     }
+
+    const fn default_max_retries() -> u32 {
+        3
+    }
+
+    /// Mapping lifetime to request: `lease_secs`, else `refresh_secs`.
+    pub fn lease_secs(&self) -> u64 {
+        self.lease_secs.unwrap_or(self.refresh_secs)
+    }
+
+    pub fn client_ip_addr(&self) -> Result<Option<IpAddr>> {
+        self.client_ip
+            .as_deref()
+            .map(str::trim)
+            .map(|raw| {
+                raw.parse()
+                    .map_err(|_| ConfigError::InvalidClientIp(raw.to_string()).into())
+            })
+            .transpose()
+    }
+}
+
+impl PortRange {
+    const fn default_min() -> u16 {
+        1
+    }
+
+    const fn default_max() -> u16 {
+        u16::MAX
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        (self.min..=self.max).contains(&port)
+    }
 }
 
-fn find_config(cli_path: Option<PathBuf>) -> Result<PathBuf> {
+impl Default for PortRange {
+    fn default() -> Self {
+        PortRange {
+            min: Self::default_min(),
+            max: Self::default_max(),
+        }
+    }
+}
+
+/// How `net.bind_interface` selects an interface: `re:` starts a regex, a
+/// value with `*` or `?` is a glob, anything else must match exactly.
+#[derive(Debug, Clone)]
+pub enum InterfacePattern {
+    Exact(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl InterfacePattern {
+    pub fn parse(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        if let Some(pattern) = raw.strip_prefix("re:") {
+            return Regex::new(pattern)
+                .map(InterfacePattern::Regex)
+                .map_err(|err| {
+                    ConfigError::InvalidInterfacePattern(pattern.to_string(), err.to_string())
+                        .into()
+                });
+        }
+        if raw.contains(['*', '?']) {
+            Ok(InterfacePattern::Glob(raw.to_string()))
+        } else {
+            Ok(InterfacePattern::Exact(raw.to_string()))
+        }
+    }
+
+    pub fn matches(&self, candidate: &str) -> bool {
+        match self {
+            InterfacePattern::Exact(name) => !name.is_empty() && candidate == name,
+            InterfacePattern::Glob(glob) => glob_matches(glob, candidate),
+            InterfacePattern::Regex(regex) => regex.is_match(candidate),
+        }
+    }
+}
+
+/// Whole-string glob match where `*` is any run of characters and `?` is
+/// exactly one.
+fn glob_matches(glob: &str, candidate: &str) -> bool {
+    let glob: Vec<char> = glob.chars().collect();
+    let candidate: Vec<char> = candidate.chars().collect();
+    let (mut g, mut c) = (0, 0);
+    // Position after the last `*` and the candidate index it resumes from.
+    let mut backtrack = None;
+    while c < candidate.len() {
+        match glob.get(g) {
+            Some('*') => {
+                g += 1;
+                backtrack = Some((g, c));
+            }
+            Some(&ch) if ch == '?' || ch == candidate[c] => {
+                g += 1;
+                c += 1;
+            }
+            _ => match backtrack {
+                Some((star_g, star_c)) => {
+                    g = star_g;
+                    c = star_c + 1;
+                    backtrack = Some((star_g, star_c + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    glob[g..].iter().all(|&ch| ch == '*')
+}
+
+/// PCP needs the client address in the same family as the gateway it asks.
+pub fn ensure_same_family(client_ip: IpAddr, gateway: IpAddr) -> Result<()> {
+    if client_ip.is_ipv4() == gateway.is_ipv4() {
+        Ok(())
+    } else {
+        Err(ConfigError::ClientIpFamilyMismatch(client_ip, gateway).into())
+    }
+}
+
+impl NetConfig {
+    const fn default_retry_interface_id() -> bool {
+        true
+    }
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig {
+            bind_interface: None,
+            retry_interface_id: Self::default_retry_interface_id(),
+            leak_protect_on_startup: false,
+        }
+    }
+}
+
+impl EventsConfig {
+    const fn default_capacity() -> usize {
+        100
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> Self {
+        EventsConfig {
+            capacity: Self::default_capacity(),
+        }
+    }
+}
+
+/// Locates the config file. Returns `None` instead of failing when no file
+/// exists but the environment provides the required qBittorrent base URL
+/// and username, so containers can run from environment variables alone.
+fn find_config(cli_path: Option<PathBuf>) -> Result<Option<PathBuf>> {
     if let Some(path) = cli_path {
-        return Ok(path);
+        return Ok(Some(path));
     }
 
     let mut candidates = Vec::new();
@@ -185,13 +1997,96 @@ fn find_config(cli_path: Option<PathBuf>) -> Result<PathBuf> {
     for candidate in candidates {
         if candidate.exists() {
             debug!("using configuration file at {}", candidate.display());
-            return Ok(candidate);
+            return Ok(Some(candidate));
         }
     }
 
+    let required = ["base_url", "username"];
+    if required
+        .iter()
+        .all(|field| env_override("qbittorrent", field).is_some())
+    {
+        debug!("no configuration file found; using environment variables only");
+        return Ok(None);
+    }
+
     Err(ConfigError::MissingConfig.into())
 }
 
+fn env_var_name(section: &str, field: &str) -> String {
+    let section = match section {
+        "qbittorrent" => "QB".to_string(),
+        other => other.to_uppercase(),
+    };
+    format!("{ENV_PREFIX}_{section}_{}", field.to_uppercase())
+}
+
+/// Reads the override for `section.field`; empty values count as unset.
+fn env_override(section: &str, field: &str) -> Option<(String, String)> {
+    let name = env_var_name(section, field);
+    let value = env::var(&name).ok().filter(|v| !v.trim().is_empty())?;
+    Some((name, value))
+}
+
+/// Overlays every `ENV_OVERRIDES` variable that is set onto the raw table.
+/// qBittorrent values go to the first instance. Returns the rendered keys
+/// that were overridden so their source can be reported as `env`.
+fn apply_env_overrides(table: &mut toml::Value) -> Result<Vec<String>> {
+    let Some(root) = table.as_table_mut() else {
+        return Ok(Vec::new());
+    };
+    let mut keys = Vec::new();
+    for &(section, field, kind) in ENV_OVERRIDES {
+        let Some((name, raw)) = env_override(section, field) else {
+            continue;
+        };
+        let invalid = || ConfigError::InvalidEnvOverride(name.clone(), raw.clone());
+        let value = match kind {
+            EnvKind::Str => toml::Value::String(raw.clone()),
+            EnvKind::Int => toml::Value::Integer(raw.trim().parse().map_err(|_| invalid())?),
+            EnvKind::Bool => {
+                toml::Value::Boolean(raw.trim().to_lowercase().parse().map_err(|_| invalid())?)
+            }
+            EnvKind::List => toml::Value::Array(
+                raw.split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| toml::Value::String(item.to_string()))
+                    .collect(),
+            ),
+        };
+
+        let entry = root
+            .entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+        let (target, key) = match entry {
+            toml::Value::Array(items) if items.len() > 1 => {
+                (&mut items[0], format!("{section}[0].{field}"))
+            }
+            toml::Value::Array(items) if !items.is_empty() => {
+                (&mut items[0], format!("{section}.{field}"))
+            }
+            other => (other, format!("{section}.{field}")),
+        };
+        if let Some(target) = target.as_table_mut() {
+            debug!("overriding {key} from {name}");
+            target.insert(field.to_string(), value);
+            keys.push(key);
+        }
+    }
+    Ok(keys)
+}
+
+/// Prometheus metric names match `[a-zA-Z_:][a-zA-Z0-9_:]*`; colons are
+/// reserved for recording rules, so a namespace may not use them.
+fn is_metric_name_segment(value: &str) -> bool {
+    let mut chars = value.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 #[cfg(target_os = "linux")]
 fn linux_default_forwarded_port_path() -> PathBuf {
     if let Some(runtime_dir) = env::var_os("XDG_RUNTIME_DIR") {
@@ -207,6 +2102,127 @@ fn linux_default_forwarded_port_path() -> PathBuf {
     ))
 }
 
+/// Resolves a rendered key such as `portmap.gateway` or
+/// `qbittorrent[1].base_url` against a raw TOML table.
+fn lookup_key<'a>(table: &'a toml::Value, key: &str) -> Option<&'a toml::Value> {
+    let Some((section, field)) = key.split_once('.') else {
+        return table.get(key);
+    };
+    let value = match section.split_once('[') {
+        Some((name, index)) => {
+            let index: usize = index.trim_end_matches(']').parse().ok()?;
+            table.get(name)?.get(index)?
+        }
+        None => {
+            let value = table.get(section)?;
+            // A single-element array renders without an index.
+            match value.as_array() {
+                Some(items) => items.first()?,
+                None => value,
+            }
+        }
+    };
+    value.get(field)
+}
+
+/// Accepts either a single `[qbittorrent]` table or a `[[qbittorrent]]` array.
+fn one_or_many_instances<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Vec<QbittorrentConfig>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<QbittorrentConfig>),
+        Many(Vec<QbittorrentConfig>),
+    }
+
+    let instances = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(instance) => vec![*instance],
+        OneOrMany::Many(instances) => instances,
+    };
+    if instances.is_empty() {
+        return Err(serde::de::Error::custom(
+            "at least one qbittorrent instance is required",
+        ));
+    }
+    Ok(instances)
+}
+
+/// Recursively overlays `overlay` onto `base`: tables merge key by key,
+/// any other value replaces the base value outright.
+fn merge_toml(base: &mut toml::Value, overlay: toml::Value) {
+    match (base, overlay) {
+        (toml::Value::Table(base), toml::Value::Table(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Accepts a string or an array of strings, dropping empty entries.
+fn one_or_many_strings<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let values = match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    };
+    Ok(values
+        .iter()
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+fn redacted<S>(value: &Option<String>, serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Keeps the header names but hides the values, which often carry
+/// credentials.
+fn redacted_headers<S>(
+    value: &Option<HashMap<String, String>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match value {
+        Some(headers) => serializer.serialize_some(
+            &headers
+                .keys()
+                .map(|name| (name.as_str(), "<redacted>"))
+                .collect::<BTreeMap<_, _>>(),
+        ),
+        None => serializer.serialize_none(),
+    }
+}
+
 fn empty_string_as_none<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -238,3 +2254,94 @@ where
         }
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_matches, is_metric_name_segment, Config, InterfacePattern};
+
+    fn minimal() -> Config {
+        toml::from_str(
+            r#"
+[qbittorrent]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+
+[protonvpn]
+
+[portmap]
+"#,
+        )
+        .expect("minimal config deserializes")
+    }
+
+    #[test]
+    fn metrics_namespace_must_be_a_metric_name_segment() {
+        assert!(is_metric_name_segment("acme_vpn"));
+        assert!(is_metric_name_segment("_private"));
+        for invalid in ["", "9lives", "acme-vpn", "acme:vpn"] {
+            assert!(!is_metric_name_segment(invalid), "{invalid:?} accepted");
+        }
+    }
+
+    #[test]
+    fn portmap_bounds_are_validated() {
+        assert!(minimal().validate().is_ok());
+
+        let mut config = minimal();
+        config.portmap.acceptable_range.min = 0;
+        assert!(config.validate().is_err());
+        let mut config = minimal();
+        config.portmap.acceptable_range.min = 2000;
+        config.portmap.acceptable_range.max = 1000;
+        let err = config.validate().unwrap_err();
+        assert!(format!("{err:#}").contains("acceptable_range"));
+
+        let mut config = minimal();
+        config.portmap.min_refresh_secs = 600;
+        config.portmap.max_refresh_secs = 60;
+        let err = config.validate().unwrap_err();
+        assert!(format!("{err:#}").contains("min_refresh_secs"));
+
+        let mut config = minimal();
+        config.portmap.lease_secs = Some(0);
+        let err = config.validate().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("lease_secs must be greater than 0"));
+        // 2^32 would wrap to a zero lifetime in the 32-bit request field.
+        config.portmap.lease_secs = Some(1 << 32);
+        let err = config.validate().unwrap_err();
+        assert!(err.to_string().contains("exceeds the protocol maximum"));
+    }
+
+    #[test]
+    fn globs_match_the_whole_name() {
+        assert!(glob_matches("proton*", "proton0"));
+        assert!(glob_matches("proton*", "proton"));
+        assert!(!glob_matches("proton*", "wg-proton"));
+        assert!(glob_matches("*proton", "wg-proton"));
+        assert!(glob_matches("tun?", "tun1"));
+        assert!(!glob_matches("tun?", "tun10"));
+        assert!(glob_matches("*ks*f?", "pvpnksintrf0"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("?", ""));
+    }
+
+    #[test]
+    fn interface_patterns_pick_their_kind() {
+        assert!(matches!(
+            InterfacePattern::parse("tun0").unwrap(),
+            InterfacePattern::Exact(_)
+        ));
+        assert!(matches!(
+            InterfacePattern::parse(" tun* ").unwrap(),
+            InterfacePattern::Glob(_)
+        ));
+        assert!(InterfacePattern::parse("re:^tun\\d$")
+            .unwrap()
+            .matches("tun0"));
+        let err = InterfacePattern::parse("re:proton(").unwrap_err();
+        assert!(err.to_string().contains("proton("));
+    }
+}
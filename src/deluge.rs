@@ -0,0 +1,308 @@
+use crate::client::TorrentClient;
+use crate::error::{DelugeError, Result, UnsupportedError};
+use crate::qbit::{InterfaceBinding, PortStatus, PortUpdateResult};
+use async_trait::async_trait;
+use reqwest::{Client, Url};
+use serde_json::{json, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// JSON-RPC error code Deluge's Web UI returns once the session is gone.
+const NOT_AUTHENTICATED: i64 = 1;
+
+/// Config keys read back after an update.
+const PORT_KEYS: [&str; 3] = ["listen_ports", "random_port", "upnp"];
+
+/// Client for the Deluge Web UI's JSON-RPC endpoint (`<base_url>/json`).
+/// Port changes go to the connected daemon through `core.set_config`.
+pub struct DelugeClient {
+    client: Client,
+    endpoint: Url,
+    password: Option<String>,
+    dry_run: bool,
+    next_id: AtomicU64,
+}
+
+impl DelugeClient {
+    /// Builds a client for the Web UI at `base_url`. `timeout` bounds each
+    /// request; `None` disables the timeout.
+    pub fn new(base_url: Url, timeout: Option<Duration>) -> Result<Self> {
+        let endpoint = base_url
+            .join("json")
+            .map_err(|err| anyhow::anyhow!("invalid Deluge base_url {base_url}: {err}"))?;
+        let mut builder = Client::builder()
+            .cookie_store(true)
+            .user_agent("qb-port-sync");
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            endpoint,
+            password: None,
+            dry_run: false,
+            next_id: AtomicU64::new(1),
+        })
+    }
+
+    /// In dry-run mode config updates are logged instead of submitted.
+    pub fn with_dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Logs in to the Web UI and makes sure it is connected to a daemon.
+    /// The password is kept so an expired session can be renewed.
+    pub async fn login(&mut self, password: &str) -> Result<()> {
+        self.authenticate(password).await?;
+        self.password = Some(password.to_string());
+        self.ensure_connected().await?;
+        info!("authenticated with the Deluge Web UI");
+        Ok(())
+    }
+
+    async fn authenticate(&self, password: &str) -> Result<()> {
+        match self.call("auth.login", json!([password])).await? {
+            Value::Bool(true) => Ok(()),
+            _ => Err(DelugeError::Auth.into()),
+        }
+    }
+
+    /// Connects the Web UI to the first known daemon when it has none.
+    async fn ensure_connected(&self) -> Result<()> {
+        if self.call("web.connected", json!([])).await? == Value::Bool(true) {
+            return Ok(());
+        }
+        let hosts = self.call("web.get_hosts", json!([])).await?;
+        let host_id = first_host_id(&hosts).ok_or(DelugeError::NoDaemon)?;
+        debug!("connecting the Deluge Web UI to daemon {host_id}");
+        self.call("web.connect", json!([host_id])).await?;
+        Ok(())
+    }
+
+    /// Calls `method`, logging in again once when the session has expired.
+    async fn call_with_reauth(&self, method: &str, params: Value) -> Result<Value> {
+        match self.call(method, params.clone()).await {
+            Err(err) if is_not_authenticated(&err) => {
+                let Some(password) = self.password.as_deref() else {
+                    return Err(err);
+                };
+                warn!("Deluge session expired; re-authenticating");
+                self.authenticate(password).await?;
+                self.ensure_connected().await?;
+                self.call(method, params).await
+            }
+            other => other,
+        }
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let body = json!({ "method": method, "params": params, "id": id });
+        let response = self
+            .client
+            .post(self.endpoint.clone())
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(DelugeError::Rpc {
+                method: method.to_string(),
+                message: format!("{status} {message}"),
+            }
+            .into());
+        }
+        rpc_result(method, response.json::<Value>().await?)
+    }
+
+    async fn read_port_status(&self) -> Result<PortStatus> {
+        let values = self
+            .call_with_reauth("core.get_config_values", json!([PORT_KEYS]))
+            .await?;
+        port_status(&values)
+    }
+}
+
+#[async_trait]
+impl TorrentClient for DelugeClient {
+    async fn set_listen_port(
+        &self,
+        port: u16,
+        bind_interface: Option<&str>,
+    ) -> Result<PortUpdateResult> {
+        if bind_interface.is_some() {
+            return Err(UnsupportedError::new("Deluge does not support bind_interface").into());
+        }
+        let update = json!({
+            "listen_ports": [port, port],
+            "random_port": false,
+            "upnp": false,
+        });
+        if self.dry_run {
+            info!("dry run: would set Deluge config {}", update);
+            return Ok(PortUpdateResult {
+                detected_port: port,
+                previous_port: None,
+                verified: false,
+                random_port: None,
+                upnp: None,
+                interface_id_missing: false,
+                interface_absent: false,
+                listen_address: None,
+                reachable: None,
+            });
+        }
+
+        // Read first, so a port another program set since the last update
+        // shows up.
+        let previous_port = self.read_port_status().await?.listen_port;
+        self.call_with_reauth("core.set_config", json!([update]))
+            .await?;
+        let status = self.read_port_status().await?;
+        let verified = status.listen_port == port;
+        if verified {
+            info!("Deluge listen port verified at {}", status.listen_port);
+        } else {
+            warn!(
+                "Deluge listen port mismatch after update: expected {}, reported {}",
+                port, status.listen_port
+            );
+        }
+        Ok(PortUpdateResult {
+            detected_port: status.listen_port,
+            previous_port: Some(previous_port),
+            verified,
+            random_port: status.random_port,
+            upnp: status.upnp,
+            interface_id_missing: false,
+            interface_absent: false,
+            listen_address: None,
+            reachable: None,
+        })
+    }
+
+    async fn get_port_status(&self) -> Result<PortStatus> {
+        self.read_port_status().await
+    }
+
+    async fn apply_interface_binding(&self, _interface: &str) -> Result<InterfaceBinding> {
+        Err(UnsupportedError::new("Deluge does not support binding to an interface").into())
+    }
+
+    async fn apply_safe_binding(&self, _interface: &str) -> Result<String> {
+        Err(UnsupportedError::new("Deluge does not support binding to an interface").into())
+    }
+}
+
+/// Unwraps a JSON-RPC response, turning its `error` member into an error.
+fn rpc_result(method: &str, mut response: Value) -> Result<Value> {
+    match response.get("error") {
+        None | Some(Value::Null) => Ok(response
+            .get_mut("result")
+            .map(Value::take)
+            .unwrap_or(Value::Null)),
+        Some(error) => {
+            let code = error.get("code").and_then(Value::as_i64);
+            if code == Some(NOT_AUTHENTICATED) {
+                return Err(DelugeError::Auth.into());
+            }
+            let message = error
+                .get("message")
+                .and_then(Value::as_str)
+                .map(str::to_string)
+                .unwrap_or_else(|| error.to_string());
+            Err(DelugeError::Rpc {
+                method: method.to_string(),
+                message,
+            }
+            .into())
+        }
+    }
+}
+
+fn is_not_authenticated(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<DelugeError>(), Some(DelugeError::Auth))
+}
+
+/// `web.get_hosts` lists `[id, host, port, ...]` entries.
+fn first_host_id(hosts: &Value) -> Option<String> {
+    hosts
+        .as_array()?
+        .first()?
+        .as_array()?
+        .first()?
+        .as_str()
+        .map(str::to_string)
+}
+
+/// Reads the lower end of `listen_ports` along with the `random_port` and
+/// `upnp` flags.
+fn port_status(values: &Value) -> Result<PortStatus> {
+    let listen_port = values
+        .get("listen_ports")
+        .and_then(Value::as_array)
+        .and_then(|ports| ports.first())
+        .and_then(Value::as_u64)
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or_else(|| anyhow::anyhow!("Deluge config missing listen_ports"))?;
+    Ok(PortStatus {
+        listen_port,
+        random_port: values.get("random_port").and_then(Value::as_bool),
+        upnp: values.get("upnp").and_then(Value::as_bool),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{first_host_id, is_not_authenticated, port_status, rpc_result};
+    use crate::qbit::PortStatus;
+    use serde_json::json;
+
+    #[test]
+    fn rpc_result_unwraps_result_and_errors() {
+        let ok = json!({ "result": true, "error": null, "id": 1 });
+        assert_eq!(rpc_result("auth.login", ok).unwrap(), json!(true));
+
+        let expired = json!({
+            "result": null,
+            "error": { "message": "Not authenticated", "code": 1 },
+            "id": 2
+        });
+        assert!(is_not_authenticated(
+            &rpc_result("core.set_config", expired).unwrap_err()
+        ));
+
+        let failed = json!({
+            "result": null,
+            "error": { "message": "Unknown method", "code": 2 },
+            "id": 3
+        });
+        let err = rpc_result("core.nope", failed).unwrap_err();
+        assert!(!is_not_authenticated(&err));
+        assert_eq!(err.to_string(), "Deluge core.nope failed: Unknown method");
+    }
+
+    #[test]
+    fn first_host_id_reads_the_first_entry() {
+        let hosts = json!([["c1a2", "127.0.0.1", 58846, "localclient"]]);
+        assert_eq!(first_host_id(&hosts).as_deref(), Some("c1a2"));
+        assert_eq!(first_host_id(&json!([])), None);
+    }
+
+    #[test]
+    fn port_status_reads_the_lower_listen_port() {
+        let values = json!({ "listen_ports": [51820, 51820], "random_port": false, "upnp": true });
+        assert_eq!(
+            port_status(&values).unwrap(),
+            PortStatus {
+                listen_port: 51820,
+                random_port: Some(false),
+                upnp: Some(true),
+            }
+        );
+        assert!(port_status(&json!({ "upnp": false })).is_err());
+    }
+}
@@ -3,12 +3,84 @@ use thiserror::Error;
 
 pub type Result<T> = anyhow::Result<T>;
 
+/// Process exit codes. `Transient` and `Unreachable` are both worth retrying;
+/// `Unreachable` singles out transport failures (connection refused, DNS,
+/// timeout) talking to qBittorrent so alerting can tell them apart from a
+/// failed gateway mapping, which stays `Transient`.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ExitCode {
     Success = 0,
     Transient = 1,
     Config = 2,
     Unsupported = 3,
+    Unreachable = 4,
+}
+
+impl ExitCode {
+    /// Every exit code, in numeric order.
+    pub const ALL: [ExitCode; 5] = [
+        ExitCode::Success,
+        ExitCode::Transient,
+        ExitCode::Config,
+        ExitCode::Unsupported,
+        ExitCode::Unreachable,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ExitCode::Success => "Success",
+            ExitCode::Transient => "Transient",
+            ExitCode::Config => "Config",
+            ExitCode::Unsupported => "Unsupported",
+            ExitCode::Unreachable => "Unreachable",
+        }
+    }
+
+    /// Whether a later attempt may succeed without changing anything.
+    pub fn is_retryable(self) -> bool {
+        matches!(self, ExitCode::Transient | ExitCode::Unreachable)
+    }
+
+    /// Ranks codes when one failure must stand for several: one that needs
+    /// fixing outranks one worth retrying, and `Unreachable` outranks the
+    /// generic `Transient`.
+    pub fn severity(self) -> u8 {
+        match self {
+            ExitCode::Success => 0,
+            ExitCode::Transient => 1,
+            ExitCode::Unreachable => 2,
+            ExitCode::Unsupported => 3,
+            ExitCode::Config => 4,
+        }
+    }
+
+    /// Machine-readable kind reported as `error_kind` in JSON reports.
+    pub fn error_kind(self) -> Option<&'static str> {
+        match self {
+            ExitCode::Success => None,
+            ExitCode::Transient => Some("transient"),
+            ExitCode::Config => Some("config"),
+            ExitCode::Unsupported => Some("unsupported"),
+            ExitCode::Unreachable => Some("unreachable"),
+        }
+    }
+
+    /// What the code means, for `--explain-exit-codes`.
+    pub fn meaning(self) -> &'static str {
+        match self {
+            ExitCode::Success => "success, including a run where the port was already set",
+            ExitCode::Transient => {
+                "transient failure (network, authentication, gateway or an unverified update); retry later"
+            }
+            ExitCode::Config => "configuration or usage error; fix the config or arguments",
+            ExitCode::Unsupported => {
+                "unsupported environment (strategy not built in or not offered by the gateway)"
+            }
+            ExitCode::Unreachable => {
+                "qBittorrent unreachable (connection refused, DNS failure or timeout); retry later"
+            }
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -23,6 +95,62 @@ pub enum ConfigError {
     MissingQbPassword,
     #[error("forwarded port path unavailable: {0}")]
     ForwardedPortUnavailable(String),
+    #[error("invalid qbittorrent base_url {0}")]
+    InvalidBaseUrl(String),
+    #[error("invalid qbittorrent header {0}: {1}")]
+    InvalidHeader(String, String),
+    #[error("the http strategy requires protonvpn.control_server_url")]
+    MissingControlServerUrl,
+    #[error("invalid protonvpn.control_server_url {0}")]
+    InvalidControlServerUrl(String),
+    #[error("qbittorrent base_url is empty and {0}/{1} are not set")]
+    MissingBaseUrl(String, String),
+    #[error("profile '{0}' not found under [profiles] in config file")]
+    UnknownProfile(String),
+    #[error("--apply-interface-only requires net.bind_interface to be set")]
+    MissingBindInterface,
+    #[error("qbittorrent.only_if_interface_present requires net.bind_interface to be set")]
+    InterfaceGuardWithoutInterface,
+    #[error("invalid net.bind_interface regex {0}: {1}")]
+    InvalidInterfacePattern(String, String),
+    #[error("another qb-port-sync{1} holds the lock file {0}")]
+    AlreadyRunning(String, String),
+    #[error("failed to lock {0}: {1}")]
+    LockFile(String, String),
+    #[error("also_set_keys entry '{0}' is not an allowed port preference")]
+    UnsupportedPreferenceKey(String),
+    #[error("metrics namespace '{0}' is not a valid metric name prefix (letters, digits and _, not starting with a digit)")]
+    InvalidMetricsNamespace(String),
+    #[error("environment variable {0} has an invalid value '{1}'")]
+    InvalidEnvOverride(String, String),
+    #[error("forwarded port file {0} is larger than {1} bytes; is something else writing to it?")]
+    PortFileTooLarge(String, u64),
+    #[error("failed to load TLS CA certificate {0}: {1}")]
+    InvalidCaCert(String, String),
+    #[error("metrics bind_address '{0}' is not an IP address")]
+    InvalidBindAddress(String),
+    #[error("no health endpoint to probe: enable [health] or [metrics] with a port, or pass --health-url")]
+    HealthEndpointDisabled,
+    #[error("portmap.min_refresh_secs ({0}) is greater than portmap.max_refresh_secs ({1})")]
+    InvalidRefreshBounds(u64, u64),
+    #[error("portmap.lease_secs must be greater than 0; a zero lifetime deletes the mapping")]
+    ZeroLeaseSecs,
+    #[error("requested mapping lifetime of {0}s (portmap.lease_secs, else refresh_secs) exceeds the protocol maximum of 4294967295s")]
+    LeaseSecsTooLong(u64),
+    #[error("portmap.acceptable_range min ({0}) must be at least 1 and no greater than max ({1})")]
+    InvalidAcceptableRange(u16, u16),
+    #[error("portmap.client_ip '{0}' is not an IP address")]
+    InvalidClientIp(String),
+    #[error("portmap.client_ip {0} and gateway {1} are not the same address family")]
+    ClientIpFamilyMismatch(std::net::IpAddr, std::net::IpAddr),
+    #[error("client.kind = \"{0}\" requires a [{0}] section")]
+    MissingClientSection(&'static str),
+    #[error("missing deluge password (set in config or QB_PORT_SYNC_DELUGE_PASSWORD)")]
+    MissingDelugePassword,
+    #[error("missing transmission password (set in config or QB_PORT_SYNC_TRANSMISSION_PASSWORD)")]
+    MissingTransmissionPassword,
+    #[error("{0} is not supported with client.kind = \"{1}\"")]
+    UnsupportedByClient(&'static str, &'static str),
 }
 
 #[derive(Debug, Error)]
@@ -36,6 +164,39 @@ pub enum QbitError {
     },
     #[error("failed to deserialize qBittorrent preferences: {0}")]
     Deserialize(#[from] serde_json::Error),
+    #[error("interface '{0}' not found in qBittorrent's network interface list")]
+    InterfaceNotFound(String),
+    #[error(
+        "expected JSON from qBittorrent but got {0}; check the base URL points at the WebUI, not a login redirect"
+    )]
+    NotJson(String),
+    #[error(
+        "qBittorrent has banned this address after too many failed logins; not retrying for {}s",
+        .0.as_secs()
+    )]
+    Banned(std::time::Duration),
+    #[error("request to qBittorrent failed: {0}")]
+    Transport(reqwest::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum DelugeError {
+    #[error("Deluge rejected the Web UI password")]
+    Auth,
+    #[error("Deluge {method} failed: {message}")]
+    Rpc { method: String, message: String },
+    #[error("the Deluge Web UI is not connected to a daemon and knows no host to connect to")]
+    NoDaemon,
+}
+
+#[derive(Debug, Error)]
+pub enum TransmissionError {
+    #[error("Transmission rejected the RPC credentials")]
+    Auth,
+    #[error("Transmission {method} failed: {result}")]
+    Rpc { method: String, result: String },
+    #[error("Transmission answered 409 without an X-Transmission-Session-Id header")]
+    MissingSessionId,
 }
 
 #[derive(Debug, Error)]
@@ -47,6 +208,21 @@ pub enum PortMapError {
     PcpNotSupported(String),
     #[error("nat-pmp mapping failed: {0}")]
     NatPmp(String),
+    #[cfg_attr(not(feature = "upnp"), allow(dead_code))]
+    #[error("upnp mapping failed: {0}")]
+    Upnp(String),
+    #[error("upnp not supported: {0}")]
+    UpnpNotSupported(String),
+    #[error("gateway refused the mapping request: {0}")]
+    Refused(String),
+    #[error("gateway mapped TCP to external port {tcp} but UDP to {udp}")]
+    ProtocolPortMismatch { tcp: u16, udp: u16 },
+    #[error("every configured gateway failed: {0}")]
+    AllGatewaysFailed(String),
+    #[error("gateway mapped external port {port}, outside portmap.acceptable_range {min}-{max}; not applying it")]
+    UnacceptablePort { port: u16, min: u16, max: u16 },
+    #[error("gateway mapped external port {granted} instead of the requested {requested}; portmap.require_exact_external rejects it")]
+    ExternalPortMismatch { requested: u16, granted: u16 },
 }
 
 #[derive(Debug, Error)]
@@ -70,10 +246,42 @@ pub fn classify_error(err: &Error) -> ExitCode {
 
     if let Some(port_err) = err.downcast_ref::<PortMapError>() {
         return match port_err {
-            PortMapError::PcpNotSupported(_) => ExitCode::Unsupported,
+            PortMapError::PcpNotSupported(_) | PortMapError::UpnpNotSupported(_) => {
+                ExitCode::Unsupported
+            }
             _ => ExitCode::Transient,
         };
     }
 
+    if let Some(QbitError::Transport(err)) = err.downcast_ref::<QbitError>() {
+        if err.is_connect() || err.is_timeout() {
+            return ExitCode::Unreachable;
+        }
+    }
+
     ExitCode::Transient
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ExitCode;
+
+    #[test]
+    fn all_lists_every_code_in_order() {
+        let codes: Vec<i32> = ExitCode::ALL.iter().map(|&code| code as i32).collect();
+        assert_eq!(codes, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn severity_ranks_codes_that_need_fixing_first() {
+        let worst = |codes: &[ExitCode]| codes.iter().copied().max_by_key(|code| code.severity());
+        assert_eq!(
+            worst(&[ExitCode::Transient, ExitCode::Unreachable]),
+            Some(ExitCode::Unreachable)
+        );
+        assert_eq!(
+            worst(&[ExitCode::Config, ExitCode::Unreachable, ExitCode::Transient]),
+            Some(ExitCode::Config)
+        );
+    }
+}
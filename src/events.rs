@@ -0,0 +1,81 @@
+use crate::report::rfc3339;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    PortApplied,
+    ApplyFailed,
+    MappingFailed,
+    Relogin,
+    HookFailed,
+    PublicIpChanged,
+    ConfigReloaded,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp: String,
+    pub kind: EventKind,
+    pub detail: String,
+}
+
+/// Bounded log of recent daemon events, shared between the sync loops and
+/// the HTTP server. Once full, the oldest event is dropped.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    inner: Arc<Mutex<VecDeque<Event>>>,
+    capacity: usize,
+}
+
+impl EventLog {
+    pub fn new(capacity: usize) -> Self {
+        EventLog {
+            inner: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, kind: EventKind, detail: impl Into<String>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let event = Event {
+            timestamp: rfc3339(SystemTime::now()),
+            kind,
+            detail: detail.into(),
+        };
+        let mut events = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        while events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Returns the buffered events, oldest first.
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    pub fn snapshot(&self) -> Vec<Event> {
+        let events = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        events.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventKind, EventLog};
+
+    #[test]
+    fn drops_oldest_event_when_full() {
+        let log = EventLog::new(2);
+        log.record(EventKind::PortApplied, "1");
+        log.record(EventKind::ApplyFailed, "2");
+        log.record(EventKind::Relogin, "3");
+        let events = log.snapshot();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].detail, "2");
+        assert_eq!(events[1].kind, EventKind::Relogin);
+    }
+}
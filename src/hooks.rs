@@ -0,0 +1,135 @@
+use crate::config::HookConfig;
+use crate::report::{write_atomic, HookReport};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::{process::Command, time};
+use tracing::{debug, info, warn};
+
+const HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the configured post-change hooks once per distinct verified port.
+#[derive(Debug, Default)]
+pub struct HookRunner {
+    hooks: Vec<HookConfig>,
+    last_port: Mutex<Option<u16>>,
+}
+
+impl HookRunner {
+    pub fn new(hooks: Vec<HookConfig>) -> Self {
+        HookRunner {
+            hooks,
+            last_port: Mutex::new(None),
+        }
+    }
+
+    /// Runs every hook in order for `port`, unless they already all succeeded
+    /// for it. Failures are logged and reported but never stop later hooks,
+    /// and leave the port to be retried on the next verified update.
+    pub async fn on_verified_port(&self, port: u16) -> Vec<HookReport> {
+        if self.hooks.is_empty() {
+            return Vec::new();
+        }
+        if *self.last_port.lock().unwrap_or_else(|e| e.into_inner()) == Some(port) {
+            debug!("post-change hooks already ran for port {port}");
+            return Vec::new();
+        }
+
+        let mut reports = Vec::with_capacity(self.hooks.len());
+        for hook in &self.hooks {
+            let result = run_hook(hook, port).await;
+            match &result {
+                Ok(()) => info!("post-change hook {} succeeded", hook.describe()),
+                Err(err) => warn!("post-change hook {} failed: {err}", hook.describe()),
+            }
+            reports.push(HookReport {
+                hook: hook.describe(),
+                ok: result.is_ok(),
+                error: result.err(),
+            });
+        }
+        if reports.iter().all(|report| report.ok) {
+            *self.last_port.lock().unwrap_or_else(|e| e.into_inner()) = Some(port);
+        }
+        reports
+    }
+}
+
+async fn run_hook(hook: &HookConfig, port: u16) -> Result<(), String> {
+    match hook {
+        HookConfig::Command { command } => {
+            let command = render_template(command, port);
+            let output = time::timeout(
+                HOOK_TIMEOUT,
+                // A timed-out hook is dropped here, which kills it.
+                Command::new("sh")
+                    .arg("-c")
+                    .arg(&command)
+                    .kill_on_drop(true)
+                    .output(),
+            )
+            .await
+            .map_err(|_| format!("timed out after {}s", HOOK_TIMEOUT.as_secs()))?
+            .map_err(|err| err.to_string())?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                Err(format!("{}: {}", output.status, stderr.trim()))
+            }
+        }
+        HookConfig::File { path } => write_port_file(path, port).map_err(|err| err.to_string()),
+    }
+}
+
+/// Written atomically so a consumer never reads a half-written port.
+fn write_port_file(path: &Path, port: u16) -> std::io::Result<()> {
+    write_atomic(path, &format!("{port}\n"))
+}
+
+/// Substitutes every `{port}` placeholder in a command template.
+pub fn render_template(template: &str, port: u16) -> String {
+    template.replace("{port}", &port.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_template, HookRunner};
+    use crate::config::HookConfig;
+
+    #[test]
+    fn substitutes_port_placeholders() {
+        assert_eq!(
+            render_template(
+                "nft add rule inet fw input tcp dport {port} accept # {port}",
+                51820
+            ),
+            "nft add rule inet fw input tcp dport 51820 accept # 51820"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_hooks_run_again_for_the_same_port() {
+        let failing = HookRunner::new(vec![HookConfig::Command {
+            command: "exit 1".to_string(),
+        }]);
+        assert!(!failing.on_verified_port(51820).await[0].ok);
+        assert_eq!(failing.on_verified_port(51820).await.len(), 1);
+
+        let passing = HookRunner::new(vec![HookConfig::Command {
+            command: "true".to_string(),
+        }]);
+        assert!(passing.on_verified_port(51820).await[0].ok);
+        assert!(passing.on_verified_port(51820).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn file_hook_replaces_the_port_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("port");
+        std::fs::write(&path, "1234\n").unwrap();
+        let hooks = HookRunner::new(vec![HookConfig::File { path: path.clone() }]);
+        assert!(hooks.on_verified_port(51820).await[0].ok);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "51820\n");
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{config::PortFileFormat, error::Result, watch};
+use reqwest::{Client, Url};
+use std::time::Duration;
+use tokio::time;
+use tracing::{debug, warn};
+
+/// Bound on each control-server request; a poll that hangs longer is
+/// abandoned and the next tick tries again.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Client for polling the VPN container's control server.
+pub fn build_client() -> Result<Client> {
+    Ok(Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .user_agent("qb-port-sync")
+        .build()?)
+}
+
+/// Fetches `url` once and reads the forwarded port from its `{"port": N}`
+/// body, as served by Gluetun's `/v1/openvpn/portforwarded`. Port 0 means
+/// no port has been forwarded yet and is an error.
+pub async fn read_port_once(client: &Client, url: &Url) -> Result<u16> {
+    let response = client.get(url.clone()).send().await?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(anyhow::anyhow!("control server {url} answered {status}"));
+    }
+    let body = response.text().await?;
+    match watch::parse_port_as(&body, PortFileFormat::Json)? {
+        0 => Err(anyhow::anyhow!(
+            "control server {url} reports no forwarded port yet"
+        )),
+        port => Ok(port),
+    }
+}
+
+/// Polls `url` every `interval` and calls `on_change` whenever the port
+/// differs from the last one seen, like [`watch::poll_forwarded_port`] does
+/// for a file.
+pub async fn watch_port<F>(
+    client: Client,
+    url: Url,
+    apply_on_start: bool,
+    interval: Duration,
+    on_change: F,
+) -> Result<()>
+where
+    F: Fn(u16) + Send + 'static,
+{
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut last_port: Option<u16> = None;
+    let mut last_error: Option<String> = None;
+    let mut first_read = true;
+    loop {
+        ticker.tick().await;
+        match read_port_once(&client, &url).await {
+            Ok(port) if first_read => {
+                watch::report_initial_port(port, apply_on_start, &on_change);
+                last_port = Some(port);
+                last_error = None;
+            }
+            Ok(port) if last_port != Some(port) => {
+                debug!("control server reports port {port}");
+                on_change(port);
+                last_port = Some(port);
+                last_error = None;
+            }
+            Ok(_) => last_error = None,
+            Err(err) => {
+                // Repeats are kept at debug so a stopped VPN container does
+                // not flood the log every interval.
+                let message = format!("{err:#}");
+                if last_error.as_deref() == Some(message.as_str()) {
+                    debug!("failed to poll forwarded port: {message}");
+                } else {
+                    warn!("failed to poll forwarded port: {message}");
+                    last_error = Some(message);
+                }
+            }
+        }
+        first_read = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_client, read_port_once};
+    use crate::test_support::{serve, JSON};
+
+    /// Answers a single request with `status` and `body`.
+    async fn serve_once(status: &'static str, body: &'static str) -> reqwest::Url {
+        let (addr, _) = serve(vec![(status, JSON, body)]).await;
+        reqwest::Url::parse(&format!("http://{addr}/v1/openvpn/portforwarded")).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_the_port_from_the_control_server() {
+        let url = serve_once("200 OK", r#"{"port":51820}"#).await;
+        let client = build_client().unwrap();
+        assert_eq!(read_port_once(&client, &url).await.unwrap(), 51820);
+    }
+
+    #[tokio::test]
+    async fn zero_port_and_error_status_are_errors() {
+        let client = build_client().unwrap();
+        let url = serve_once("200 OK", r#"{"port":0}"#).await;
+        let err = read_port_once(&client, &url).await.unwrap_err();
+        assert!(err.to_string().contains("no forwarded port yet"));
+
+        let url = serve_once("401 Unauthorized", "").await;
+        let err = read_port_once(&client, &url).await.unwrap_err();
+        assert!(err.to_string().contains("401"));
+    }
+}
@@ -1,10 +1,22 @@
+pub mod client;
+pub mod coalesce;
 pub mod config;
+pub mod deluge;
 pub mod error;
+pub mod events;
+pub mod hooks;
+pub mod http_source;
+pub mod lock;
 #[cfg(feature = "metrics")]
 pub mod metrics_server;
 pub mod portmap;
 pub mod qbit;
 pub mod report;
+pub mod state;
+pub mod systemd;
+#[cfg(test)]
+mod test_support;
+pub mod transmission;
 pub mod watch;
 
 pub use config::Config;
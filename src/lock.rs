@@ -0,0 +1,86 @@
+use crate::error::{ConfigError, Result};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Exclusive advisory lock on `daemon.lock_file`, held until dropped. The
+/// file keeps the PID of the holder; it is not removed on release, so a
+/// waiting process never locks a file that is about to be unlinked.
+#[derive(Debug)]
+pub struct ProcessLock {
+    file: File,
+    path: PathBuf,
+}
+
+/// Locks `path`, creating it if needed. Fails with
+/// `ConfigError::AlreadyRunning` while another process holds it.
+pub fn acquire(path: &Path) -> Result<ProcessLock> {
+    let failed = |err: &dyn std::fmt::Display| {
+        ConfigError::LockFile(path.display().to_string(), err.to_string())
+    };
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| failed(&err))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let _ = file.read_to_string(&mut holder);
+            let holder = holder
+                .trim()
+                .parse::<u32>()
+                .map(|pid| format!(" (pid {pid})"))
+                .unwrap_or_default();
+            return Err(ConfigError::AlreadyRunning(path.display().to_string(), holder).into());
+        }
+        Err(TryLockError::Error(err)) => return Err(failed(&err).into()),
+    }
+
+    file.set_len(0)
+        .and_then(|()| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| writeln!(file, "{}", std::process::id()))
+        .map_err(|err| failed(&err))?;
+    debug!("acquired lock file {}", path.display());
+    Ok(ProcessLock {
+        file,
+        path: path.to_path_buf(),
+    })
+}
+
+impl Drop for ProcessLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+        debug!("released lock file {}", self.path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::acquire;
+    use crate::error::{classify_error, ConfigError, ExitCode};
+
+    #[test]
+    fn second_holder_is_refused_until_release() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("qb-port-sync.lock");
+
+        let held = acquire(&path).unwrap();
+        let pid = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(pid.trim(), std::process::id().to_string());
+
+        let err = acquire(&path).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ConfigError>(),
+            Some(ConfigError::AlreadyRunning(_, holder)) if holder.contains(pid.trim())
+        ));
+        assert_eq!(classify_error(&err), ExitCode::Config);
+
+        drop(held);
+        assert!(acquire(&path).is_ok());
+    }
+}
@@ -1,29 +1,65 @@
+mod client;
+mod coalesce;
 mod config;
+mod deluge;
 mod error;
+mod events;
+mod hooks;
+mod http_source;
+mod lock;
 #[cfg(feature = "metrics")]
 mod metrics_server;
 mod portmap;
 mod qbit;
 mod report;
+mod state;
+mod systemd;
+#[cfg(test)]
+mod test_support;
+mod transmission;
 mod watch;
 
-use clap::{ArgAction, Parser, ValueEnum};
-use config::Config;
-use error::{classify_error, ConfigError, ExitCode, Result, UnsupportedError};
+use anyhow::anyhow;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use client::TorrentClient;
+use coalesce::{ApplyCoalescer, ExternalChangeDetector, FlapGuard};
+use config::{
+    ClientKind, Config, DelugeConfig, PortProtocol, QbittorrentConfig, TransmissionConfig,
+    WatchMode,
+};
+use deluge::DelugeClient;
+use error::{classify_error, ConfigError, ExitCode, QbitError, Result, UnsupportedError};
+use events::{EventKind, EventLog};
+use hooks::HookRunner;
+#[cfg(feature = "metrics")]
+use metrics_server::{metric_name, HealthState};
 use portmap::{
-    map_prefer_pcp_fallback_natpmp, map_with_natpmp, map_with_pcp, Strategy as MapStrategy,
+    ensure_acceptable_port, external_address, gateway_reachable, map_prefer_pcp_fallback_natpmp,
+    map_with_natpmp, map_with_pcp, map_with_upnp, port_reachable, refresh_jitter, release_mapping,
+    resolve_gateways, AddressFamily, GatewayCache, MapResult, PublicIpMonitor,
+    Strategy as MapStrategy,
+};
+use qbit::{PortUpdateResult, QbitClient, LOOPBACK_INTERFACE};
+use report::{
+    append_line, json_line, rfc3339, CheckReport, Diagnostics, HookReport, InstanceReport,
+    InstanceStatus, JsonReport, StatusReport, SummaryReport, ValidationReport,
 };
-use qbit::{PortUpdateResult, QbitClient};
-use report::JsonReport;
 use reqwest::Url;
-use std::path::PathBuf;
+use serde_json::Value;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
 #[cfg(feature = "metrics")]
 use std::sync::atomic::{AtomicBool, Ordering};
-#[cfg(feature = "metrics")]
 use std::sync::Arc;
-use std::{process, time::Duration};
+use std::{
+    process,
+    time::{Duration, Instant, SystemTime},
+};
+use systemd::Watchdog;
 use tokio::{signal, sync::mpsc, time};
 use tracing::{debug, error, info, warn};
+use transmission::TransmissionClient;
+use watch::WatchProbe;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -32,254 +68,865 @@ use tracing::{debug, error, info, warn};
     about = "Synchronize qBittorrent listening port with ProtonVPN."
 )]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Override configuration file path.
-    #[arg(long)]
+    #[arg(long, global = true)]
     config: Option<PathBuf>,
 
+    /// Apply a named `[profiles.<name>]` table over the base config
+    /// (defaults to QB_PORT_SYNC_PROFILE).
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
     /// Perform a single port sync then exit.
     #[arg(long)]
     once: bool,
 
+    /// With --once, retry transient failures until this many seconds have
+    /// passed; configuration and unsupported errors still fail at once.
+    #[arg(long, value_name = "SECS", requires = "once")]
+    deadline: Option<u64>,
+
     /// Select port sync strategy.
-    #[arg(long, value_enum, default_value_t = StrategyOpt::Auto)]
+    #[arg(long, global = true, value_enum, default_value_t = StrategyOpt::Auto)]
     strategy: StrategyOpt,
 
     /// Output machine-readable JSON summary.
-    #[arg(long)]
+    #[arg(long, global = true)]
     json: bool,
 
+    /// Also write the JSON report to this file: a one-shot run replaces it
+    /// atomically, the daemon appends each status line.
+    #[arg(long, value_name = "PATH", visible_alias = "report-file")]
+    json_file: Option<PathBuf>,
+
+    /// With --json-file, do not print the JSON report to stdout.
+    #[arg(long, requires = "json_file")]
+    json_file_only: bool,
+
+    /// Add gateway, TTL and timing fields to the JSON report.
+    #[arg(long, global = true)]
+    verbose_json: bool,
+
+    /// Resolve and detect the port but log the qBittorrent update instead of applying it.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Validate the configuration and the forwarded-port file setup, then exit.
+    #[arg(long)]
+    check: bool,
+
+    /// Re-apply only the net.bind_interface binding, keeping the current listen port, then exit.
+    #[arg(long)]
+    apply_interface_only: bool,
+
+    /// Override `portmap.refresh_secs` for this run (must be non-zero), also
+    /// capping TTL-based refreshes; mainly a debugging aid.
+    #[arg(long, visible_alias = "interval", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    refresh_secs: Option<u64>,
+
+    /// Override `daemon.startup_delay_secs`: wait this long before the first sync.
+    #[arg(long, value_name = "SECS")]
+    delay: Option<u64>,
+
+    /// Override `portmap.protocol` for this run, e.g. to see whether the
+    /// gateway maps UDP at all. Ignored by the file and http strategies.
+    #[arg(long, value_enum)]
+    protocol: Option<ProtocolOpt>,
+
+    /// Print the effective configuration with the source of each value, then exit.
+    #[arg(long)]
+    print_effective_config: bool,
+
+    /// Print the resolved configuration as TOML (JSON with --json), password
+    /// redacted, then exit.
+    #[arg(long, visible_alias = "config-print")]
+    print_config: bool,
+
+    /// Print what each exit code means, then exit.
+    #[arg(long)]
+    explain_exit_codes: bool,
+
+    /// Print an example config.toml with every setting at its default,
+    /// then exit.
+    #[arg(long)]
+    dump_schema: bool,
+
+    /// Show how --strategy resolves on this machine (the paths probed and
+    /// the decision), then exit without contacting the torrent client.
+    #[arg(long)]
+    explain: bool,
+
     /// Increase log verbosity (-vv for debug).
-    #[arg(short, long, action = ArgAction::Count)]
+    #[arg(short, long, global = true, action = ArgAction::Count)]
     verbose: u8,
+
+    /// Log only warnings and errors, e.g. for cron. RUST_LOG still wins.
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+}
+
+#[derive(Subcommand, Debug, Clone, PartialEq, Eq)]
+enum Command {
+    /// Synchronize the listen port (the default when no subcommand is given).
+    Run,
+    /// Check configuration and connectivity without changing qBittorrent.
+    Validate,
+    /// Print qBittorrent's current listen port, random_port and upnp settings.
+    Status,
+    /// Probe a running daemon's /healthz and exit 0 when it answers 200.
+    #[cfg(feature = "metrics")]
+    Healthcheck {
+        /// URL to probe instead of the health or metrics port from the config.
+        #[arg(long, value_name = "URL")]
+        health_url: Option<Url>,
+    },
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
 enum StrategyOpt {
     File,
+    Http,
     Pcp,
     Natpmp,
+    Upnp,
     Auto,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+enum ProtocolOpt {
+    Tcp,
+    Udp,
+    Both,
+}
+
+impl From<ProtocolOpt> for PortProtocol {
+    fn from(opt: ProtocolOpt) -> Self {
+        match opt {
+            ProtocolOpt::Tcp => PortProtocol::TCP,
+            ProtocolOpt::Udp => PortProtocol::UDP,
+            ProtocolOpt::Both => PortProtocol::BOTH,
+        }
+    }
+}
+
+#[derive(Debug)]
 struct StrategyOutcome {
     strategy: String,
     detected_port: Option<u16>,
     verified: bool,
     note: Option<String>,
+    instances: Vec<InstanceReport>,
+    hooks: Vec<HookReport>,
+    family_mismatch: bool,
+    external_ip: Option<IpAddr>,
+    reachable: Option<bool>,
+    diagnostics: Diagnostics,
+    /// Error for the instances that failed; classified like any other.
+    error: Option<anyhow::Error>,
 }
 
-#[derive(Debug, Clone)]
+/// A logged-in torrent client and the name it is reported under.
+struct Instance {
+    name: String,
+    client: Box<dyn TorrentClient>,
+    /// Checker URL from the instance's config when `verify_reachable` is set.
+    reachability_check: Option<String>,
+}
+
+/// Outcome of pushing one port to every configured instance.
+struct AppliedPort {
+    /// Result from the first instance that accepted the port.
+    update: PortUpdateResult,
+    /// Whether every instance that accepted the port also verified it.
+    verified: bool,
+    /// Per-instance results; empty when only one instance is configured.
+    instances: Vec<InstanceReport>,
+    /// Combined error for the instances that failed, if any.
+    failure: Option<anyhow::Error>,
+    /// Results of post-change hooks run for this port.
+    hooks: Vec<HookReport>,
+    /// Whether any instance listens only on the other IP family.
+    family_mismatch: bool,
+    /// Time spent in `set_listen_port` across all instances.
+    client_duration: Duration,
+}
+
+impl AppliedPort {
+    /// Every instance took the port and, outside dry-run mode, verified it.
+    /// A skip for an absent bind interface never counts.
+    fn succeeded(&self, dry_run: bool) -> bool {
+        self.failure.is_none() && !self.update.interface_absent && (self.verified || dry_run)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum StrategyPlan {
     File { path: PathBuf },
+    Http { url: Url },
     Portmap { mode: PortmapMode },
 }
 
-#[derive(Debug, Clone, Copy)]
+/// How the daemon loops report each port update.
+#[derive(Debug, Clone)]
+struct OutputMode {
+    /// Emit one NDJSON status line per update on stdout.
+    json: bool,
+    /// File each status line is appended to (`--json-file`).
+    file: Option<Arc<Path>>,
+    dry_run: bool,
+    /// Keep the `--verbose-json` diagnostic fields.
+    verbose: bool,
+}
+
+impl OutputMode {
+    fn emit(&self, cycle: u64, report: impl FnOnce() -> JsonReport) {
+        if !self.json && self.file.is_none() {
+            return;
+        }
+        let mut report = report();
+        if !self.verbose {
+            report.diagnostics = None;
+        }
+        report.cycle = Some(cycle);
+        report.timestamp = Some(rfc3339(SystemTime::now()));
+        self.write_line(&json_line(&report));
+    }
+
+    /// Prints `line` with `--json` and appends it to `--json-file`.
+    fn write_line(&self, line: &str) {
+        if self.json {
+            println!("{line}");
+        }
+        if let Some(path) = &self.file {
+            if let Err(err) = append_line(path, line) {
+                warn!("failed to append JSON report to {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum PortmapMode {
     Auto,
     PcpOnly,
     NatOnly,
+    UpnpOnly,
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    init_tracing(cli.verbose);
+    let log_file = init_tracing(cli.verbose, cli.quiet);
+
+    if cli.explain_exit_codes {
+        explain_exit_codes(cli.json);
+        process::exit(ExitCode::Success as i32);
+    }
 
-    let exit_code = match run(cli).await {
-        Ok((report, code, emit_json)) => {
-            if emit_json {
-                println!("{}", report.line().unwrap_or_else(|_| "{}".into()));
+    if cli.dump_schema {
+        match Config::render_example() {
+            Ok(example) => print!("{example}"),
+            Err(err) => {
+                error!("{err:#}");
+                process::exit(ExitCode::Config as i32);
             }
-            code
         }
-        Err((report, err, code, emit_json)) => {
-            if emit_json {
-                println!("{}", report.line().unwrap_or_else(|_| "{}".into()));
+        process::exit(ExitCode::Success as i32);
+    }
+
+    if cli.command == Some(Command::Validate) {
+        let code = validate(&cli).await;
+        process::exit(code as i32);
+    }
+
+    if cli.command == Some(Command::Status) {
+        let code = status(&cli).await;
+        process::exit(code as i32);
+    }
+
+    #[cfg(feature = "metrics")]
+    if let Some(Command::Healthcheck { health_url }) = &cli.command {
+        let code = match healthcheck(&cli, health_url.clone()).await {
+            Ok(()) => ExitCode::Success,
+            Err(err) => {
+                error!("{err:#}");
+                classify_error(&err)
             }
+        };
+        process::exit(code as i32);
+    }
+
+    let json_file = cli.json_file.clone();
+    let json_file_only = cli.json_file_only;
+    let (report, exit_code, emit_json) = match run(cli, &log_file).await {
+        Ok((report, code, emit_json)) => (report, code, emit_json),
+        Err((report, err, code, emit_json)) => {
             error!("{err:#}");
-            code
+            (report, code, emit_json)
         }
     };
+    if emit_json && !json_file_only {
+        println!("{}", json_line(&report));
+    }
+    if let Some(path) = json_file {
+        // The daemon's final line joins the status lines it appended.
+        let written = if report.strategy == "daemon" {
+            report.append_to(&path)
+        } else {
+            report.write_to(&path)
+        };
+        if let Err(err) = written {
+            warn!("failed to write JSON report to {}: {err}", path.display());
+        }
+    }
 
     process::exit(exit_code as i32);
 }
 
+/// Prints every `ExitCode` with its meaning, as a table or a JSON array.
+fn explain_exit_codes(json: bool) {
+    if json {
+        let codes: Vec<Value> = ExitCode::ALL
+            .iter()
+            .map(|&code| {
+                serde_json::json!({
+                    "code": code as i32,
+                    "name": code.name(),
+                    "meaning": code.meaning(),
+                })
+            })
+            .collect();
+        println!("{}", Value::Array(codes));
+        return;
+    }
+    for code in ExitCode::ALL {
+        println!("{:<3}{:<13}{}", code as i32, code.name(), code.meaning());
+    }
+}
+
+/// Runs the `validate` subcommand: loads the config, resolves what the
+/// selected strategy needs and logs in to every qBittorrent instance, without
+/// posting any preference.
+async fn validate(cli: &Cli) -> ExitCode {
+    let mut checks = Vec::new();
+    let mut config_failed = false;
+    let mut connectivity_failed = false;
+    let mut record = |name: &str, result: std::result::Result<String, String>, config_level| {
+        let ok = result.is_ok();
+        if !ok {
+            if config_level {
+                config_failed = true;
+            } else {
+                connectivity_failed = true;
+            }
+        }
+        checks.push(CheckReport {
+            name: name.to_string(),
+            ok,
+            detail: result.unwrap_or_else(|err| err),
+        });
+    };
+
+    let config = match Config::load_with_profile(cli.config.clone(), cli.profile.as_deref()) {
+        Ok(config) => {
+            let source = config
+                .source_path()
+                .map(|path| path.display().to_string())
+                .unwrap_or_else(|| "from environment".to_string());
+            record("config", Ok(format!("loaded {source}")), true);
+            Some(config)
+        }
+        Err(err) => {
+            record("config", Err(format!("{err:#}")), true);
+            None
+        }
+    };
+
+    if let Some(config) = config.as_ref() {
+        match resolve_plan(cli.strategy, config) {
+            Ok(StrategyPlan::File { path }) => {
+                record(
+                    "forwarded_port_path",
+                    describe_watch_probe(config, &path),
+                    true,
+                );
+            }
+            Ok(StrategyPlan::Http { url }) => {
+                // The URL itself was checked while resolving the plan, so a
+                // failed fetch (e.g. a stopped VPN container) is connectivity.
+                let result = match http_source::build_client() {
+                    Ok(client) => http_source::read_port_once(&client, &url).await,
+                    Err(err) => Err(err),
+                };
+                let config_error =
+                    matches!(&result, Err(err) if classify_error(err) == ExitCode::Config);
+                let result = result
+                    .map(|port| format!("{url} reports port {port}"))
+                    .map_err(|err| format!("{err:#}"));
+                record("control_server_url", result, config_error);
+            }
+            Ok(StrategyPlan::Portmap { mode }) => {
+                let result = resolve_gateways(&config.portmap)
+                    .map(|gateways| {
+                        let gateways = gateways
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        format!("{gateways} ({})", portmap_mode_label(mode))
+                    })
+                    .map_err(|err| format!("{err:#}"));
+                record("gateway", result, true);
+            }
+            Err(err) => record("strategy", Err(format!("{err:#}")), true),
+        }
+
+        let events = EventLog::default();
+        let kind = config.client.kind;
+        for (label, connected) in connect_clients(config, true, &events).await {
+            let name = match kind {
+                ClientKind::Qbittorrent => format!("qbittorrent:{label}"),
+                _ => label,
+            };
+            match connected {
+                Ok(_) => record(&name, Ok("login succeeded".to_string()), false),
+                Err(err) => {
+                    let config_error = classify_error(&err) == ExitCode::Config;
+                    record(&name, Err(format!("{err:#}")), config_error);
+                }
+            }
+        }
+    }
+
+    let report = ValidationReport {
+        ok: !config_failed && !connectivity_failed,
+        checks,
+    };
+    if cli.json {
+        println!("{}", json_line(&report));
+    } else {
+        for check in &report.checks {
+            let status = if check.ok { "PASS" } else { "FAIL" };
+            println!("{status} {}: {}", check.name, check.detail);
+        }
+    }
+
+    if config_failed {
+        ExitCode::Config
+    } else if connectivity_failed {
+        ExitCode::Transient
+    } else {
+        ExitCode::Success
+    }
+}
+
+/// Give up on a daemon that does not answer `/healthz` within this long.
+#[cfg(feature = "metrics")]
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Probes a running daemon's `/healthz`, so a container image needs no curl
+/// for its `HEALTHCHECK`. Never talks to qBittorrent.
+#[cfg(feature = "metrics")]
+async fn healthcheck(cli: &Cli, health_url: Option<Url>) -> Result<()> {
+    let url = match health_url {
+        Some(url) => url,
+        None => Config::load_with_profile(cli.config.clone(), cli.profile.as_deref())?
+            .health_check_url()?,
+    };
+    let client = reqwest::Client::builder()
+        .timeout(HEALTHCHECK_TIMEOUT)
+        .build()?;
+    let status = client.get(url.clone()).send().await?.status();
+    if status != reqwest::StatusCode::OK {
+        return Err(anyhow!("{url} answered {status}"));
+    }
+    info!("{url} answered {status}");
+    Ok(())
+}
+
+/// Runs the `status` subcommand: logs in to every qBittorrent instance and
+/// prints the port settings it reports, without changing them.
+async fn status(cli: &Cli) -> ExitCode {
+    let mut report = StatusReport::default();
+    let mut code = ExitCode::Success;
+    match Config::load_with_profile(cli.config.clone(), cli.profile.as_deref()) {
+        Ok(config) => {
+            let events = EventLog::default();
+            for (name, connected) in connect_clients(&config, true, &events).await {
+                let result = match connected {
+                    Ok(instance) => instance.client.get_port_status().await,
+                    Err(err) => Err(err),
+                };
+                let mut entry = InstanceStatus {
+                    name,
+                    ..InstanceStatus::default()
+                };
+                match result {
+                    Ok(port_status) => {
+                        entry.listen_port = Some(port_status.listen_port);
+                        entry.random_port = port_status.random_port;
+                        entry.upnp = port_status.upnp;
+                    }
+                    Err(err) => {
+                        if code != ExitCode::Config {
+                            code = classify_error(&err);
+                        }
+                        entry.error = Some(format!("{err:#}"));
+                    }
+                }
+                report.instances.push(entry);
+            }
+        }
+        Err(err) => {
+            code = classify_error(&err);
+            report.error = Some(format!("{err:#}"));
+        }
+    }
+    report.ok = code == ExitCode::Success;
+
+    if cli.json {
+        println!("{}", json_line(&report));
+        return code;
+    }
+    if let Some(error) = report.error.as_deref() {
+        error!("{error}");
+    }
+    let flag = |value: Option<bool>| value.map_or("unknown".to_string(), |v| v.to_string());
+    for entry in &report.instances {
+        match (entry.listen_port, entry.error.as_deref()) {
+            (Some(port), _) => println!(
+                "{}: listen_port={port} random_port={} upnp={}",
+                entry.name,
+                flag(entry.random_port),
+                flag(entry.upnp)
+            ),
+            (None, error) => println!("{}: error: {}", entry.name, error.unwrap_or_default()),
+        }
+    }
+    code
+}
+
+/// Loads the configuration and applies the command-line overrides, at
+/// startup and again on each reload.
+fn load_config(cli: &Cli) -> Result<Config> {
+    let mut config = Config::load_with_profile(cli.config.clone(), cli.profile.as_deref())?;
+    if let Some(secs) = cli.refresh_secs {
+        config.override_refresh_secs(secs);
+    }
+    if let Some(secs) = cli.delay {
+        config.override_startup_delay_secs(secs);
+    }
+    match cli.protocol {
+        Some(_) if matches!(cli.strategy, StrategyOpt::File | StrategyOpt::Http) => {
+            debug!(
+                "ignoring --protocol: --strategy {} does not map ports",
+                strategy_opt_label(cli.strategy)
+            );
+        }
+        Some(protocol) => config.override_protocol(protocol.into()),
+        None => {}
+    }
+    Ok(config)
+}
+
 async fn run(
     cli: Cli,
+    log_file: &LogFile,
 ) -> std::result::Result<(JsonReport, ExitCode, bool), (JsonReport, anyhow::Error, ExitCode, bool)>
 {
-    if cli.json && !cli.once {
-        let err = UnsupportedError::new("--json is only supported with --once mode");
-        let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-        report.applied = false;
-        report.note = String::from("json mode requires --once");
-        report.error = Some(err.0.clone());
-        let err = anyhow::Error::from(err);
-        return Err((report, err, ExitCode::Config, true));
-    }
-
-    let config = match Config::load(cli.config.clone()) {
+    // Installed before the slow startup steps so a `systemctl reload` during
+    // login or the startup delay is held for the daemon loop instead of
+    // terminating the process.
+    let reload = ReloadSignal::new();
+    let config = match load_config(&cli) {
         Ok(cfg) => cfg,
         Err(err) => {
             let code = classify_error(&err);
             let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
             report.applied = false;
             report.note = String::new();
-            report.error = Some(format!("{err:#}"));
+            report.set_error(&err);
             return Err((report, err, code, cli.json));
         }
     };
+    if let Some(path) = config.daemon.log_file.as_deref() {
+        log_file.attach(path);
+    }
 
-    let password = match config.qbittorrent_password() {
-        Ok(pw) => pw,
-        Err(err) => {
-            let code = classify_error(&err);
-            let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-            report.applied = false;
-            report.error = Some(format!("{err:#}"));
-            return Err((report, err, code, cli.json));
+    if cli.print_effective_config {
+        if let Some(profile) = config.active_profile() {
+            println!("# profile: {profile}");
         }
-    };
+        print!("{}", config.render_effective());
+        let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
+        report.note = String::from("printed effective configuration");
+        return Ok((report, ExitCode::Success, false));
+    }
 
-    let base_url = match Url::parse(&config.qbittorrent.base_url) {
-        Ok(url) => url,
-        Err(err) => {
-            let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-            report.error = Some(err.to_string());
-            return Err((report, err.into(), ExitCode::Config, cli.json));
+    if cli.print_config {
+        if let Err(err) = print_resolved_config(&config, cli.json) {
+            let code = classify_error(&err);
+            let report = failure_report(strategy_opt_label(cli.strategy), &err, false);
+            return Err((report, err, code, false));
         }
-    };
+        let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
+        report.note = String::from("printed resolved configuration");
+        return Ok((report, ExitCode::Success, false));
+    }
 
-    let client = match QbitClient::new(base_url) {
-        Ok(client) => client,
-        Err(err) => {
+    if cli.explain {
+        let (report, code) = explain_strategy(&config, cli.strategy, cli.json);
+        return Ok((report, code, cli.json));
+    }
+
+    if cli.check {
+        let report = check_setup(&config, cli.strategy);
+        let code = if report.error.is_some() {
+            ExitCode::Config
+        } else {
+            ExitCode::Success
+        };
+        return Ok((report, code, cli.json));
+    }
+
+    let _lock = match config.daemon.lock_file.as_deref().map(lock::acquire) {
+        Some(Err(err)) => {
             let code = classify_error(&err);
             let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-            report.error = Some(format!("{err:#}"));
+            report.set_error(&err);
             return Err((report, err, code, cli.json));
         }
+        Some(Ok(lock)) => Some(lock),
+        None => None,
     };
 
-    if let Err(err) = client.login(&config.qbittorrent.username, &password).await {
+    if config.daemon.startup_delay_secs > 0 && !cli.apply_interface_only {
+        info!(
+            "waiting {}s before the first sync",
+            config.daemon.startup_delay_secs
+        );
+        time::sleep(Duration::from_secs(config.daemon.startup_delay_secs)).await;
+    }
+
+    let events = EventLog::new(config.events.capacity);
+    let hooks = HookRunner::new(config.post_change_hooks.clone());
+    let mut instances = Vec::new();
+    let mut login_failures = Vec::new();
+    for (name, connected) in connect_clients(&config, cli.dry_run, &events).await {
+        match connected {
+            Ok(instance) => instances.push(instance),
+            Err(err) => login_failures.push((name, err)),
+        }
+    }
+    // A daemon waits out a ban rather than exiting, since a restart would log
+    // in again straight away and extend it.
+    let banned = |(_, err): &(String, anyhow::Error)| ban_deadline(err).is_some();
+    let wait_out_bans = !cli.once
+        && !cli.apply_interface_only
+        && !login_failures.is_empty()
+        && login_failures.iter().all(banned);
+    if instances.is_empty() && !wait_out_bans {
+        let mut failures = login_failures.into_iter();
+        let err = match failures.next() {
+            Some((_, err)) => err,
+            None => anyhow!("no torrent client instances configured"),
+        };
         let code = classify_error(&err);
         let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-        report.error = Some(format!("{err:#}"));
+        report.applied = false;
+        report.set_error(&err);
         return Err((report, err, code, cli.json));
     }
+    for (name, err) in login_failures {
+        warn!("{name}: login failed: {err:#}; retrying before each update");
+        let reachability_check = config
+            .qbittorrent
+            .iter()
+            .find(|qb| qb.label() == name)
+            .and_then(QbittorrentConfig::reachability_check);
+        instances.push(Instance {
+            client: Box::new(PendingClient::new(
+                name.clone(),
+                &config,
+                cli.dry_run,
+                &events,
+                &err,
+            )),
+            name,
+            reachability_check,
+        });
+    }
 
-    #[cfg(feature = "metrics")]
-    let health_flag = Arc::new(AtomicBool::new(false));
-
-    #[cfg(feature = "metrics")]
-    let _metrics_handle = if config.metrics.enabled && config.metrics.port > 0 {
-        match metrics_server::install_recorder() {
-            Ok(handle) => {
-                let port = if config.health.enabled && config.health.port > 0 {
-                    config.health.port
+    if cli.apply_interface_only {
+        return match rebind_interface(&config, &instances, &events).await {
+            Ok(mut report) => {
+                report.applied &= !cli.dry_run;
+                report.dry_run = cli.dry_run;
+                let code = if report.error.is_some() {
+                    ExitCode::Transient
                 } else {
-                    config.metrics.port
+                    ExitCode::Success
                 };
-                let health_clone = health_flag.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = metrics_server::run_server(port, handle, health_clone).await {
-                        error!("metrics server failed: {err:#}");
-                    }
-                });
-                Some(())
-            }
-            Err(err) => {
-                warn!("failed to install metrics recorder: {err:#}");
-                None
-            }
-        }
-    } else if config.health.enabled && config.health.port > 0 {
-        match metrics_server::install_recorder() {
-            Ok(handle) => {
-                let port = config.health.port;
-                let health_clone = health_flag.clone();
-                tokio::spawn(async move {
-                    if let Err(err) = metrics_server::run_server(port, handle, health_clone).await {
-                        error!("health server failed: {err:#}");
-                    }
-                });
-                Some(())
+                Ok((report, code, cli.json))
             }
             Err(err) => {
-                warn!("failed to install health recorder: {err:#}");
-                None
+                let code = classify_error(&err);
+                let mut report = JsonReport::new("interface");
+                report.set_error(&err);
+                report.dry_run = cli.dry_run;
+                Err((report, err, code, cli.json))
             }
+        };
+    }
+
+    // Check the tunnel before restoring anything: re-applying the saved port
+    // would undo the leak protection's binding.
+    let tunnel_down = config.net.leak_protect_on_startup && tunnel_looks_down(&config).await;
+    if tunnel_down {
+        protect_against_leak(&config, &instances).await;
+    }
+
+    if !cli.once {
+        if tunnel_down {
+            debug!("VPN tunnel appears down; not re-applying the last known good port");
+        } else {
+            restore_last_known_port(&config, &instances, &events, &hooks).await;
         }
-    } else {
-        None
-    };
+    }
+
+    #[cfg(feature = "metrics")]
+    let health_flag = Arc::new(HealthState::default());
+    #[cfg(feature = "metrics")]
+    let live_flag = Arc::new(AtomicBool::new(false));
+
+    #[cfg(feature = "metrics")]
+    spawn_observability_server(&config, &health_flag, &live_flag, &events);
 
     let plan = match resolve_plan(cli.strategy, &config) {
         Ok(plan) => plan,
         Err(err) => {
             let code = classify_error(&err);
             let mut report = JsonReport::new(strategy_opt_label(cli.strategy));
-            report.error = Some(format!("{err:#}"));
+            report.set_error(&err);
             return Err((report, err, code, cli.json));
         }
     };
 
+    // Startup is done and the sync loop is about to run: `/livez` turns green.
+    #[cfg(feature = "metrics")]
+    {
+        health_flag.set_max_staleness(config.health_max_staleness(None));
+        live_flag.store(true, Ordering::Relaxed);
+    }
+
     if cli.once {
-        match run_once(
-            plan.clone(),
-            &config,
-            &client,
-            #[cfg(feature = "metrics")]
-            health_flag.clone(),
-        )
-        .await
-        {
+        let deadline = cli
+            .deadline
+            .map(|secs| Instant::now() + Duration::from_secs(secs));
+        let result = loop {
+            let result = run_once(
+                plan.clone(),
+                &config,
+                &instances,
+                &events,
+                &hooks,
+                #[cfg(feature = "metrics")]
+                health_flag.clone(),
+            )
+            .await;
+            let error = match &result {
+                Ok(StrategyOutcome {
+                    error: Some(err), ..
+                })
+                | Err(err) => Some(err).filter(|err| classify_error(err).is_retryable()),
+                Ok(_) => None,
+            }
+            .map(|err| format!("{err:#}"));
+            let remaining =
+                deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            match (error, remaining) {
+                (Some(error), Some(remaining)) if !remaining.is_zero() => {
+                    let delay = ONCE_RETRY_DELAY.min(remaining);
+                    warn!(
+                        "attempt failed: {error}; retrying in {}s ({}s left before the deadline)",
+                        delay.as_secs(),
+                        remaining.as_secs()
+                    );
+                    time::sleep(delay).await;
+                }
+                _ => break result,
+            }
+        };
+        match result {
             Ok(outcome) => {
                 let mut report = JsonReport::new(outcome.strategy.clone());
                 report.detected_port = outcome.detected_port;
-                report.applied = true;
+                report.applied = !cli.dry_run;
                 report.verified = outcome.verified;
                 report.note = outcome.note.unwrap_or_default();
+                report.instances = outcome.instances;
+                report.hooks = outcome.hooks;
+                report.family_mismatch = outcome.family_mismatch;
+                report.external_ip = outcome.external_ip.map(|ip| ip.to_string());
+                report.reachable = outcome.reachable;
+                report.diagnostics = cli.verbose_json.then_some(outcome.diagnostics);
+                report.dry_run = cli.dry_run;
+                if let Some(err) = outcome.error {
+                    let code = classify_error(&err);
+                    report.set_error(&err);
+                    return Err((report, err, code, cli.json));
+                }
                 Ok((report, ExitCode::Success, cli.json))
             }
             Err(err) => {
                 let code = classify_error(&err);
                 let strategy_name = match &plan {
-                    StrategyPlan::File { .. } => "file".to_string(),
-                    StrategyPlan::Portmap { mode } => match mode {
-                        PortmapMode::Auto => "auto".to_string(),
-                        PortmapMode::PcpOnly => "pcp".to_string(),
-                        PortmapMode::NatOnly => "natpmp".to_string(),
-                    },
+                    StrategyPlan::File { .. } => "file",
+                    StrategyPlan::Http { .. } => "http",
+                    StrategyPlan::Portmap { mode } => portmap_mode_label(*mode),
                 };
                 let mut report = JsonReport::new(strategy_name);
                 report.applied = false;
                 report.verified = false;
                 report.note = String::new();
-                report.error = Some(format!("{err:#}"));
+                report.set_error(&err);
+                report.dry_run = cli.dry_run;
                 Err((report, err, code, cli.json))
             }
         }
     } else {
-        match run_daemon(
-            plan,
-            &config,
-            client,
+        let output = OutputMode {
+            json: cli.json && !cli.json_file_only,
+            file: cli.json_file.as_deref().map(Arc::from),
+            dry_run: cli.dry_run,
+            verbose: cli.verbose_json,
+        };
+        let mut ctx = DaemonContext {
+            cli: &cli,
+            config,
+            instances,
+            events,
+            hooks,
+            output,
+            stats: DaemonStats::new(),
             #[cfg(feature = "metrics")]
             health_flag,
-        )
-        .await
-        {
+        };
+        match run_daemon(&mut ctx, plan, reload).await {
             Ok(_) => {
                 let mut report = JsonReport::new("daemon");
                 report.note = String::from("exited on signal");
+                report.timestamp = Some(rfc3339(SystemTime::now()));
                 Ok((report, ExitCode::Success, cli.json))
             }
             Err(err) => {
                 let code = classify_error(&err);
                 let mut report = JsonReport::new("daemon");
-                report.error = Some(format!("{err:#}"));
+                report.set_error(&err);
+                report.timestamp = Some(rfc3339(SystemTime::now()));
                 Err((report, err, code, cli.json))
             }
         }
@@ -289,148 +936,353 @@ async fn run(
 async fn run_once(
     plan: StrategyPlan,
     config: &Config,
-    client: &QbitClient,
-    #[cfg(feature = "metrics")] health_flag: Arc<AtomicBool>,
+    instances: &[Instance],
+    events: &EventLog,
+    hooks: &HookRunner,
+    #[cfg(feature = "metrics")] health_flag: Arc<HealthState>,
 ) -> Result<StrategyOutcome> {
-    let bind_interface = config.bind_interface();
     match plan {
         StrategyPlan::File { path } => {
             debug!("reading forwarded port from {:?}", path);
             let port = watch::read_forwarded_port_once(config)?;
-            let update = client.set_listen_port(port, bind_interface).await?;
+            let applied =
+                apply_port(instances, port, AddressFamily::V4, config, events, hooks).await?;
 
             #[cfg(feature = "metrics")]
-            {
-                metrics::counter!("qb_port_sync_port_updates_total").increment(1);
-                metrics::gauge!("qb_port_sync_current_port").set(update.detected_port as f64);
-                metrics::gauge!("qb_port_sync_last_update_timestamp_seconds").set(
-                    std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs() as f64,
-                );
-                health_flag.store(true, Ordering::Relaxed);
-            }
+            health_flag.set(applied.failure.is_none());
 
             Ok(StrategyOutcome {
                 strategy: "file".to_string(),
-                detected_port: Some(update.detected_port),
-                verified: update.verified,
-                note: build_note(&update, None),
+                detected_port: Some(applied.update.detected_port),
+                verified: applied.verified,
+                note: build_note(&applied.update, None),
+                diagnostics: diagnostics(&applied, None),
+                instances: applied.instances,
+                hooks: applied.hooks,
+                family_mismatch: applied.family_mismatch,
+                external_ip: None,
+                reachable: applied.update.reachable,
+                error: applied.failure,
             })
         }
-        StrategyPlan::Portmap { mode } => {
-            let map_result = match mode {
-                PortmapMode::Auto => map_prefer_pcp_fallback_natpmp(&config.portmap).await?,
-                PortmapMode::PcpOnly => map_with_pcp(&config.portmap).await?,
-                PortmapMode::NatOnly => map_with_natpmp(&config.portmap).await?,
-            };
+        StrategyPlan::Http { url } => {
+            debug!("reading forwarded port from {}", url);
+            let client = http_source::build_client()?;
+            let port = http_source::read_port_once(&client, &url).await?;
+            let applied =
+                apply_port(instances, port, AddressFamily::V4, config, events, hooks).await?;
+
+            #[cfg(feature = "metrics")]
+            health_flag.set(applied.failure.is_none());
+
+            Ok(StrategyOutcome {
+                strategy: "http".to_string(),
+                detected_port: Some(applied.update.detected_port),
+                verified: applied.verified,
+                note: build_note(&applied.update, None),
+                diagnostics: diagnostics(&applied, None),
+                instances: applied.instances,
+                hooks: applied.hooks,
+                family_mismatch: applied.family_mismatch,
+                external_ip: None,
+                reachable: applied.update.reachable,
+                error: applied.failure,
+            })
+        }
+        StrategyPlan::Portmap { mode } => {
+            let gateways = resolve_gateways(&config.portmap)?;
+            let mapping_started = Instant::now();
+            let map_result = match mode {
+                PortmapMode::Auto => {
+                    map_prefer_pcp_fallback_natpmp(&config.portmap, &gateways).await?
+                }
+                PortmapMode::PcpOnly => map_with_pcp(&config.portmap, &gateways).await?,
+                PortmapMode::NatOnly => map_with_natpmp(&config.portmap, &gateways).await?,
+                PortmapMode::UpnpOnly => map_with_upnp(&config.portmap, &gateways).await?,
+            };
+            let mapping_duration = mapping_started.elapsed();
+            ensure_acceptable_port(&config.portmap, map_result.external_port)?;
             let strategy_label = map_strategy_label(mode, map_result.strategy);
-            let update = client
-                .set_listen_port(map_result.external_port, bind_interface)
-                .await?;
+            let applied = apply_port(
+                instances,
+                map_result.external_port,
+                map_result.family,
+                config,
+                events,
+                hooks,
+            )
+            .await?;
 
             #[cfg(feature = "metrics")]
-            {
-                metrics::counter!("qb_port_sync_port_updates_total").increment(1);
-                metrics::gauge!("qb_port_sync_current_port").set(update.detected_port as f64);
-                metrics::gauge!("qb_port_sync_last_update_timestamp_seconds").set(
-                    std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs() as f64,
-                );
-                health_flag.store(true, Ordering::Relaxed);
-            }
+            health_flag.set(applied.failure.is_none());
+
+            let external_ip = match map_result.external_ip {
+                Some(address) => Some(address),
+                None if config.portmap.external_ip => {
+                    external_address(&config.portmap).await.map(IpAddr::V4)
+                }
+                None => None,
+            };
 
             Ok(StrategyOutcome {
                 strategy: strategy_label,
-                detected_port: Some(update.detected_port),
-                verified: update.verified,
-                note: build_note(&update, map_result.ttl),
+                detected_port: Some(applied.update.detected_port),
+                verified: applied.verified,
+                note: build_note(&applied.update, map_result.ttl),
+                diagnostics: diagnostics(&applied, Some((&map_result, mapping_duration))),
+                instances: applied.instances,
+                hooks: applied.hooks,
+                family_mismatch: applied.family_mismatch,
+                external_ip,
+                reachable: applied.update.reachable,
+                error: applied.failure,
             })
         }
     }
 }
 
+/// Why a daemon loop returned without an error.
+enum DaemonExit {
+    /// SIGINT or SIGTERM: the process exits.
+    Shutdown,
+    /// A reloaded configuration resolves to this plan instead.
+    Switch(StrategyPlan),
+}
+
+/// What every daemon loop works with, carried across strategy switches.
+/// `config` follows reloads and `stats` keeps counting across them.
+struct DaemonContext<'a> {
+    cli: &'a Cli,
+    config: Config,
+    instances: Vec<Instance>,
+    events: EventLog,
+    hooks: HookRunner,
+    output: OutputMode,
+    stats: DaemonStats,
+    #[cfg(feature = "metrics")]
+    health_flag: Arc<HealthState>,
+}
+
 async fn run_daemon(
-    plan: StrategyPlan,
-    config: &Config,
-    client: QbitClient,
-    #[cfg(feature = "metrics")] health_flag: Arc<AtomicBool>,
+    ctx: &mut DaemonContext<'_>,
+    mut plan: StrategyPlan,
+    mut reload: ReloadSignal,
 ) -> Result<()> {
-    match plan {
-        StrategyPlan::File { path } => {
-            run_file_daemon(
-                path,
-                config,
-                client,
-                #[cfg(feature = "metrics")]
-                health_flag,
-            )
-            .await
-        }
-        StrategyPlan::Portmap { mode } => {
-            run_portmap_daemon(
-                mode,
-                config,
-                client,
-                #[cfg(feature = "metrics")]
-                health_flag,
-            )
-            .await
+    let mut shutdown = ShutdownSignal::new();
+    loop {
+        #[cfg(feature = "metrics")]
+        update_max_staleness(&ctx.health_flag, &ctx.config, &plan);
+        let exit = match &plan {
+            StrategyPlan::File { path } => {
+                run_file_daemon(ctx, &plan, path.clone(), &mut shutdown, &mut reload).await?
+            }
+            StrategyPlan::Http { url } => {
+                run_http_daemon(ctx, &plan, url.clone(), &mut shutdown, &mut reload).await?
+            }
+            StrategyPlan::Portmap { mode } => {
+                run_portmap_daemon(ctx, &plan, *mode, &mut shutdown, &mut reload).await?
+            }
+        };
+        match exit {
+            DaemonExit::Shutdown => return Ok(()),
+            DaemonExit::Switch(next) => {
+                info!("switching strategy from {plan:?} to {next:?}");
+                plan = next;
+            }
         }
     }
 }
 
 async fn run_file_daemon(
+    ctx: &mut DaemonContext<'_>,
+    plan: &StrategyPlan,
     path: PathBuf,
-    config: &Config,
-    client: QbitClient,
-    #[cfg(feature = "metrics")] health_flag: Arc<AtomicBool>,
-) -> Result<()> {
+    shutdown: &mut ShutdownSignal,
+    reload: &mut ReloadSignal,
+) -> Result<DaemonExit> {
     info!("starting file-watcher strategy on {:?}", path);
-    let bind_interface = config.bind_interface().map(|s| s.to_string());
-    let (tx, mut rx) = mpsc::channel::<u16>(16);
+    let config = &ctx.config;
+    let (tx, rx) = mpsc::channel::<u16>(16);
     let watcher_path = path.clone();
-    tokio::spawn(async move {
-        if let Err(err) = watch::watch_forwarded_port(watcher_path, move |port| {
+    let format = config.protonvpn.port_file_format;
+    let max_bytes = config.protonvpn.max_file_bytes;
+    let apply_on_start = config.protonvpn.apply_on_start;
+    let watch_mode = config.protonvpn.watch_mode;
+    let poll_interval = Duration::from_secs(config.protonvpn.poll_interval_secs.max(1));
+    let initial_read_timeout = Duration::from_secs(config.protonvpn.initial_read_timeout_secs);
+    let debounce = Duration::from_millis(config.protonvpn.debounce_ms);
+    let watcher = tokio::spawn(async move {
+        let on_change = move |port| {
             let _ = tx.try_send(port);
-        })
-        .await
-        {
+        };
+        let result = match watch_mode {
+            WatchMode::Inotify => {
+                watch::watch_forwarded_port(
+                    watcher_path,
+                    format,
+                    max_bytes,
+                    apply_on_start,
+                    initial_read_timeout,
+                    debounce,
+                    on_change,
+                )
+                .await
+            }
+            WatchMode::Poll => {
+                info!("polling forwarded port file every {:?}", poll_interval);
+                watch::poll_forwarded_port(
+                    watcher_path,
+                    format,
+                    max_bytes,
+                    apply_on_start,
+                    poll_interval,
+                    on_change,
+                )
+                .await
+            }
+        };
+        if let Err(err) = result {
             warn!("forwarded port watcher terminated: {err:#}");
         }
     });
 
+    let exit = run_push_daemon(ctx, "file", plan, rx, shutdown, reload).await;
+    watcher.abort();
+    exit
+}
+
+async fn run_http_daemon(
+    ctx: &mut DaemonContext<'_>,
+    plan: &StrategyPlan,
+    url: Url,
+    shutdown: &mut ShutdownSignal,
+    reload: &mut ReloadSignal,
+) -> Result<DaemonExit> {
+    let poll_interval = Duration::from_secs(ctx.config.protonvpn.poll_interval_secs.max(1));
+    info!(
+        "starting control-server strategy: polling {} every {:?}",
+        url, poll_interval
+    );
+    let client = http_source::build_client()?;
+    let (tx, rx) = mpsc::channel::<u16>(16);
+    let apply_on_start = ctx.config.protonvpn.apply_on_start;
+    let poller = tokio::spawn(async move {
+        let on_change = move |port| {
+            let _ = tx.try_send(port);
+        };
+        if let Err(err) =
+            http_source::watch_port(client, url, apply_on_start, poll_interval, on_change).await
+        {
+            warn!("control server poller terminated: {err:#}");
+        }
+    });
+
+    let exit = run_push_daemon(ctx, "http", plan, rx, shutdown, reload).await;
+    poller.abort();
+    exit
+}
+
+/// Applies each port a watcher or poller sends on `rx` until shutdown,
+/// reporting under `strategy`.
+async fn run_push_daemon(
+    ctx: &mut DaemonContext<'_>,
+    strategy: &'static str,
+    plan: &StrategyPlan,
+    mut rx: mpsc::Receiver<u16>,
+    shutdown: &mut ShutdownSignal,
+    reload: &mut ReloadSignal,
+) -> Result<DaemonExit> {
+    let DaemonContext {
+        cli,
+        config,
+        instances,
+        events,
+        hooks,
+        output,
+        stats,
+        #[cfg(feature = "metrics")]
+        health_flag,
+    } = ctx;
+    let mut failures = FailureTracker::new(config.daemon.max_consecutive_failures);
+    let mut coalescer = ApplyCoalescer::new(config.min_apply_interval());
+    let mut flap_guard = FlapGuard::new(
+        config.daemon.ignore_recent_ports,
+        config.recent_port_window(),
+    );
+    let mut external_changes = ExternalChangeDetector::default();
+    let mut watchdog = Watchdog::from_env();
     loop {
+        // Biased so a port that is already queued on `rx` replaces the
+        // pending one before it is applied.
         tokio::select! {
-            _ = signal::ctrl_c() => {
+            biased;
+            _ = shutdown.recv() => {
                 info!("received shutdown signal");
-                return Ok(());
+                log_shutdown_summary(stats, output);
+                #[cfg(feature = "metrics")]
+                flush_final_metrics(config);
+                return Ok(DaemonExit::Shutdown);
+            }
+            _ = reload.recv() => {
+                if let Some(next) = reload_config(cli, config, plan, events) {
+                    return Ok(DaemonExit::Switch(next));
+                }
             }
-            Some(port) = rx.recv() => {
+            _ = watchdog.tick() => systemd::notify_watchdog(),
+            Some(port) = rx.recv() => coalescer.push(port),
+            port = coalescer.next_port() => {
+                if flap_guard.suppresses(port) {
+                    continue;
+                }
+                let cycle = stats.start_cycle();
                 info!("applying forwarded port {}", port);
-                match client.set_listen_port(port, bind_interface.as_deref()).await {
-                    Ok(update) => {
+                match apply_port(
+                    instances,
+                    port,
+                    AddressFamily::V4,
+                    config,
+                    events,
+                    hooks,
+                )
+                .await
+                {
+                    Ok(applied) => {
+                        if !applied.update.interface_absent {
+                            flap_guard.record_applied(port);
+                            external_changes.observe(
+                                port,
+                                applied.update.previous_port,
+                                applied.update.detected_port,
+                            );
+                        }
+                        if let Some(err) = applied.failure.as_ref() {
+                            warn!("forwarded port {} only partially applied: {err:#}", port);
+                        }
+                        output.emit(cycle, || {
+                            applied_report(strategy, &applied, None, output.dry_run)
+                        });
                         #[cfg(feature = "metrics")]
                         {
-                            metrics::counter!("qb_port_sync_port_updates_total").increment(1);
-                            metrics::gauge!("qb_port_sync_current_port")
-                                .set(update.detected_port as f64);
-                            metrics::gauge!("qb_port_sync_last_update_timestamp_seconds").set(
-                                std::time::SystemTime::now()
-                                    .duration_since(std::time::UNIX_EPOCH)
-                                    .unwrap_or_default()
-                                    .as_secs() as f64,
+                            health_flag.set(
+                                applied.failure.is_none() && !applied.update.interface_absent,
                             );
-                            health_flag.store(true, Ordering::Relaxed);
+                            record_active_strategy(strategy);
                         }
+                        if applied.succeeded(output.dry_run) {
+                            systemd::notify_ready();
+                            stats.record_update(applied.update.detected_port);
+                        } else {
+                            stats.record_failure();
+                        }
+                        failures.record(applied.succeeded(output.dry_run))?;
                     }
                     Err(err) => {
                         warn!("failed to apply forwarded port {}: {err:#}", port);
+                        output.emit(cycle, || failure_report(strategy, &err, output.dry_run));
                         #[cfg(feature = "metrics")]
-                        health_flag.store(false, Ordering::Relaxed);
+                        health_flag.set(false);
+                        stats.record_failure();
+                        failures.record(false)?;
                     }
                 }
             }
@@ -439,97 +1291,1395 @@ async fn run_file_daemon(
 }
 
 async fn run_portmap_daemon(
+    ctx: &mut DaemonContext<'_>,
+    plan: &StrategyPlan,
     mode: PortmapMode,
-    config: &Config,
-    client: QbitClient,
-    #[cfg(feature = "metrics")] health_flag: Arc<AtomicBool>,
-) -> Result<()> {
+    shutdown: &mut ShutdownSignal,
+    reload: &mut ReloadSignal,
+) -> Result<DaemonExit> {
     info!("starting port-mapping strategy: {:?}", mode);
+    let mut failures = FailureTracker::new(ctx.config.daemon.max_consecutive_failures);
+    let mut state = PortmapState::new(&ctx.config);
+    let mut watchdog = Watchdog::from_env();
     loop {
-        let next_delay = match portmap_cycle(
-            &mode,
+        // A cycle can wait out `min_apply_interval_secs`, so signals and the
+        // watchdog are served while it runs.
+        let cycle = {
+            let cycle = portmap_cycle(ctx, &mode, &mut state);
+            tokio::pin!(cycle);
+            loop {
+                tokio::select! {
+                    result = &mut cycle => break Some(result),
+                    _ = shutdown.recv() => break None,
+                    _ = watchdog.tick() => systemd::notify_watchdog(),
+                }
+            }
+        };
+        let Some(cycle) = cycle else {
+            return Ok(shutdown_portmap(ctx, &mut state).await);
+        };
+        let DaemonContext {
+            cli,
             config,
-            &client,
+            events,
+            output,
+            stats,
             #[cfg(feature = "metrics")]
-            &health_flag,
-        )
-        .await
-        {
-            Ok(delay) => delay,
+            health_flag,
+            ..
+        } = &mut *ctx;
+        let next_delay = match cycle {
+            Ok((delay, succeeded)) => {
+                if succeeded {
+                    systemd::notify_ready();
+                } else {
+                    stats.record_failure();
+                }
+                failures.record(succeeded)?;
+                delay
+            }
             Err(err) => {
                 warn!("port mapping cycle failed: {err:#}");
+                stats.record_failure();
+                output.emit(stats.cycles, || {
+                    failure_report(portmap_mode_label(mode), &err, output.dry_run)
+                });
                 #[cfg(feature = "metrics")]
-                health_flag.store(false, Ordering::Relaxed);
-                Duration::from_secs(config.portmap.refresh_secs)
+                {
+                    health_flag.set(false);
+                    metrics::counter!(metric_name("mapping_failures_total")).increment(1);
+                }
+                failures.record(false)?;
+                let delay = config.portmap_error_delay(failures.consecutive());
+                if failures.consecutive() > 1 {
+                    info!(
+                        "{} consecutive failures; retrying in {}s",
+                        failures.consecutive(),
+                        delay.as_secs()
+                    );
+                }
+                delay
             }
         };
 
-        tokio::select! {
-            _ = signal::ctrl_c() => {
-                info!("received shutdown signal");
-                return Ok(());
+        let mut wait = next_delay + refresh_jitter(config.portmap.refresh_jitter_secs);
+        if let Some(ttl) = state.mapping.as_ref().and_then(|map| map.ttl) {
+            // Jitter must not push the refresh past the point where the
+            // current mapping is about to lapse.
+            wait = wait.min((ttl * 3 / 4).max(next_delay));
+        }
+        #[cfg(feature = "metrics")]
+        health_flag.set_max_staleness(config.health_max_staleness(Some(wait)));
+        let next_cycle = time::sleep(wait);
+        tokio::pin!(next_cycle);
+        loop {
+            tokio::select! {
+                _ = shutdown.recv() => return Ok(shutdown_portmap(ctx, &mut state).await),
+                _ = reload.recv() => {
+                    if let Some(next) = reload_config(cli, config, plan, events) {
+                        if let Some(mapping) = state.mapping.take() {
+                            release_on_shutdown(&mapping).await;
+                        }
+                        return Ok(DaemonExit::Switch(next));
+                    }
+                    // The gateway settings may have changed: discover it again.
+                    state.gateways = GatewayCache::new(config.gateway_cache_ttl());
+                }
+                _ = watchdog.tick() => systemd::notify_watchdog(),
+                _ = &mut next_cycle => break,
             }
-            _ = time::sleep(next_delay) => {}
         }
     }
 }
 
+/// Ends the port-mapping daemon on SIGINT or SIGTERM, releasing the mapping.
+async fn shutdown_portmap(ctx: &DaemonContext<'_>, state: &mut PortmapState) -> DaemonExit {
+    info!("received shutdown signal");
+    log_shutdown_summary(&ctx.stats, &ctx.output);
+    if let Some(mapping) = state.mapping.take() {
+        release_on_shutdown(&mapping).await;
+    }
+    #[cfg(feature = "metrics")]
+    flush_final_metrics(&ctx.config);
+    DaemonExit::Shutdown
+}
+
 async fn portmap_cycle(
+    ctx: &mut DaemonContext<'_>,
     mode: &PortmapMode,
-    config: &Config,
-    client: &QbitClient,
-    #[cfg(feature = "metrics")] health_flag: &Arc<AtomicBool>,
-) -> Result<Duration> {
-    let bind_interface = config.bind_interface();
-    let map = match mode {
-        PortmapMode::Auto => map_prefer_pcp_fallback_natpmp(&config.portmap).await?,
-        PortmapMode::PcpOnly => map_with_pcp(&config.portmap).await?,
-        PortmapMode::NatOnly => map_with_natpmp(&config.portmap).await?,
+    state: &mut PortmapState,
+) -> Result<(Duration, bool)> {
+    let DaemonContext {
+        config,
+        instances,
+        events,
+        hooks,
+        output,
+        stats,
+        #[cfg(feature = "metrics")]
+        health_flag,
+        ..
+    } = ctx;
+    stats.start_cycle();
+    let mapping_started = Instant::now();
+    let map = match state.gateways.resolve(&config.portmap) {
+        Ok(gateways) => match mode {
+            PortmapMode::Auto => map_prefer_pcp_fallback_natpmp(&config.portmap, &gateways).await,
+            PortmapMode::PcpOnly => map_with_pcp(&config.portmap, &gateways).await,
+            PortmapMode::NatOnly => map_with_natpmp(&config.portmap, &gateways).await,
+            PortmapMode::UpnpOnly => map_with_upnp(&config.portmap, &gateways).await,
+        },
+        Err(err) => Err(err),
+    };
+    let mapping_duration = mapping_started.elapsed();
+    let map = match map {
+        Ok(map) => map,
+        Err(err) => {
+            // The gateway may have moved (VPN reconnect); discover it again.
+            state.gateways.invalidate();
+            events.record(EventKind::MappingFailed, format!("{err:#}"));
+            return Err(err);
+        }
     };
+    if let Err(err) = ensure_acceptable_port(&config.portmap, map.external_port) {
+        events.record(EventKind::MappingFailed, format!("{err:#}"));
+        return Err(err);
+    }
 
+    state.mapping = Some(map.clone());
     let label = map_strategy_label(*mode, map.strategy);
+    let on_address = map
+        .external_ip
+        .map(|address| format!(" on {address}"))
+        .unwrap_or_default();
     info!(
-        "port mapping obtained via {}: external {}",
+        "port mapping obtained via {}: external {}{on_address}",
         label, map.external_port
     );
-    let update = client
-        .set_listen_port(map.external_port, bind_interface)
-        .await?;
+    let delay = config.portmap_refresh_delay(map.ttl);
+    let port = state
+        .coalescer
+        .apply_port_coalesced(map.external_port)
+        .await;
+    if state.flap_guard.suppresses(port) {
+        return Ok((delay, true));
+    }
+    let applied = apply_port(instances, port, map.family, config, events, hooks).await?;
+    if !applied.update.interface_absent {
+        state.flap_guard.record_applied(port);
+        state.external_changes.observe(
+            port,
+            applied.update.previous_port,
+            applied.update.detected_port,
+        );
+    }
+    let external_ip = if config.portmap.external_ip {
+        monitor_public_ip(config, map.external_ip, &mut state.public_ip, events)
+            .await
+            .map(IpAddr::V4)
+    } else {
+        None
+    }
+    .or(map.external_ip);
+    output.emit(stats.cycles, || {
+        let mut report = applied_report(&label, &applied, map.ttl, output.dry_run);
+        report.external_ip = external_ip.map(|ip| ip.to_string());
+        report.diagnostics = Some(diagnostics(&applied, Some((&map, mapping_duration))));
+        report
+    });
 
     #[cfg(feature = "metrics")]
     {
-        metrics::counter!("qb_port_sync_port_updates_total").increment(1);
-        metrics::gauge!("qb_port_sync_current_port").set(update.detected_port as f64);
-        metrics::gauge!("qb_port_sync_last_update_timestamp_seconds").set(
-            std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap_or_default()
-                .as_secs() as f64,
-        );
-        health_flag.store(update.verified, Ordering::Relaxed);
+        health_flag.set(applied.verified && applied.failure.is_none());
+        record_active_strategy(&label);
     }
 
-    if !update.verified {
+    if let Some(err) = applied.failure.as_ref() {
+        warn!("port {} only partially applied: {err:#}", map.external_port);
+    }
+    if !applied.verified {
         warn!(
             "listen port verification failed after applying {}",
             map.external_port
         );
     }
-    let delay = map
-        .ttl
-        .map(|ttl| (ttl / 2).max(Duration::from_secs(10)))
-        .unwrap_or_else(|| Duration::from_secs(config.portmap.refresh_secs));
+    if applied.succeeded(output.dry_run) {
+        stats.record_update(applied.update.detected_port);
+    }
     info!("next mapping refresh in {} seconds", delay.as_secs());
-    Ok(delay)
+    #[cfg(feature = "metrics")]
+    record_mapping_schedule(map.ttl, delay);
+    Ok((delay, applied.succeeded(output.dry_run)))
+}
+
+/// State carried from one port-mapping cycle to the next.
+#[derive(Debug)]
+struct PortmapState {
+    public_ip: PublicIpMonitor,
+    gateways: GatewayCache,
+    /// Last mapping the gateway granted, released on shutdown.
+    mapping: Option<MapResult>,
+    coalescer: ApplyCoalescer,
+    flap_guard: FlapGuard,
+    external_changes: ExternalChangeDetector,
+}
+
+impl PortmapState {
+    fn new(config: &Config) -> Self {
+        PortmapState {
+            public_ip: PublicIpMonitor::default(),
+            gateways: GatewayCache::new(config.gateway_cache_ttl()),
+            mapping: None,
+            coalescer: ApplyCoalescer::new(config.min_apply_interval()),
+            flap_guard: FlapGuard::new(
+                config.daemon.ignore_recent_ports,
+                config.recent_port_window(),
+            ),
+            external_changes: ExternalChangeDetector::default(),
+        }
+    }
+}
+
+/// Running totals of a daemon loop, logged on shutdown.
+#[derive(Debug)]
+struct DaemonStats {
+    started: Instant,
+    /// Cycles run so far, failed ones included.
+    cycles: u64,
+    updates: u64,
+    failures: u64,
+    last_port: Option<u16>,
+}
+
+impl DaemonStats {
+    fn new() -> Self {
+        DaemonStats {
+            started: Instant::now(),
+            cycles: 0,
+            updates: 0,
+            failures: 0,
+            last_port: None,
+        }
+    }
+
+    /// Counts a daemon cycle, whatever its outcome, and returns its number.
+    fn start_cycle(&mut self) -> u64 {
+        self.cycles += 1;
+        #[cfg(feature = "metrics")]
+        metrics::counter!(metric_name("cycles_total")).increment(1);
+        self.cycles
+    }
+
+    fn record_update(&mut self, port: u16) {
+        self.updates += 1;
+        let previous = self.last_port.replace(port);
+        if previous != Some(port) {
+            #[cfg(feature = "metrics")]
+            record_port_change(previous.is_some());
+        }
+    }
+
+    fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+}
+
+/// Logs the daemon's totals before it exits on a signal and, with `--json`,
+/// emits them as a `"kind":"summary"` line.
+fn log_shutdown_summary(stats: &DaemonStats, output: &OutputMode) {
+    let uptime = stats.started.elapsed().as_secs();
+    let last_port = stats
+        .last_port
+        .map_or_else(|| "none".to_string(), |port| port.to_string());
+    info!(
+        "shutting down after {}s: {} cycles, {} updates, {} failures, last port {}",
+        uptime, stats.cycles, stats.updates, stats.failures, last_port
+    );
+    if output.json || output.file.is_some() {
+        output.write_line(&json_line(&SummaryReport {
+            kind: "summary",
+            cycles: stats.cycles,
+            updates: stats.updates,
+            failures: stats.failures,
+            last_port: stats.last_port,
+            uptime_secs: uptime,
+            timestamp: rfc3339(SystemTime::now()),
+        }));
+    }
+}
+
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Pause between `--once` attempts while a `--deadline` allows retries.
+const ONCE_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Asks the gateway to drop the current mapping so it does not keep a stale
+/// forward. Failures are only logged: the gateway may already have dropped it.
+async fn release_on_shutdown(mapping: &MapResult) {
+    match time::timeout(RELEASE_TIMEOUT, release_mapping(mapping)).await {
+        Ok(Ok(())) => info!(
+            "released port mapping for external port {}",
+            mapping.external_port
+        ),
+        Ok(Err(err)) => warn!(
+            "failed to release port mapping for external port {}: {err:#}",
+            mapping.external_port
+        ),
+        Err(_) => warn!(
+            "releasing port mapping for external port {} timed out",
+            mapping.external_port
+        ),
+    }
+}
+
+/// Takes the gateway's public address from the mapping, or queries it, and
+/// records a change from the previous cycle, which usually means the VPN
+/// moved to another server.
+async fn monitor_public_ip(
+    config: &Config,
+    reported: Option<IpAddr>,
+    monitor: &mut PublicIpMonitor,
+    events: &EventLog,
+) -> Option<Ipv4Addr> {
+    // Reuse the address from the mapping response instead of asking again.
+    let address = match reported {
+        Some(IpAddr::V4(address)) => address,
+        _ => external_address(&config.portmap).await?,
+    };
+    if let Some(previous) = monitor.observe(address) {
+        warn!("gateway public address changed from {previous} to {address}");
+        events.record(
+            EventKind::PublicIpChanged,
+            format!("{previous} -> {address}"),
+        );
+        #[cfg(feature = "metrics")]
+        metrics::counter!(metric_name("public_ip_changes_total")).increment(1);
+    } else {
+        debug!("gateway public address {address}");
+    }
+    Some(address)
+}
+
+/// SIGINT, or SIGTERM as sent by `systemctl stop` on Unix. One is created per
+/// daemon run and polled by every `select!`, so a signal that arrives while a
+/// cycle is busy is still seen once the loop waits again.
+struct ShutdownSignal {
+    #[cfg(unix)]
+    interrupt: Option<signal::unix::Signal>,
+    #[cfg(unix)]
+    terminate: Option<signal::unix::Signal>,
+}
+
+impl ShutdownSignal {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            use signal::unix::SignalKind;
+            let interrupt = signal::unix::signal(SignalKind::interrupt())
+                .map_err(|err| warn!("failed to install SIGINT handler: {err}"))
+                .ok();
+            let terminate = signal::unix::signal(SignalKind::terminate())
+                .map_err(|err| warn!("failed to install SIGTERM handler: {err}"))
+                .ok();
+            ShutdownSignal {
+                interrupt,
+                terminate,
+            }
+        }
+
+        #[cfg(not(unix))]
+        ShutdownSignal {}
+    }
+
+    /// Completes when the daemon is asked to stop.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        {
+            async fn next(signal: Option<&mut signal::unix::Signal>) {
+                match signal {
+                    Some(signal) => {
+                        signal.recv().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            }
+            tokio::select! {
+                _ = next(self.interrupt.as_mut()) => {}
+                _ = next(self.terminate.as_mut()) => {}
+            }
+        }
+
+        #[cfg(not(unix))]
+        {
+            let _ = signal::ctrl_c().await;
+        }
+    }
+}
+
+/// SIGHUP stream asking the daemon to reload its configuration. It never
+/// fires off Unix or when the handler cannot be installed.
+struct ReloadSignal {
+    #[cfg(unix)]
+    hangup: Option<signal::unix::Signal>,
+}
+
+impl ReloadSignal {
+    fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+                .map_err(|err| warn!("failed to install SIGHUP handler: {err}"))
+                .ok();
+            ReloadSignal { hangup }
+        }
+
+        #[cfg(not(unix))]
+        ReloadSignal {}
+    }
+
+    /// Completes when a reload is requested.
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(hangup) = self.hangup.as_mut() {
+            hangup.recv().await;
+            return;
+        }
+        std::future::pending::<()>().await
+    }
+}
+
+/// Loads the configuration again and takes its live settings over into
+/// `config`, warning about changes that need a restart. Returns the plan to
+/// switch to when the strategy now resolves differently, or when the
+/// forwarded-port source changed and its watcher must start over.
+fn reload_config(
+    cli: &Cli,
+    config: &mut Config,
+    plan: &StrategyPlan,
+    events: &EventLog,
+) -> Option<StrategyPlan> {
+    info!("received SIGHUP; reloading configuration");
+    let fresh = match load_config(cli) {
+        Ok(fresh) => fresh,
+        Err(err) => {
+            warn!("keeping the running configuration: {err:#}");
+            return None;
+        }
+    };
+    let diff = config.apply_reload(fresh);
+    for key in &diff.restart_required {
+        warn!("{key} changed; restart qb-port-sync to apply it");
+    }
+    if diff.applied.is_empty() {
+        info!("no reloadable settings changed");
+        return None;
+    }
+    info!(
+        "reloaded {}; applying from the next cycle",
+        diff.applied.join(", ")
+    );
+    events.record(EventKind::ConfigReloaded, diff.applied.join(", "));
+    let next = match resolve_plan(cli.strategy, config) {
+        Ok(next) => next,
+        Err(err) => {
+            warn!("keeping the current strategy: {err:#}");
+            return None;
+        }
+    };
+    let source_changed = !matches!(plan, StrategyPlan::Portmap { .. })
+        && diff.applied.iter().any(|key| key.starts_with("protonvpn."));
+    (next != *plan || source_changed).then_some(next)
+}
+
+/// Counts failed daemon cycles in a row and errors once
+/// `daemon.max_consecutive_failures` is exceeded (0 means never).
+struct FailureTracker {
+    limit: u32,
+    count: u32,
+}
+
+impl FailureTracker {
+    fn new(limit: u32) -> Self {
+        FailureTracker { limit, count: 0 }
+    }
+
+    /// Failed cycles since the last success.
+    fn consecutive(&self) -> u32 {
+        self.count
+    }
+
+    fn record(&mut self, succeeded: bool) -> Result<()> {
+        self.count = if succeeded { 0 } else { self.count + 1 };
+        #[cfg(feature = "metrics")]
+        metrics::gauge!(metric_name("consecutive_failures")).set(self.count as f64);
+        if self.limit > 0 && self.count > self.limit {
+            error!(
+                "{} consecutive failed cycles exceed daemon.max_consecutive_failures = {}; exiting so the supervisor can restart",
+                self.count, self.limit
+            );
+            return Err(anyhow!(
+                "giving up after {} consecutive failed cycles",
+                self.count
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Logs in to the clients selected by `client.kind`: every qBittorrent
+/// instance, or the Deluge or Transmission one. Each result carries the name the client
+/// is reported under.
+async fn connect_clients(
+    config: &Config,
+    dry_run: bool,
+    events: &EventLog,
+) -> Vec<(String, Result<Instance>)> {
+    let kind = config.client.kind;
+    match kind {
+        ClientKind::Qbittorrent => {}
+        ClientKind::Deluge => {
+            if let Some(deluge) = &config.deluge {
+                let connected = connect_deluge(deluge, dry_run).await;
+                return vec![(kind.label().to_string(), connected)];
+            }
+        }
+        ClientKind::Transmission => {
+            if let Some(transmission) = &config.transmission {
+                let connected = connect_transmission(transmission, dry_run).await;
+                return vec![(kind.label().to_string(), connected)];
+            }
+        }
+    }
+    let mut clients = Vec::with_capacity(config.qbittorrent.len());
+    for qb in &config.qbittorrent {
+        let connected = connect_instance(qb, config, dry_run, events).await;
+        clients.push((qb.label().to_string(), connected));
+    }
+    clients
+}
+
+async fn connect_deluge(deluge: &DelugeConfig, dry_run: bool) -> Result<Instance> {
+    let password = deluge.resolved_password()?;
+    let mut client =
+        DelugeClient::new(deluge.resolved_base_url()?, deluge.timeout())?.with_dry_run(dry_run);
+    client.login(&password).await?;
+    Ok(Instance {
+        name: ClientKind::Deluge.label().to_string(),
+        client: Box::new(client),
+        reachability_check: None,
+    })
+}
+
+async fn connect_transmission(
+    transmission: &TransmissionConfig,
+    dry_run: bool,
+) -> Result<Instance> {
+    let client =
+        TransmissionClient::new(transmission.resolved_base_url()?, transmission.timeout())?
+            .with_credentials(transmission.credentials()?)
+            .with_dry_run(dry_run);
+    client.connect().await?;
+    Ok(Instance {
+        name: ClientKind::Transmission.label().to_string(),
+        client: Box::new(client),
+        reachability_check: None,
+    })
+}
+
+async fn connect_instance(
+    qb: &QbittorrentConfig,
+    config: &Config,
+    dry_run: bool,
+    events: &EventLog,
+) -> Result<Instance> {
+    let password = qb.resolved_password()?;
+    let base_url = qb.resolved_base_url()?;
+    let mut client = QbitClient::new(
+        base_url,
+        qb.timeout(),
+        qb.http_version,
+        qb.tls_ca_cert.as_deref(),
+        qb.tls_accept_invalid_certs,
+        qb.user_agent.as_deref(),
+        qb.extra_header_map()?,
+    )?
+    .with_interface_id_retry(config.net.retry_interface_id)
+    .with_dry_run(dry_run)
+    .with_extra_port_keys(qb.also_set_keys.clone())
+    .with_port_settings_enforcement(qb.enforce_port_settings)
+    .with_managed_port_settings(qb.manage_random_port, qb.manage_upnp)
+    .with_interface_guard(qb.only_if_interface_present)
+    .with_login_retries(
+        qb.login_retries,
+        Duration::from_secs(qb.login_retry_delay_secs),
+    )
+    .with_ban_cooldown(Duration::from_secs(qb.ban_cooldown_secs))
+    .with_event_log(events.clone());
+    client.login(&qb.username, &password).await?;
+    Ok(Instance {
+        name: qb.label().to_string(),
+        client: Box::new(client),
+        reachability_check: qb.reachability_check(),
+    })
+}
+
+/// Logs in to the client reported as `name` alone, for [`PendingClient`].
+async fn connect_named(
+    config: &Config,
+    name: &str,
+    dry_run: bool,
+    events: &EventLog,
+) -> Result<Instance> {
+    match config.client.kind {
+        ClientKind::Deluge => {
+            if let Some(deluge) = &config.deluge {
+                return connect_deluge(deluge, dry_run).await;
+            }
+        }
+        ClientKind::Transmission => {
+            if let Some(transmission) = &config.transmission {
+                return connect_transmission(transmission, dry_run).await;
+            }
+        }
+        ClientKind::Qbittorrent => {}
+    }
+    let qb = config
+        .qbittorrent
+        .iter()
+        .find(|qb| qb.label() == name)
+        .ok_or_else(|| anyhow!("no client instance named {name}"))?;
+    connect_instance(qb, config, dry_run, events).await
+}
+
+/// Stands in for an instance whose login failed at startup, so the others
+/// are still updated. Each call tries to log in again first; until one
+/// succeeds the instance reports the login error like a failed update.
+/// While qBittorrent bans this address no login is attempted, since each
+/// one would extend the ban.
+struct PendingClient {
+    name: String,
+    config: Config,
+    dry_run: bool,
+    events: EventLog,
+    banned_until: std::sync::Mutex<Option<Instant>>,
+    connected: tokio::sync::OnceCell<Instance>,
+}
+
+impl PendingClient {
+    fn new(
+        name: String,
+        config: &Config,
+        dry_run: bool,
+        events: &EventLog,
+        login_error: &anyhow::Error,
+    ) -> Self {
+        PendingClient {
+            name,
+            config: config.clone(),
+            dry_run,
+            events: events.clone(),
+            banned_until: std::sync::Mutex::new(ban_deadline(login_error)),
+            connected: tokio::sync::OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> Result<&dyn TorrentClient> {
+        let instance = self
+            .connected
+            .get_or_try_init(|| async {
+                let mut banned_until = *self.banned_until.lock().unwrap_or_else(|e| e.into_inner());
+                if let Some(remaining) =
+                    banned_until.and_then(|until| until.checked_duration_since(Instant::now()))
+                {
+                    return Err(QbitError::Banned(remaining).into());
+                }
+                let connected =
+                    connect_named(&self.config, &self.name, self.dry_run, &self.events).await;
+                if let Err(err) = &connected {
+                    banned_until = ban_deadline(err);
+                    *self.banned_until.lock().unwrap_or_else(|e| e.into_inner()) = banned_until;
+                }
+                let instance = connected?;
+                info!("{}: logged in", self.name);
+                Ok::<_, anyhow::Error>(instance)
+            })
+            .await?;
+        Ok(instance.client.as_ref())
+    }
+}
+
+/// When a login refused with a ban may be tried again.
+fn ban_deadline(err: &anyhow::Error) -> Option<Instant> {
+    match err.downcast_ref::<QbitError>() {
+        Some(QbitError::Banned(cooldown)) => Some(Instant::now() + *cooldown),
+        _ => None,
+    }
+}
+
+#[async_trait::async_trait]
+impl TorrentClient for PendingClient {
+    async fn set_listen_port(
+        &self,
+        port: u16,
+        bind_interface: Option<&str>,
+    ) -> Result<PortUpdateResult> {
+        self.client()
+            .await?
+            .set_listen_port(port, bind_interface)
+            .await
+    }
+
+    async fn get_port_status(&self) -> Result<qbit::PortStatus> {
+        self.client().await?.get_port_status().await
+    }
+
+    async fn apply_interface_binding(&self, interface: &str) -> Result<qbit::InterfaceBinding> {
+        self.client()
+            .await?
+            .apply_interface_binding(interface)
+            .await
+    }
+
+    async fn apply_safe_binding(&self, interface: &str) -> Result<String> {
+        self.client().await?.apply_safe_binding(interface).await
+    }
+}
+
+/// Applies `port` to every instance in turn. A failing instance does not
+/// stop the others; the call only errors when none accepted the port.
+async fn apply_port(
+    instances: &[Instance],
+    port: u16,
+    family: AddressFamily,
+    config: &Config,
+    events: &EventLog,
+    hooks: &HookRunner,
+) -> Result<AppliedPort> {
+    let bind_interface = config.bind_interface();
+    let mut reachable = None;
+    let mut first_update = None;
+    let mut verified = true;
+    let mut family_mismatch = false;
+    let mut reports = Vec::with_capacity(instances.len());
+    let mut failures = Vec::new();
+    let mut client_duration = Duration::ZERO;
+
+    for instance in instances {
+        #[cfg(feature = "metrics")]
+        let labels = instance_labels(instances, instance);
+        let started = Instant::now();
+        let result = instance.client.set_listen_port(port, bind_interface).await;
+        client_duration += started.elapsed();
+        match result {
+            Ok(mut update) => {
+                if let Some(checker) = instance.reachability_check.as_deref() {
+                    if update.verified {
+                        if reachable.is_none() {
+                            reachable =
+                                Some(probe_reachability(checker, update.detected_port).await);
+                        }
+                        update.reachable = reachable.flatten();
+                    }
+                }
+                if update.interface_absent {
+                    // Nothing reached qBittorrent, so none of the success
+                    // bookkeeping below applies.
+                    #[cfg(feature = "metrics")]
+                    record_apply_skipped(labels);
+                } else {
+                    #[cfg(feature = "metrics")]
+                    record_port_update(&update, labels);
+                    events.record(
+                        EventKind::PortApplied,
+                        format!(
+                            "{}: port {} (verified: {})",
+                            instance.name, update.detected_port, update.verified
+                        ),
+                    );
+                }
+                verified &= update.verified;
+                if let Some(addr) = update.listen_address {
+                    if AddressFamily::of(addr) != family {
+                        warn!(
+                            "{} listens only on {} but the port was mapped for {:?}; it will not be reachable",
+                            instance.name, addr, family
+                        );
+                        family_mismatch = true;
+                    }
+                }
+                reports.push(InstanceReport {
+                    name: instance.name.clone(),
+                    applied: !update.interface_absent,
+                    verified: update.verified,
+                    detected_port: Some(update.detected_port),
+                    reachable: update.reachable,
+                    error: None,
+                });
+                first_update.get_or_insert(update);
+            }
+            Err(err) => {
+                #[cfg(feature = "metrics")]
+                record_apply_failure(labels);
+                events.record(
+                    EventKind::ApplyFailed,
+                    format!("{}: port {port}: {err:#}", instance.name),
+                );
+                reports.push(InstanceReport {
+                    name: instance.name.clone(),
+                    error: Some(format!("{err:#}")),
+                    ..InstanceReport::default()
+                });
+                failures.push((instance.name.as_str(), err));
+            }
+        }
+    }
+
+    // With several instances the summary wraps the most severe error, so
+    // the exit code still reflects it.
+    let failure = if instances.len() == 1 {
+        failures.pop().map(|(_, err)| err)
+    } else if failures.is_empty() {
+        None
+    } else {
+        let summary = failures
+            .iter()
+            .map(|(name, err)| format!("{name}: {err:#}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        let worst = (0..failures.len())
+            .rev()
+            .max_by_key(|&index| classify_error(&failures[index].1).severity())
+            .unwrap_or_default();
+        let (_, err) = failures.swap_remove(worst);
+        Some(err.context(format!("failed to apply port {port} to {summary}")))
+    };
+
+    let hook_reports = match first_update.as_ref() {
+        Some(update) if verified && failure.is_none() => {
+            if let Some(path) = config.daemon.state_file.as_deref() {
+                if let Err(err) = state::save_state(path, update.detected_port, family) {
+                    warn!("failed to write state file {}: {err}", path.display());
+                }
+            }
+            hooks.on_verified_port(update.detected_port).await
+        }
+        _ => Vec::new(),
+    };
+    for report in hook_reports.iter().filter(|report| !report.ok) {
+        events.record(
+            EventKind::HookFailed,
+            format!(
+                "{}: {}",
+                report.hook,
+                report.error.as_deref().unwrap_or_default()
+            ),
+        );
+    }
+
+    match first_update {
+        Some(update) => Ok(AppliedPort {
+            update,
+            verified,
+            hooks: hook_reports,
+            family_mismatch,
+            client_duration,
+            instances: if instances.len() > 1 {
+                reports
+            } else {
+                Vec::new()
+            },
+            failure,
+        }),
+        None => Err(failure.unwrap_or_else(|| anyhow!("no qBittorrent instances configured"))),
+    }
+}
+
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Asks the external `checker` whether `port` accepts connections from the
+/// internet. `None` when the checker could not be asked or gave no answer.
+async fn probe_reachability(checker: &str, port: u16) -> Option<bool> {
+    match port_reachable(checker, port, REACHABILITY_TIMEOUT).await {
+        Ok(true) => {
+            info!("port {port} is reachable from outside");
+            Some(true)
+        }
+        Ok(false) => {
+            warn!("port {port} is not reachable from outside");
+            Some(false)
+        }
+        Err(err) => {
+            warn!("cannot check whether port {port} is reachable: {err:#}");
+            None
+        }
+    }
+}
+
+/// Starts the HTTP server for metrics and/or health probes. Metrics install
+/// the Prometheus recorder; a standalone health server skips it entirely.
+#[cfg(feature = "metrics")]
+fn spawn_observability_server(
+    config: &Config,
+    health_flag: &Arc<HealthState>,
+    live_flag: &Arc<AtomicBool>,
+    events: &EventLog,
+) {
+    let metrics_enabled = config.metrics.enabled && config.metrics.port > 0;
+    let health_enabled = config.health.enabled && config.health.port > 0;
+
+    metrics_server::set_namespace(&config.metrics.namespace);
+    let bind_ip = match config.metrics.bind_ip() {
+        Ok(ip) => ip,
+        Err(err) => {
+            warn!("not starting metrics/health server: {err:#}");
+            return;
+        }
+    };
+    let serve_metrics = metrics_enabled || (health_enabled && !config.health.standalone);
+    let handle = if serve_metrics || config.metrics.textfile_path.is_some() {
+        match metrics_server::install_recorder() {
+            Ok(handle) => Some(handle),
+            Err(err) => {
+                warn!("failed to install metrics recorder: {err:#}");
+                return;
+            }
+        }
+    } else {
+        None
+    };
+    let handle = handle.filter(|_| serve_metrics);
+    let port = if health_enabled {
+        config.health.port
+    } else if metrics_enabled {
+        config.metrics.port
+    } else {
+        return;
+    };
+
+    let health_clone = health_flag.clone();
+    let live_clone = live_flag.clone();
+    let events_clone = events.clone();
+    tokio::spawn(async move {
+        if let Err(err) = metrics_server::run_server(
+            bind_ip,
+            port,
+            handle,
+            health_clone,
+            live_clone,
+            events_clone,
+        )
+        .await
+        {
+            error!("metrics/health server failed: {err:#}");
+        }
+    });
+}
+
+/// Writes the metrics one last time to `metrics.textfile_path`, if set, so
+/// the terminal state of a short-lived daemon is captured.
+#[cfg(feature = "metrics")]
+fn flush_final_metrics(config: &Config) {
+    let Some(path) = config.metrics.textfile_path.as_deref() else {
+        return;
+    };
+    match metrics_server::write_textfile(path) {
+        Ok(()) => info!("wrote final metrics to {}", path.display()),
+        Err(err) => warn!(
+            "failed to write final metrics to {}: {err:#}",
+            path.display()
+        ),
+    }
+}
+
+/// Prints the configuration after all resolution for `--print-config`. The
+/// gateway list is replaced by the addresses actually used when they can be
+/// worked out locally; nothing is contacted.
+fn print_resolved_config(config: &Config, json: bool) -> Result<()> {
+    let mut resolved = config.resolved();
+    if let Ok(gateways) = resolve_gateways(&config.portmap) {
+        resolved.portmap.gateway = gateways.iter().map(IpAddr::to_string).collect();
+    }
+    let rendered = if json {
+        serde_json::to_string_pretty(&resolved)? + "\n"
+    } else {
+        toml::to_string_pretty(&resolved)?
+    };
+    print!("{rendered}");
+    Ok(())
+}
+
+/// Whether the forwarded-port file at `path` can be watched, as reported by
+/// `validate` and `--check`. An unwatchable directory is fine when
+/// `protonvpn.watch_mode = "poll"` does not need notifications.
+fn describe_watch_probe(config: &Config, path: &Path) -> std::result::Result<String, String> {
+    match watch::probe_watch(path) {
+        WatchProbe::Watchable if path.exists() => {
+            Ok(format!("{} present and watchable", path.display()))
+        }
+        WatchProbe::Watchable => Ok(format!(
+            "{} not written yet; directory is watchable",
+            path.display()
+        )),
+        WatchProbe::MissingParent(parent) => {
+            Err(format!("directory {} does not exist", parent.display()))
+        }
+        WatchProbe::Unwatchable(reason) => match config.protonvpn.watch_mode {
+            WatchMode::Poll => Ok(format!(
+                "directory of {} cannot be watched ({reason}); polling is configured",
+                path.display()
+            )),
+            WatchMode::Inotify => Err(format!(
+                "directory cannot be watched ({reason}); set protonvpn.watch_mode = \"poll\""
+            )),
+        },
+    }
+}
+
+/// Offline validation for `--check`: the config has already loaded, so this
+/// looks at what the file strategy would rely on.
+fn check_setup(config: &Config, strategy: StrategyOpt) -> JsonReport {
+    let mut report = JsonReport::new("check");
+    let mut notes = vec![match config.source_path() {
+        Some(path) => format!("config ok ({})", path.display()),
+        None => "config ok (environment only)".to_string(),
+    }];
+
+    match resolve_plan(strategy, config) {
+        Ok(StrategyPlan::File { path }) => match describe_watch_probe(config, &path) {
+            Ok(detail) => notes.push(format!("forwarded port file: {detail}")),
+            Err(detail) => {
+                report.set_error_message(format!("forwarded port file: {detail}"), ExitCode::Config)
+            }
+        },
+        Ok(_) => notes.push("file strategy not selected; watcher not checked".to_string()),
+        Err(err) => report.set_error(&err),
+    }
+
+    report.verified = report.error.is_none();
+    report.note = notes.join("; ");
+    for line in &notes {
+        info!("check: {line}");
+    }
+    if let Some(err) = &report.error {
+        error!("check: {err}");
+    }
+    report
+}
+
+/// Re-pins every instance to `net.bind_interface` without touching the
+/// listen port. Errors only when no instance could be rebound.
+async fn rebind_interface(
+    config: &Config,
+    instances: &[Instance],
+    events: &EventLog,
+) -> Result<JsonReport> {
+    let interface = config
+        .bind_interface()
+        .ok_or(ConfigError::MissingBindInterface)?;
+    let mut report = JsonReport::new("interface");
+    report.verified = true;
+    let mut reports = Vec::with_capacity(instances.len());
+    let mut failures = Vec::new();
+
+    for instance in instances {
+        match instance.client.apply_interface_binding(interface).await {
+            Ok(binding) => {
+                info!(
+                    "{}: bound to {} (listen port {} unchanged)",
+                    instance.name,
+                    binding.describe(),
+                    binding.listen_port
+                );
+                report.applied = true;
+                report.verified &= binding.verified;
+                report.detected_port.get_or_insert(binding.listen_port);
+                report.binding.get_or_insert_with(|| binding.describe());
+                reports.push(InstanceReport {
+                    name: instance.name.clone(),
+                    applied: true,
+                    verified: binding.verified,
+                    detected_port: Some(binding.listen_port),
+                    reachable: None,
+                    error: None,
+                });
+            }
+            Err(err) => {
+                events.record(
+                    EventKind::ApplyFailed,
+                    format!("{}: interface {interface}: {err:#}", instance.name),
+                );
+                reports.push(InstanceReport {
+                    name: instance.name.clone(),
+                    error: Some(format!("{err:#}")),
+                    ..InstanceReport::default()
+                });
+                failures.push((instance.name.as_str(), err));
+            }
+        }
+    }
+
+    if !report.applied {
+        return Err(match failures.pop() {
+            Some((_, err)) if instances.len() == 1 => err,
+            _ => anyhow!("failed to bind any qBittorrent instance to {interface}"),
+        });
+    }
+    if !failures.is_empty() {
+        let summary = failures
+            .iter()
+            .map(|(name, err)| format!("{name}: {err:#}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        report.set_error_message(
+            format!("failed to bind {interface} on {summary}"),
+            ExitCode::Transient,
+        );
+    }
+    report.note = format!("listen port left unchanged; bound to {interface}");
+    if instances.len() > 1 {
+        report.instances = reports;
+    }
+    Ok(report)
+}
+
+/// Per-instance metric labels. A single instance keeps unlabeled series so
+/// existing dashboards and alerts still match.
+#[cfg(feature = "metrics")]
+fn instance_labels(instances: &[Instance], instance: &Instance) -> Vec<metrics::Label> {
+    if instances.len() > 1 {
+        vec![metrics::Label::new("instance", instance.name.clone())]
+    } else {
+        Vec::new()
+    }
+}
+
+/// Bounds readiness staleness for the daemon strategy now running, when it
+/// starts or is switched to. The portmap daemon starts from `refresh_secs`
+/// and narrows the bound to the delay it schedules after each cycle.
+#[cfg(feature = "metrics")]
+fn update_max_staleness(health_flag: &HealthState, config: &Config, plan: &StrategyPlan) {
+    let cycle_delay =
+        matches!(plan, StrategyPlan::Portmap { .. }).then(|| config.portmap_refresh_delay(None));
+    health_flag.set_max_staleness(config.health_max_staleness(cycle_delay));
+}
+
+#[cfg(feature = "metrics")]
+fn record_port_update(update: &PortUpdateResult, labels: Vec<metrics::Label>) {
+    metrics::counter!(metric_name("port_updates_total"), labels.clone()).increment(1);
+    metrics::gauge!(metric_name("current_port"), labels.clone()).set(update.detected_port as f64);
+    metrics::gauge!(metric_name("instance_healthy"), labels.clone()).set(if update.verified {
+        1.0
+    } else {
+        0.0
+    });
+    metrics::gauge!(metric_name("last_update_timestamp_seconds"), labels)
+        .set(metrics_server::mark_updated() as f64);
+}
+
+/// An instance skipped because its bind interface is absent is not healthy,
+/// but the skip is neither an update nor an apply failure.
+#[cfg(feature = "metrics")]
+fn record_apply_skipped(labels: Vec<metrics::Label>) {
+    metrics::gauge!(metric_name("instance_healthy"), labels).set(0.0);
+}
+
+#[cfg(feature = "metrics")]
+fn record_apply_failure(labels: Vec<metrics::Label>) {
+    metrics::counter!(metric_name("apply_failures_total"), labels.clone()).increment(1);
+    metrics::gauge!(metric_name("instance_healthy"), labels).set(0.0);
+}
+
+#[cfg(feature = "metrics")]
+const STRATEGY_LABELS: [&str; 5] = ["pcp", "natpmp", "file", "http", "upnp"];
+
+/// Sets `<namespace>_strategy_info` to 1 for the strategy that completed the
+/// last cycle and 0 for the others.
+#[cfg(feature = "metrics")]
+fn record_active_strategy(active: &str) {
+    for strategy in STRATEGY_LABELS {
+        metrics::gauge!(metric_name("strategy_info"), "strategy" => strategy)
+            .set(if strategy == active { 1.0 } else { 0.0 });
+    }
+}
+
+#[cfg(feature = "metrics")]
+fn record_mapping_schedule(ttl: Option<Duration>, delay: Duration) {
+    metrics::gauge!(metric_name("mapping_ttl_seconds"))
+        .set(ttl.map(|ttl| ttl.as_secs_f64()).unwrap_or(0.0));
+    metrics::gauge!(metric_name("next_refresh_timestamp_seconds")).set(
+        (std::time::SystemTime::now() + delay)
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as f64,
+    );
+}
+
+/// Stamps `last_port_change_timestamp_seconds` when the daemon applies a new
+/// port, and counts it in `port_changes_total` when it `replaced` an earlier
+/// one rather than being the first of the run.
+#[cfg(feature = "metrics")]
+fn record_port_change(replaced: bool) {
+    if replaced {
+        metrics::counter!(metric_name("port_changes_total")).increment(1);
+    }
+    metrics::gauge!(metric_name("last_port_change_timestamp_seconds")).set(
+        SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as f64,
+    );
+}
+
+/// Re-applies the port saved in `daemon.state_file` so qBittorrent does not
+/// sit on a stale port until the first cycle completes. Failures are logged
+/// and the daemon carries on.
+async fn restore_last_known_port(
+    config: &Config,
+    instances: &[Instance],
+    events: &EventLog,
+    hooks: &HookRunner,
+) {
+    let Some(saved) = config
+        .daemon
+        .state_file
+        .as_deref()
+        .and_then(state::load_state)
+    else {
+        return;
+    };
+    info!(
+        "re-applying last known good port {} (saved {})",
+        saved.port, saved.updated_at
+    );
+    match apply_port(instances, saved.port, saved.family, config, events, hooks).await {
+        Ok(applied) => {
+            if let Some(err) = applied.failure {
+                warn!(
+                    "last known good port {} only partially applied: {err:#}",
+                    saved.port
+                );
+            }
+        }
+        Err(err) => warn!(
+            "failed to re-apply last known good port {}: {err:#}",
+            saved.port
+        ),
+    }
+}
+
+/// The tunnel looks down when there is neither a forwarded-port file nor a
+/// gateway answer.
+async fn tunnel_looks_down(config: &Config) -> bool {
+    let file_present = config
+        .resolved_forwarded_port_path()
+        .map(|path| path.exists())
+        .unwrap_or(false);
+    if file_present || gateway_reachable(&config.portmap).await {
+        debug!("VPN tunnel appears up; skipping startup leak protection");
+        return false;
+    }
+    true
+}
+
+/// With the tunnel down at startup, binds every instance that still has a
+/// listen port to the interface matching `bind_interface`, or loopback if
+/// none is configured or matches, until a port is applied.
+async fn protect_against_leak(config: &Config, instances: &[Instance]) {
+    let interface = config.bind_interface().unwrap_or(LOOPBACK_INTERFACE);
+    for instance in instances {
+        let listen_port = match instance.client.get_port_status().await {
+            Ok(status) => status.listen_port,
+            Err(err) => {
+                warn!(
+                    "leak protection could not read preferences from {}: {err:#}",
+                    instance.name
+                );
+                continue;
+            }
+        };
+        if listen_port == 0 {
+            continue;
+        }
+
+        match instance.client.apply_safe_binding(interface).await {
+            Ok(bound) => warn!(
+                "VPN tunnel appears down; bound {} to '{}' until a forwarded port is applied",
+                instance.name, bound
+            ),
+            Err(err) => warn!(
+                "failed to apply startup leak protection to {}: {err:#}",
+                instance.name
+            ),
+        }
+    }
+}
+
+/// Builds a status line for a port that reached at least one instance.
+fn applied_report(
+    strategy: &str,
+    applied: &AppliedPort,
+    ttl: Option<Duration>,
+    dry_run: bool,
+) -> JsonReport {
+    let mut report = JsonReport::new(strategy);
+    report.detected_port = Some(applied.update.detected_port);
+    report.applied = !dry_run;
+    report.verified = applied.verified;
+    report.note = build_note(&applied.update, ttl).unwrap_or_default();
+    report.instances = applied.instances.clone();
+    report.hooks = applied.hooks.clone();
+    report.family_mismatch = applied.family_mismatch;
+    report.reachable = applied.update.reachable;
+    report.diagnostics = Some(diagnostics(applied, None));
+    if let Some(err) = applied.failure.as_ref() {
+        report.set_error(err);
+    }
+    report.dry_run = dry_run;
+    report
+}
+
+/// `--verbose-json` fields for an applied port, with the mapping it came
+/// from and how long the gateway took to grant it.
+fn diagnostics(applied: &AppliedPort, mapping: Option<(&MapResult, Duration)>) -> Diagnostics {
+    let millis = |duration: Duration| u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    let mut diagnostics = Diagnostics {
+        qbit_duration_ms: Some(millis(applied.client_duration)),
+        ..Diagnostics::default()
+    };
+    if let Some((map, elapsed)) = mapping {
+        diagnostics.gateway = Some(map.request.gateway.to_string());
+        diagnostics.internal_port = Some(map.request.internal_port);
+        diagnostics.ttl_secs = map.ttl.map(|ttl| ttl.as_secs());
+        diagnostics.mapping_duration_ms = Some(millis(elapsed));
+    }
+    diagnostics
+}
+
+fn failure_report(strategy: &str, err: &anyhow::Error, dry_run: bool) -> JsonReport {
+    let mut report = JsonReport::new(strategy);
+    report.set_error(err);
+    report.dry_run = dry_run;
+    report
+}
+
+fn portmap_mode_label(mode: PortmapMode) -> &'static str {
+    match mode {
+        PortmapMode::Auto => "auto",
+        PortmapMode::PcpOnly => "pcp",
+        PortmapMode::NatOnly => "natpmp",
+        PortmapMode::UpnpOnly => "upnp",
+    }
 }
 
 fn map_strategy_label(mode: PortmapMode, result_strategy: MapStrategy) -> String {
     match mode {
         PortmapMode::PcpOnly => "pcp".to_string(),
         PortmapMode::NatOnly => "natpmp".to_string(),
+        PortmapMode::UpnpOnly => "upnp".to_string(),
         PortmapMode::Auto => match result_strategy {
             MapStrategy::Pcp => "pcp".to_string(),
             MapStrategy::NatPmp => "natpmp".to_string(),
+            MapStrategy::Upnp => "upnp".to_string(),
         },
     }
 }
@@ -545,6 +2695,12 @@ fn build_note(update: &PortUpdateResult, ttl: Option<Duration>) -> Option<String
     if matches!(update.upnp, Some(true)) {
         notes.push("upnp still enabled".to_string());
     }
+    if update.interface_id_missing {
+        notes.push("network_interface_id unresolved".to_string());
+    }
+    if update.interface_absent {
+        notes.push("skipped: interface absent".to_string());
+    }
     if notes.is_empty() {
         None
     } else {
@@ -553,22 +2709,130 @@ fn build_note(update: &PortUpdateResult, ttl: Option<Duration>) -> Option<String
 }
 
 fn prefer_file_strategy(config: &Config) -> bool {
+    file_strategy_probe(config).0
+}
+
+/// Whether `auto` picks the file strategy, with the reasoning behind it:
+/// the forwarded-port file or its directory must exist, and only on Linux.
+fn file_strategy_probe(config: &Config) -> (bool, Vec<String>) {
+    let mut reasons = Vec::new();
+
     #[cfg(target_os = "linux")]
     {
-        if let Some(path) = config.resolved_forwarded_port_path() {
-            if path.exists() {
-                return true;
-            }
-            if let Some(parent) = path.parent() {
-                return parent.exists();
-            }
+        let Some(path) = config.resolved_forwarded_port_path() else {
+            reasons.push("no forwarded port path is configured".to_string());
+            return (false, reasons);
+        };
+        let candidates = config.forwarded_port_candidates();
+        if candidates.len() > 1 {
+            reasons.push(format!(
+                "candidates in order: {}",
+                candidates
+                    .iter()
+                    .map(|candidate| candidate.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        let source = if config.protonvpn.forwarded_port_path.as_ref() == Some(&path) {
+            "protonvpn.forwarded_port_path"
+        } else if config.protonvpn.forwarded_port_paths.contains(&path) {
+            "protonvpn.forwarded_port_paths"
+        } else {
+            "ProtonVPN default"
+        };
+        reasons.push(format!(
+            "forwarded port path: {} ({source})",
+            path.display()
+        ));
+        if path.exists() {
+            reasons.push("the file exists".to_string());
+            return (true, reasons);
+        }
+        reasons.push("the file does not exist".to_string());
+        if let Some(parent) = path.parent() {
+            let exists = parent.exists();
+            reasons.push(format!(
+                "its directory {} {}",
+                parent.display(),
+                if exists {
+                    "exists, so the file is expected there"
+                } else {
+                    "does not exist either"
+                }
+            ));
+            return (exists, reasons);
         }
     }
 
     #[cfg(not(target_os = "linux"))]
-    let _ = config;
+    {
+        let _ = config;
+        reasons.push("auto only picks the file strategy on Linux".to_string());
+    }
+
+    (false, reasons)
+}
+
+/// `--explain`: walks through `resolve_plan` for `strategy` and reports
+/// each step, for telling why a machine ended up on a given strategy.
+fn explain_strategy(config: &Config, strategy: StrategyOpt, json: bool) -> (JsonReport, ExitCode) {
+    let mut report = JsonReport::new(strategy_opt_label(strategy));
+    let mut lines = vec![format!("--strategy {}", strategy_opt_label(strategy))];
+    match strategy {
+        StrategyOpt::Auto => {
+            if config.protonvpn.control_server_url.is_some() {
+                lines.push(
+                    "protonvpn.control_server_url is set, so the http strategy wins".to_string(),
+                );
+            } else {
+                let (prefer_file, reasons) = file_strategy_probe(config);
+                lines.extend(reasons);
+                lines.push(if prefer_file {
+                    "auto uses the file strategy".to_string()
+                } else {
+                    "auto falls back to port mapping (PCP, then NAT-PMP, then UPnP)".to_string()
+                });
+            }
+        }
+        _ => lines.push("chosen explicitly; nothing is probed".to_string()),
+    }
 
-    false
+    let code = match resolve_plan(strategy, config) {
+        Ok(plan) => {
+            let decision = match &plan {
+                StrategyPlan::File { path } => format!("file watching {}", path.display()),
+                StrategyPlan::Http { url } => format!("http polling {url}"),
+                StrategyPlan::Portmap { mode } => {
+                    let gateways = match resolve_gateways(&config.portmap) {
+                        Ok(gateways) => gateways
+                            .iter()
+                            .map(IpAddr::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                        Err(err) => format!("unresolved: {err:#}"),
+                    };
+                    format!("{} via gateway {gateways}", portmap_mode_label(*mode))
+                }
+            };
+            lines.push(format!("decision: {decision}"));
+            ExitCode::Success
+        }
+        Err(err) => {
+            lines.push(format!("decision: none ({err:#})"));
+            report.set_error(&err);
+            classify_error(&err)
+        }
+    };
+
+    report.verified = code == ExitCode::Success;
+    report.note = lines.join("; ");
+    if !json {
+        for line in &lines {
+            println!("{line}");
+        }
+    }
+    (report, code)
 }
 
 fn resolve_plan(strategy: StrategyOpt, config: &Config) -> Result<StrategyPlan> {
@@ -577,14 +2841,24 @@ fn resolve_plan(strategy: StrategyOpt, config: &Config) -> Result<StrategyPlan>
             let path = resolve_forwarded_port_path(config)?;
             Ok(StrategyPlan::File { path })
         }
+        StrategyOpt::Http => Ok(StrategyPlan::Http {
+            url: config.protonvpn.resolved_control_server_url()?,
+        }),
         StrategyOpt::Pcp => Ok(StrategyPlan::Portmap {
             mode: PortmapMode::PcpOnly,
         }),
         StrategyOpt::Natpmp => Ok(StrategyPlan::Portmap {
             mode: PortmapMode::NatOnly,
         }),
+        StrategyOpt::Upnp => Ok(StrategyPlan::Portmap {
+            mode: PortmapMode::UpnpOnly,
+        }),
         StrategyOpt::Auto => {
-            if prefer_file_strategy(config) {
+            if config.protonvpn.control_server_url.is_some() {
+                Ok(StrategyPlan::Http {
+                    url: config.protonvpn.resolved_control_server_url()?,
+                })
+            } else if prefer_file_strategy(config) {
                 let path = resolve_forwarded_port_path(config)?;
                 Ok(StrategyPlan::File { path })
             } else {
@@ -599,8 +2873,10 @@ fn resolve_plan(strategy: StrategyOpt, config: &Config) -> Result<StrategyPlan>
 fn strategy_opt_label(opt: StrategyOpt) -> &'static str {
     match opt {
         StrategyOpt::File => "file",
+        StrategyOpt::Http => "http",
         StrategyOpt::Pcp => "pcp",
         StrategyOpt::Natpmp => "natpmp",
+        StrategyOpt::Upnp => "upnp",
         StrategyOpt::Auto => "auto",
     }
 }
@@ -618,45 +2894,94 @@ fn resolve_forwarded_port_path(config: &Config) -> Result<PathBuf> {
     Err(ConfigError::ForwardedPortUnavailable(path.display().to_string()).into())
 }
 
-fn init_tracing(verbose: u8) {
+fn init_tracing(verbose: u8, quiet: bool) -> LogFile {
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::util::SubscriberInitExt;
+
     let filter = match verbose {
+        _ if quiet => "warn",
         0 => "info",
         1 => "debug",
         _ => "trace",
     };
+    let env_filter =
+        tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| filter.into());
+
+    // Empty until the config names a log file; the filter applies to it too.
+    #[cfg(feature = "file-log")]
+    let (file_layer, log_file) = {
+        let (layer, handle) = tracing_subscriber::reload::Layer::new(None);
+        (layer, LogFile { handle })
+    };
+    #[cfg(not(feature = "file-log"))]
+    let (file_layer, log_file) = (tracing_subscriber::layer::Identity::new(), LogFile {});
 
+    let subscriber = tracing_subscriber::registry()
+        .with(file_layer)
+        .with(env_filter)
+        .with(tracing_subscriber::fmt::layer().with_target(false));
     #[cfg(all(target_os = "linux", feature = "journald"))]
-    {
-        use tracing_subscriber::layer::SubscriberExt;
-        use tracing_subscriber::util::SubscriberInitExt;
+    let subscriber = subscriber.with(tracing_journald::layer().ok());
+    let _ = subscriber.try_init();
+    log_file
+}
+
+#[cfg(feature = "file-log")]
+type LogFileLayer = tracing_subscriber::fmt::Layer<
+    tracing_subscriber::Registry,
+    tracing_subscriber::fmt::format::DefaultFields,
+    tracing_subscriber::fmt::format::Format,
+    tracing_appender::rolling::RollingFileAppender,
+>;
 
-        let env_filter =
-            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| filter.into());
+/// Adds `daemon.log_file` output once the config is loaded; tracing is set
+/// up before that so config errors are still logged.
+struct LogFile {
+    #[cfg(feature = "file-log")]
+    handle: tracing_subscriber::reload::Handle<Option<LogFileLayer>, tracing_subscriber::Registry>,
+}
 
-        let fmt_layer = tracing_subscriber::fmt::layer().with_target(false);
+impl LogFile {
+    #[cfg(feature = "file-log")]
+    fn attach(&self, path: &Path) {
+        use tracing_appender::rolling::{RollingFileAppender, Rotation};
 
-        if let Ok(journald_layer) = tracing_journald::layer() {
-            let _ = tracing_subscriber::registry()
-                .with(env_filter)
-                .with(fmt_layer)
-                .with(journald_layer)
-                .try_init();
-        } else {
-            let _ = tracing_subscriber::registry()
-                .with(env_filter)
-                .with(fmt_layer)
-                .try_init();
+        let (Some(name), directory) = (path.file_name(), path.parent()) else {
+            warn!(
+                "log_file {} names no file; not logging to it",
+                path.display()
+            );
+            return;
+        };
+        let directory = directory
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let appender = match RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix(name.to_string_lossy())
+            .build(directory)
+        {
+            Ok(appender) => appender,
+            Err(err) => {
+                warn!("failed to open log_file {}: {err}", path.display());
+                return;
+            }
+        };
+        let layer = tracing_subscriber::fmt::layer()
+            .with_target(false)
+            .with_ansi(false)
+            .with_writer(appender);
+        match self.handle.reload(Some(layer)) {
+            Ok(()) => debug!("also logging to {}", path.display()),
+            Err(err) => warn!("failed to enable log_file {}: {err}", path.display()),
         }
     }
 
-    #[cfg(not(all(target_os = "linux", feature = "journald")))]
-    {
-        let _ = tracing_subscriber::fmt()
-            .with_env_filter(
-                tracing_subscriber::EnvFilter::try_from_default_env()
-                    .unwrap_or_else(|_| filter.into()),
-            )
-            .with_target(false)
-            .try_init();
+    #[cfg(not(feature = "file-log"))]
+    fn attach(&self, path: &Path) {
+        warn!(
+            "ignoring log_file {}: built without the file-log feature",
+            path.display()
+        );
     }
 }
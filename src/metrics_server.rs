@@ -1,4 +1,8 @@
 #[cfg(feature = "metrics")]
+use crate::events::EventLog;
+#[cfg(feature = "metrics")]
+use crate::report::write_atomic;
+#[cfg(feature = "metrics")]
 use anyhow::Result;
 #[cfg(feature = "metrics")]
 use http_body_util::Full;
@@ -13,33 +17,151 @@ use hyper::{Request, Response, StatusCode};
 #[cfg(feature = "metrics")]
 use hyper_util::rt::TokioIo;
 #[cfg(feature = "metrics")]
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
 #[cfg(feature = "metrics")]
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 #[cfg(feature = "metrics")]
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::path::Path;
+#[cfg(feature = "metrics")]
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 #[cfg(feature = "metrics")]
 use std::sync::Arc;
 #[cfg(feature = "metrics")]
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "metrics")]
 use tokio::net::TcpListener;
 #[cfg(feature = "metrics")]
 use tracing::{error, info};
 
+#[cfg(feature = "metrics")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "metrics")]
+static NAMESPACE: OnceLock<String> = OnceLock::new();
+
+/// Sets the prefix used by [`metric_name`]. Only the first call has effect.
+#[cfg(feature = "metrics")]
+pub fn set_namespace(namespace: &str) {
+    let _ = NAMESPACE.set(namespace.to_string());
+}
+
+/// Full metric name for `suffix` under the configured namespace
+/// (`qb_port_sync` unless `[metrics] namespace` says otherwise).
+#[cfg(feature = "metrics")]
+pub fn metric_name(suffix: &str) -> String {
+    let namespace = NAMESPACE.get().map_or("qb_port_sync", String::as_str);
+    format!("{namespace}_{suffix}")
+}
+
+/// Unix time of the last port update, the value exported as
+/// `last_update_timestamp_seconds`; 0 before the first one.
+#[cfg(feature = "metrics")]
+static LAST_UPDATE_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Records a port update happening now and returns its Unix timestamp for
+/// the `last_update_timestamp_seconds` gauge.
+#[cfg(feature = "metrics")]
+pub fn mark_updated() -> u64 {
+    let now = unix_now();
+    LAST_UPDATE_SECS.store(now, Ordering::Relaxed);
+    now
+}
+
+#[cfg(feature = "metrics")]
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Readiness behind `/readyz` and `/healthz`: the latest sync succeeded and
+/// the last port update is no older than the staleness limit.
+#[cfg(feature = "metrics")]
+#[derive(Debug, Default)]
+pub struct HealthState {
+    healthy: AtomicBool,
+    /// 0 disables the staleness check.
+    max_staleness_secs: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+impl HealthState {
+    /// Records whether the latest sync succeeded.
+    pub fn set(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    pub fn set_max_staleness(&self, max_staleness: Option<Duration>) {
+        let secs = max_staleness.map_or(0, |limit| limit.as_secs().max(1));
+        self.max_staleness_secs.store(secs, Ordering::Relaxed);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.is_ready_at(unix_now(), LAST_UPDATE_SECS.load(Ordering::Relaxed))
+    }
+
+    fn is_ready_at(&self, now: u64, last_update: u64) -> bool {
+        let max_staleness = self.max_staleness_secs.load(Ordering::Relaxed);
+        self.healthy.load(Ordering::Relaxed)
+            && (max_staleness == 0 || now.saturating_sub(last_update) <= max_staleness)
+    }
+}
+
+/// Recorder installed by [`install_recorder`], kept for [`write_textfile`].
+#[cfg(feature = "metrics")]
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Buckets for the `*_duration_seconds` histograms, from a fast LAN round
+/// trip up to a mapping request that went through all its retries.
+#[cfg(feature = "metrics")]
+const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
 #[cfg(feature = "metrics")]
 pub fn install_recorder() -> Result<PrometheusHandle> {
-    let handle = PrometheusBuilder::new().install_recorder()?;
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(
+            Matcher::Suffix("_duration_seconds".to_string()),
+            DURATION_BUCKETS,
+        )?
+        .install_recorder()?;
+    let _ = HANDLE.set(handle.clone());
     Ok(handle)
 }
 
+/// Renders the current metrics in the Prometheus text format and replaces
+/// `path` with them, for node_exporter's textfile collector.
+#[cfg(feature = "metrics")]
+pub fn write_textfile(path: &Path) -> Result<()> {
+    let handle = HANDLE
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("metrics recorder is not installed"))?;
+    write_atomic(path, &handle.render())?;
+    Ok(())
+}
+
+/// Serves `/metrics`, `/events` and the probe routes. With no Prometheus
+/// `handle` (standalone health mode) only `/livez`, `/readyz`, `/healthz`
+/// and `/events` exist. `/readyz` follows `health`; `/livez` follows `live_flag`, set once
+/// the sync loop has started.
 #[cfg(feature = "metrics")]
 pub async fn run_server(
+    bind_ip: IpAddr,
     port: u16,
-    handle: PrometheusHandle,
-    health_flag: Arc<AtomicBool>,
+    handle: Option<PrometheusHandle>,
+    health: Arc<HealthState>,
+    live_flag: Arc<AtomicBool>,
+    events: EventLog,
 ) -> Result<()> {
-    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let addr = SocketAddr::new(bind_ip, port);
     let listener = TcpListener::bind(addr).await?;
-    info!("metrics and health server listening on {}", addr);
+    if handle.is_some() {
+        info!("metrics and health server listening on {}", addr);
+    } else {
+        info!("standalone health server listening on {}", addr);
+    }
 
     loop {
         let (stream, _) = match listener.accept().await {
@@ -51,14 +173,18 @@ pub async fn run_server(
         };
 
         let handle_clone = handle.clone();
-        let health_flag_clone = health_flag.clone();
+        let health_clone = health.clone();
+        let live_flag_clone = live_flag.clone();
+        let events_clone = events.clone();
 
         tokio::spawn(async move {
             let io = TokioIo::new(stream);
             let service = service_fn(move |req: Request<hyper::body::Incoming>| {
                 let handle = handle_clone.clone();
-                let health = health_flag_clone.clone();
-                async move { handle_request(req, handle, health).await }
+                let health = health_clone.clone();
+                let live = live_flag_clone.clone();
+                let events = events_clone.clone();
+                async move { handle_request(req, handle, health, live, events).await }
             });
 
             if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
@@ -71,11 +197,13 @@ pub async fn run_server(
 #[cfg(feature = "metrics")]
 async fn handle_request(
     req: Request<hyper::body::Incoming>,
-    handle: PrometheusHandle,
-    health_flag: Arc<AtomicBool>,
+    handle: Option<PrometheusHandle>,
+    health: Arc<HealthState>,
+    live_flag: Arc<AtomicBool>,
+    events: EventLog,
 ) -> Result<Response<Full<Bytes>>, hyper::Error> {
-    match req.uri().path() {
-        "/metrics" => {
+    match (req.uri().path(), handle) {
+        ("/metrics", Some(handle)) => {
             let metrics_text = handle.render();
             Ok(Response::builder()
                 .status(StatusCode::OK)
@@ -83,19 +211,19 @@ async fn handle_request(
                 .body(Full::new(Bytes::from(metrics_text)))
                 .unwrap())
         }
-        "/healthz" => {
-            let is_healthy = health_flag.load(Ordering::Relaxed);
-            if is_healthy {
-                Ok(Response::builder()
-                    .status(StatusCode::OK)
-                    .body(Full::new(Bytes::from("OK")))
-                    .unwrap())
-            } else {
-                Ok(Response::builder()
-                    .status(StatusCode::SERVICE_UNAVAILABLE)
-                    .body(Full::new(Bytes::from("Unhealthy")))
-                    .unwrap())
-            }
+        // `/healthz` predates the split and stays an alias for readiness.
+        ("/healthz" | "/readyz", _) => Ok(probe_response(health.is_ready(), "Unhealthy")),
+        ("/livez", _) => Ok(probe_response(
+            live_flag.load(Ordering::Relaxed),
+            "Starting",
+        )),
+        ("/events", _) => {
+            let body = serde_json::to_string(&events.snapshot()).unwrap_or_else(|_| "[]".into());
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Full::new(Bytes::from(body)))
+                .unwrap())
         }
         _ => Ok(Response::builder()
             .status(StatusCode::NOT_FOUND)
@@ -103,3 +231,47 @@ async fn handle_request(
             .unwrap()),
     }
 }
+
+/// 200 "OK" when `ok`, otherwise 503 with `failure` as the body.
+#[cfg(feature = "metrics")]
+fn probe_response(ok: bool, failure: &'static str) -> Response<Full<Bytes>> {
+    let (status, body) = if ok {
+        (StatusCode::OK, "OK")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, failure)
+    };
+    Response::builder()
+        .status(status)
+        .body(Full::new(Bytes::from(body)))
+        .unwrap()
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::{probe_response, HealthState};
+    use hyper::StatusCode;
+    use std::time::Duration;
+
+    #[test]
+    fn stale_updates_make_health_unready() {
+        let health = HealthState::default();
+        assert!(!health.is_ready_at(1_000, 0));
+        health.set(true);
+        assert!(health.is_ready_at(1_000, 0));
+
+        health.set_max_staleness(Some(Duration::from_secs(600)));
+        assert!(health.is_ready_at(1_000, 400));
+        assert!(!health.is_ready_at(1_000, 399));
+        health.set(false);
+        assert!(!health.is_ready_at(1_000, 1_000));
+    }
+
+    #[test]
+    fn probe_response_reports_unavailable_until_ok() {
+        assert_eq!(probe_response(true, "Starting").status(), StatusCode::OK);
+        assert_eq!(
+            probe_response(false, "Starting").status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
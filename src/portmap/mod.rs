@@ -1,14 +1,22 @@
+#[cfg(feature = "metrics")]
+use crate::metrics_server::metric_name;
 use crate::{
     config::{PortMapConfig, PortProtocol},
-    error::{PortMapError, Result},
+    error::{classify_error, ExitCode, PortMapError, Result, UnsupportedError},
 };
 use anyhow::{anyhow, Context};
 use rand::{rngs::SmallRng, Rng, SeedableRng};
-use std::{net::IpAddr, str::FromStr, time::Duration};
+use std::{
+    future::Future,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
 use tracing::{debug, info, warn};
 
 mod natpmp;
 mod pcp;
+mod upnp;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Protocol {
@@ -22,6 +30,27 @@ pub enum Strategy {
     #[cfg_attr(not(feature = "pcp"), allow(dead_code))]
     Pcp,
     NatPmp,
+    #[cfg_attr(not(feature = "upnp"), allow(dead_code))]
+    Upnp,
+}
+
+/// IP family a forwarded port was mapped for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    #[default]
+    V4,
+    V6,
+}
+
+impl AddressFamily {
+    pub fn of(addr: IpAddr) -> Self {
+        match addr {
+            IpAddr::V4(_) => AddressFamily::V4,
+            IpAddr::V6(v6) if v6.to_ipv4_mapped().is_some() => AddressFamily::V4,
+            IpAddr::V6(_) => AddressFamily::V6,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +58,11 @@ pub struct MapResult {
     pub external_port: u16,
     pub ttl: Option<Duration>,
     pub strategy: Strategy,
+    pub family: AddressFamily,
+    /// Public address the mapping applies to, when the gateway reported it.
+    pub external_ip: Option<IpAddr>,
+    /// Request that produced the mapping, kept so it can be released.
+    pub(crate) request: MapRequest,
 }
 
 #[derive(Debug, Clone)]
@@ -37,12 +71,27 @@ pub(crate) struct MapRequest {
     pub gateway: IpAddr,
     pub internal_port: u16,
     pub external_preference: Option<u16>,
+    /// Mapping lifetime requested from the gateway.
+    pub lease_secs: u64,
+    /// How often the daemon refreshes and verifies the mapping.
     pub refresh_secs: u64,
+    pub max_retries: u32,
+    #[cfg_attr(not(feature = "pcp"), allow(dead_code))]
+    pub client_ip: Option<IpAddr>,
+    /// Reject a granted external port that differs from `external_preference`.
+    pub require_exact_external: bool,
 }
 
-pub async fn map_prefer_pcp_fallback_natpmp(config: &PortMapConfig) -> Result<MapResult> {
-    let request = build_request(config)?;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+
+pub async fn map_prefer_pcp_fallback_natpmp(
+    config: &PortMapConfig,
+    gateways: &[IpAddr],
+) -> Result<MapResult> {
+    with_gateway_failover(build_requests(config, gateways), map_auto).await
+}
 
+async fn map_auto(request: MapRequest) -> Result<MapResult> {
     match try_pcp(&request).await {
         Ok(result) => {
             info!(
@@ -51,6 +100,7 @@ pub async fn map_prefer_pcp_fallback_natpmp(config: &PortMapConfig) -> Result<Ma
             );
             Ok(result)
         }
+        Err(err) if is_port_rejection(&err) => Err(err),
         Err(err) => {
             match err.downcast_ref::<PortMapError>() {
                 Some(PortMapError::PcpNotSupported(_)) => {
@@ -60,24 +110,258 @@ pub async fn map_prefer_pcp_fallback_natpmp(config: &PortMapConfig) -> Result<Ma
                 _ => warn!("PCP mapping error: {err:#}"),
             }
 
-            let result = try_natpmp(&request).await?;
-            info!(
-                "acquired NAT-PMP mapping: internal {} -> external {}",
-                request.internal_port, result.external_port
-            );
-            Ok(result)
+            // NAT-PMP and UPnP are IPv4-only, so PCP is the only route here.
+            if let Err(unsupported) = ensure_natpmp_gateway(request.gateway) {
+                debug!("{unsupported:#}; not falling back from PCP");
+                return Err(err);
+            }
+
+            let natpmp_err = match try_natpmp(&request).await {
+                Ok(result) => {
+                    info!(
+                        "acquired NAT-PMP mapping: internal {} -> external {}",
+                        request.internal_port, result.external_port
+                    );
+                    return Ok(result);
+                }
+                Err(err) => err,
+            };
+
+            match try_upnp(&request).await {
+                Ok(result) => {
+                    warn!("NAT-PMP mapping failed: {natpmp_err:#}");
+                    info!(
+                        "acquired UPnP mapping: internal {} -> external {}",
+                        request.internal_port, result.external_port
+                    );
+                    Ok(result)
+                }
+                Err(err) => match err.downcast_ref::<PortMapError>() {
+                    Some(PortMapError::UpnpNotSupported(msg)) => {
+                        debug!("UPnP fallback unavailable: {msg}");
+                        Err(natpmp_err)
+                    }
+                    _ => {
+                        warn!("NAT-PMP mapping failed: {natpmp_err:#}");
+                        Err(err)
+                    }
+                },
+            }
         }
     }
 }
 
-pub async fn map_with_pcp(config: &PortMapConfig) -> Result<MapResult> {
-    let request = build_request(config)?;
-    try_pcp(&request).await
+pub async fn map_with_pcp(config: &PortMapConfig, gateways: &[IpAddr]) -> Result<MapResult> {
+    with_gateway_failover(build_requests(config, gateways), |request| async move {
+        try_pcp(&request).await
+    })
+    .await
 }
 
-pub async fn map_with_natpmp(config: &PortMapConfig) -> Result<MapResult> {
-    let request = build_request(config)?;
-    try_natpmp(&request).await
+pub async fn map_with_natpmp(config: &PortMapConfig, gateways: &[IpAddr]) -> Result<MapResult> {
+    with_gateway_failover(build_requests(config, gateways), |request| async move {
+        ensure_natpmp_gateway(request.gateway)?;
+        try_natpmp(&request).await
+    })
+    .await
+}
+
+/// Runs `map` for each request (one per configured gateway) in order and
+/// returns the first mapping obtained. A single gateway's error is returned
+/// unchanged; when several fail, the error names each one and keeps their
+/// exit code if they all agree, so gateways that all lack the protocol
+/// still report it as unsupported.
+async fn with_gateway_failover<F, Fut>(requests: Vec<MapRequest>, mut map: F) -> Result<MapResult>
+where
+    F: FnMut(MapRequest) -> Fut,
+    Fut: Future<Output = Result<MapResult>>,
+{
+    let mut failures = Vec::new();
+    for request in requests {
+        let gateway = request.gateway;
+        debug!(
+            "requesting a {}s lease through gateway {gateway} (refreshing every {}s)",
+            request.lease_secs, request.refresh_secs
+        );
+        match map(request).await {
+            Ok(result) => return Ok(result),
+            Err(err) => {
+                debug!("mapping through gateway {gateway} failed: {err:#}");
+                failures.push((gateway, err));
+            }
+        }
+    }
+    if failures.len() == 1 {
+        return Err(failures.remove(0).1);
+    }
+    let summary = failures
+        .iter()
+        .map(|(gateway, err)| format!("{gateway}: {err:#}"))
+        .collect::<Vec<_>>()
+        .join("; ");
+    let code = classify_error(&failures[0].1);
+    if code != ExitCode::Transient && failures.iter().all(|(_, err)| classify_error(err) == code) {
+        let (_, first) = failures.remove(0);
+        return Err(first.context(PortMapError::AllGatewaysFailed(summary).to_string()));
+    }
+    Err(PortMapError::AllGatewaysFailed(summary).into())
+}
+
+/// NAT-PMP is defined for IPv4 only; an IPv6 gateway needs PCP.
+fn ensure_natpmp_gateway(gateway: IpAddr) -> Result<()> {
+    match gateway {
+        IpAddr::V4(_) => Ok(()),
+        IpAddr::V6(addr) => Err(UnsupportedError::new(format!(
+            "NAT-PMP cannot map through IPv6 gateway {addr}; use the pcp or auto strategy"
+        ))
+        .into()),
+    }
+}
+
+/// UPnP finds its gateway through SSDP, so only the first of `gateways` is
+/// recorded and no failover applies.
+pub async fn map_with_upnp(config: &PortMapConfig, gateways: &[IpAddr]) -> Result<MapResult> {
+    let request = build_request(config, gateways[0]);
+    try_upnp(&request).await
+}
+
+/// Returns `true` when any configured gateway answers a NAT-PMP
+/// public-address probe. Used to tell whether the VPN tunnel is up without
+/// requesting a mapping.
+pub async fn gateway_reachable(config: &PortMapConfig) -> bool {
+    external_address(config).await.is_some()
+}
+
+/// How long to wait for a NAT-PMP external-address response.
+const PUBLIC_ADDRESS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Asks the gateways in order for their public IPv4 address using the
+/// NAT-PMP external-address request and returns the first answer. Gateways
+/// that do not speak NAT-PMP, or do not answer in time, yield `None` rather
+/// than failing the cycle.
+pub async fn external_address(config: &PortMapConfig) -> Option<Ipv4Addr> {
+    let gateways = match resolve_gateways(config) {
+        Ok(gateways) => gateways,
+        Err(err) => {
+            debug!("cannot query public address: {err:#}");
+            return None;
+        }
+    };
+    for gateway in gateways {
+        match natpmp::public_address(gateway, PUBLIC_ADDRESS_TIMEOUT).await {
+            Ok(address) => {
+                debug!("gateway {gateway} reachable, public address {address}");
+                return Some(address);
+            }
+            Err(err) => debug!("gateway {gateway} did not report a public address: {err:#}"),
+        }
+    }
+    None
+}
+
+/// Asks an external port checker whether `port` accepts connections from
+/// the internet. `checker` is a URL with a `{port}` placeholder; the service
+/// connects back to the address the request came from and answers `1` when
+/// the port is open and `0` when it is not.
+pub async fn port_reachable(checker: &str, port: u16, timeout: Duration) -> Result<bool> {
+    let url = checker.replace("{port}", &port.to_string());
+    let response = reqwest::Client::builder()
+        .timeout(timeout)
+        .build()?
+        .get(&url)
+        .send()
+        .await
+        .with_context(|| format!("failed to query {url}"))?
+        .error_for_status()?;
+    let body = response.text().await?;
+    match body.trim() {
+        "1" => Ok(true),
+        "0" => Ok(false),
+        other => Err(anyhow!("unexpected answer from {url}: {other:?}")),
+    }
+}
+
+/// The autodiscovered default gateway, kept between daemon cycles so a
+/// route-table flap during discovery does not fail a cycle. Configured
+/// gateways are parsed each time and never cached.
+#[derive(Debug, Default)]
+pub struct GatewayCache {
+    cached: Option<(IpAddr, Instant)>,
+    /// How long a discovered gateway is trusted; `None` keeps it until
+    /// [`GatewayCache::invalidate`].
+    ttl: Option<Duration>,
+}
+
+impl GatewayCache {
+    pub fn new(ttl: Option<Duration>) -> Self {
+        GatewayCache { cached: None, ttl }
+    }
+
+    /// Like [`resolve_gateways`], but autodiscovery is skipped while the
+    /// cached gateway is fresh, and a failed discovery falls back to the
+    /// last gateway found.
+    pub fn resolve(&mut self, config: &PortMapConfig) -> Result<Vec<IpAddr>> {
+        if !config.gateway.is_empty() || !config.autodiscover_gateway {
+            return resolve_gateways(config);
+        }
+        if let Some((gateway, found_at)) = self.cached {
+            if self.ttl.is_none_or(|ttl| found_at.elapsed() < ttl) {
+                return Ok(vec![gateway]);
+            }
+        }
+        match resolve_gateways(config) {
+            Ok(gateways) => {
+                self.cached = Some((gateways[0], Instant::now()));
+                Ok(gateways)
+            }
+            Err(err) => match self.cached {
+                Some((gateway, _)) => {
+                    warn!("{err:#}; reusing last known gateway {gateway}");
+                    Ok(vec![gateway])
+                }
+                None => Err(err),
+            },
+        }
+    }
+
+    /// Forgets the cached gateway so the next cycle discovers it again,
+    /// e.g. after a mapping through it failed.
+    pub fn invalidate(&mut self) {
+        if let Some((gateway, _)) = self.cached.take() {
+            debug!("dropping cached gateway {gateway}");
+        }
+    }
+}
+
+/// Remembers the last public address seen so a change (usually a new VPN
+/// server) can be reported once.
+#[derive(Debug, Default)]
+pub struct PublicIpMonitor {
+    last: Option<Ipv4Addr>,
+}
+
+impl PublicIpMonitor {
+    /// Records `address` and returns the previous one if it differs.
+    /// The first address observed is never reported as a change.
+    pub fn observe(&mut self, address: Ipv4Addr) -> Option<Ipv4Addr> {
+        match self.last.replace(address) {
+            Some(previous) if previous != address => Some(previous),
+            _ => None,
+        }
+    }
+}
+
+/// Deletes a PCP or NAT-PMP mapping by re-requesting it with a zero
+/// lifetime. UPnP leases are left to expire.
+pub async fn release_mapping(mapping: &MapResult) -> Result<()> {
+    match mapping.strategy {
+        Strategy::Pcp => pcp::release(&mapping.request).await,
+        Strategy::NatPmp => natpmp::release(&mapping.request).await,
+        Strategy::Upnp => {
+            debug!("leaving UPnP mapping to expire");
+            Ok(())
+        }
+    }
 }
 
 pub fn protocol_from_config(protocol: PortProtocol) -> Protocol {
@@ -88,10 +372,72 @@ pub fn protocol_from_config(protocol: PortProtocol) -> Protocol {
     }
 }
 
+/// Runs `attempt` up to `1 + max_retries` times with exponential backoff
+/// starting at 500ms. Only transient failures are retried; a refused
+/// request, an unsupported gateway or a rejected external port is returned
+/// immediately.
+async fn with_retries<F, Fut>(label: &str, max_retries: u32, mut attempt: F) -> Result<MapResult>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<MapResult>>,
+{
+    let mut delay = RETRY_BASE_DELAY;
+    let mut retries = 0;
+    loop {
+        let err = match attempt().await {
+            Ok(result) => return Ok(result),
+            Err(err) => err,
+        };
+        if !is_transient(&err) {
+            return Err(err);
+        }
+        if retries >= max_retries {
+            return Err(if retries == 0 {
+                err
+            } else {
+                err.context(format!(
+                    "{label} mapping failed after {} attempts",
+                    retries + 1
+                ))
+            });
+        }
+        retries += 1;
+        debug!(
+            "{label} mapping attempt failed ({err:#}); retry {retries}/{max_retries} in {delay:?}"
+        );
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+    }
+}
+
+/// The gateway granted a port `portmap.require_exact_external` refuses.
+/// Asking again, or through another protocol, would only map more ports
+/// the same way.
+fn is_port_rejection(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<PortMapError>(),
+        Some(PortMapError::ExternalPortMismatch { .. })
+    )
+}
+
+/// Timeouts, socket errors and a busy gateway, which asking again shortly
+/// may get past. The PCP and NAT-PMP clients report refusals separately as
+/// [`PortMapError::Refused`].
+fn is_transient(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<PortMapError>() {
+        Some(PortMapError::Pcp(_) | PortMapError::NatPmp(_) | PortMapError::Upnp(_)) => true,
+        Some(_) => false,
+        None => err.downcast_ref::<std::io::Error>().is_some(),
+    }
+}
+
 async fn try_pcp(request: &MapRequest) -> Result<MapResult> {
     #[cfg(feature = "pcp")]
     {
-        pcp::map(request.clone()).await
+        let started = Instant::now();
+        let result = with_retries("PCP", request.max_retries, || pcp::map(request.clone())).await;
+        record_mapping_duration("pcp", started);
+        result
     }
 
     #[cfg(not(feature = "pcp"))]
@@ -105,21 +451,69 @@ async fn try_pcp(request: &MapRequest) -> Result<MapResult> {
 }
 
 async fn try_natpmp(request: &MapRequest) -> Result<MapResult> {
-    natpmp::map(request.clone()).await
+    let started = Instant::now();
+    let result = with_retries("NAT-PMP", request.max_retries, || {
+        natpmp::map(request.clone())
+    })
+    .await;
+    record_mapping_duration("natpmp", started);
+    result
+}
+
+/// Records how long a mapping attempt took, retries included, in the
+/// `mapping_duration_seconds` histogram.
+fn record_mapping_duration(strategy: &'static str, started: Instant) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(metric_name("mapping_duration_seconds"), "strategy" => strategy)
+        .record(started.elapsed().as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = (strategy, started);
 }
 
-fn build_request(config: &PortMapConfig) -> Result<MapRequest> {
+async fn try_upnp(request: &MapRequest) -> Result<MapResult> {
+    #[cfg(feature = "upnp")]
+    {
+        upnp::map(request.clone()).await
+    }
+
+    #[cfg(not(feature = "upnp"))]
+    {
+        let _ = request;
+        Err(
+            PortMapError::UpnpNotSupported("upnp feature not enabled at compile time".to_string())
+                .into(),
+        )
+    }
+}
+
+/// One request per gateway, sharing the same ports.
+fn build_requests(config: &PortMapConfig, gateways: &[IpAddr]) -> Vec<MapRequest> {
+    let first = build_request(config, gateways[0]);
+    gateways
+        .iter()
+        .map(|&gateway| MapRequest {
+            gateway,
+            ..first.clone()
+        })
+        .collect()
+}
+
+fn build_request(config: &PortMapConfig, gateway: IpAddr) -> MapRequest {
     let protocol = protocol_from_config(config.protocol);
-    let gateway = resolve_gateway(config)?;
     let (internal_port, external_preference) = resolve_ports(config);
 
-    Ok(MapRequest {
+    MapRequest {
         protocol,
         gateway,
         internal_port,
         external_preference,
+        lease_secs: config.lease_secs(),
         refresh_secs: config.refresh_secs,
-    })
+        max_retries: config.max_retries,
+        // Already checked by Config::validate.
+        client_ip: config.client_ip_addr().ok().flatten(),
+        require_exact_external: config.require_exact_external,
+    }
 }
 
 fn resolve_ports(config: &PortMapConfig) -> (u16, Option<u16>) {
@@ -132,17 +526,66 @@ fn resolve_ports(config: &PortMapConfig) -> (u16, Option<u16>) {
     }
 }
 
-fn resolve_gateway(config: &PortMapConfig) -> Result<IpAddr> {
-    if let Some(ref gateway) = config.gateway {
-        if !gateway.trim().is_empty() {
-            return IpAddr::from_str(gateway).context("invalid configured gateway address");
+/// A random extra delay of up to `max_secs` for a refresh cycle.
+pub fn refresh_jitter(max_secs: u64) -> Duration {
+    if max_secs == 0 {
+        return Duration::ZERO;
+    }
+    let mut rng = SmallRng::from_entropy();
+    Duration::from_millis(rng.gen_range(0..=max_secs.saturating_mul(1000)))
+}
+
+/// Rejects a mapped external port outside `portmap.acceptable_range`, such as
+/// a port 1 handed out while the VPN reconnects. The error is transient, so
+/// the cycle backs off and maps again.
+pub fn ensure_acceptable_port(config: &PortMapConfig, port: u16) -> Result<()> {
+    let range = config.acceptable_range;
+    if range.contains(port) {
+        Ok(())
+    } else {
+        Err(PortMapError::UnacceptablePort {
+            port,
+            min: range.min,
+            max: range.max,
+        }
+        .into())
+    }
+}
+
+/// With `portmap.require_exact_external`, rejects a mapping whose external
+/// port is not the one requested rather than applying the gateway's choice.
+/// Without a preference (`internal_port = 0`) any port is accepted. Callers
+/// release the rejected mapping before returning the error.
+pub(crate) fn ensure_exact_external(request: &MapRequest, external_port: u16) -> Result<()> {
+    match request.external_preference {
+        Some(requested) if request.require_exact_external && external_port != requested => {
+            Err(PortMapError::ExternalPortMismatch {
+                requested,
+                granted: external_port,
+            }
+            .into())
         }
+        _ => Ok(()),
+    }
+}
+
+/// Every configured gateway in order, or the autodiscovered default gateway.
+pub fn resolve_gateways(config: &PortMapConfig) -> Result<Vec<IpAddr>> {
+    if !config.gateway.is_empty() {
+        return config
+            .gateway
+            .iter()
+            .map(|gateway| {
+                IpAddr::from_str(gateway)
+                    .with_context(|| format!("invalid configured gateway address '{gateway}'"))
+            })
+            .collect();
     }
 
     if config.autodiscover_gateway {
         let gateway = default_net::get_default_gateway()
             .map_err(|err| anyhow!("failed to autodiscover default gateway: {err}"))?;
-        return Ok(gateway.ip_addr);
+        return Ok(vec![gateway.ip_addr]);
     }
 
     Err(anyhow!(
@@ -150,25 +593,424 @@ fn resolve_gateway(config: &PortMapConfig) -> Result<IpAddr> {
     ))
 }
 
-fn effective_protocol(protocol: Protocol) -> Protocol {
-    match protocol {
-        Protocol::Both => Protocol::Tcp,
-        other => other,
+/// Runs `map_one` once for a single protocol, or for TCP and then UDP when
+/// `Protocol::Both` is requested. qBittorrent listens on one port for both,
+/// so the UDP request asks for the TCP external port and the call fails if
+/// the gateway hands out a different one. Returns the port and the shorter TTL.
+pub(crate) async fn map_each_protocol<F, Fut>(
+    request: &MapRequest,
+    mut map_one: F,
+) -> Result<(u16, Option<Duration>)>
+where
+    F: FnMut(Protocol, Option<u16>) -> Fut,
+    Fut: Future<Output = Result<(u16, Option<Duration>)>>,
+{
+    let Protocol::Both = request.protocol else {
+        return map_one(request.protocol, request.external_preference).await;
+    };
+
+    let (tcp_port, tcp_ttl) = map_one(Protocol::Tcp, request.external_preference).await?;
+    let (udp_port, udp_ttl) = map_one(Protocol::Udp, Some(tcp_port)).await?;
+    if udp_port != tcp_port {
+        return Err(PortMapError::ProtocolPortMismatch {
+            tcp: tcp_port,
+            udp: udp_port,
+        }
+        .into());
     }
+    let ttl = match (tcp_ttl, udp_ttl) {
+        (Some(tcp), Some(udp)) => Some(tcp.min(udp)),
+        (tcp, udp) => tcp.or(udp),
+    };
+    Ok((tcp_port, ttl))
 }
 
-pub(crate) fn mapping_protocol(protocol: Protocol) -> Protocol {
-    effective_protocol(protocol)
+/// Protocols a request maps, in the order they are requested.
+pub(crate) fn protocols(protocol: Protocol) -> &'static [Protocol] {
+    match protocol {
+        Protocol::Tcp => &[Protocol::Tcp],
+        Protocol::Udp => &[Protocol::Udp],
+        Protocol::Both => &[Protocol::Tcp, Protocol::Udp],
+    }
 }
 
 pub(crate) fn build_result(
+    request: &MapRequest,
     external_port: u16,
     ttl: Option<Duration>,
     strategy: Strategy,
+    family: AddressFamily,
+    external_ip: Option<IpAddr>,
 ) -> MapResult {
     MapResult {
         external_port,
         ttl,
         strategy,
+        family,
+        external_ip,
+        request: request.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        build_request, build_result, ensure_acceptable_port, ensure_exact_external,
+        ensure_natpmp_gateway, map_each_protocol, port_reachable, protocols, refresh_jitter,
+        with_gateway_failover, with_retries, AddressFamily, GatewayCache, MapRequest, Protocol,
+        PublicIpMonitor, Strategy,
+    };
+    use crate::config::PortMapConfig;
+    use crate::error::{classify_error, ExitCode, PortMapError};
+    use crate::test_support::serve;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    fn request(protocol: Protocol) -> MapRequest {
+        MapRequest {
+            protocol,
+            gateway: "10.2.0.1".parse().unwrap(),
+            internal_port: 51820,
+            external_preference: None,
+            lease_secs: 60,
+            refresh_secs: 60,
+            max_retries: 0,
+            client_ip: None,
+            require_exact_external: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn both_maps_tcp_then_udp_on_the_same_port() {
+        let calls = std::sync::Mutex::new(Vec::new());
+        let result = map_each_protocol(&request(Protocol::Both), |protocol, external| {
+            calls
+                .lock()
+                .unwrap()
+                .push((format!("{protocol:?}"), external));
+            let ttl = match protocol {
+                Protocol::Udp => 30,
+                _ => 60,
+            };
+            async move { Ok((external.unwrap_or(40000), Some(Duration::from_secs(ttl)))) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, (40000, Some(Duration::from_secs(30))));
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![("Tcp".to_string(), None), ("Udp".to_string(), Some(40000))]
+        );
+    }
+
+    #[tokio::test]
+    async fn both_rejects_diverging_ports() {
+        let result = map_each_protocol(&request(Protocol::Both), |protocol, _| async move {
+            match protocol {
+                Protocol::Udp => Ok((40001, None)),
+                _ => Ok((40000, None)),
+            }
+        })
+        .await;
+        let err = result.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PortMapError>(),
+            Some(PortMapError::ProtocolPortMismatch {
+                tcp: 40000,
+                udp: 40001
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn single_protocol_maps_once() {
+        let calls = AtomicU32::new(0);
+        let result = map_each_protocol(&request(Protocol::Udp), |_, _| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            async { Ok((40000, None)) }
+        })
+        .await
+        .unwrap();
+        assert_eq!(result, (40000, None));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_until_success() {
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("test", 1, || async {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(PortMapError::NatPmp("timeout".into()).into())
+            } else {
+                Ok(build_result(
+                    &request(Protocol::Tcp),
+                    51820,
+                    None,
+                    Strategy::NatPmp,
+                    AddressFamily::V4,
+                    Some("203.0.113.7".parse().unwrap()),
+                ))
+            }
+        })
+        .await;
+        let result = result.unwrap();
+        assert_eq!(result.external_port, 51820);
+        assert_eq!(result.external_ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn refresh_jitter_stays_within_bounds() {
+        assert_eq!(refresh_jitter(0), Duration::ZERO);
+        for _ in 0..100 {
+            assert!(refresh_jitter(5) <= Duration::from_secs(5));
+        }
+    }
+
+    fn via_gateways(gateways: &[&str]) -> Vec<MapRequest> {
+        gateways
+            .iter()
+            .map(|gateway| MapRequest {
+                gateway: gateway.parse().unwrap(),
+                ..request(Protocol::Tcp)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn failover_uses_the_first_gateway_that_maps() {
+        let requests = via_gateways(&["10.2.0.1", "10.3.0.1", "10.4.0.1"]);
+        let result = with_gateway_failover(requests, |request| async move {
+            if request.gateway.to_string() == "10.2.0.1" {
+                return Err(PortMapError::NatPmp("timeout".into()).into());
+            }
+            Ok(build_result(
+                &request,
+                51820,
+                None,
+                Strategy::NatPmp,
+                AddressFamily::V4,
+                None,
+            ))
+        })
+        .await
+        .unwrap();
+        assert_eq!(result.request.gateway.to_string(), "10.3.0.1");
+    }
+
+    #[tokio::test]
+    async fn failover_names_every_failed_gateway() {
+        let requests = via_gateways(&["10.2.0.1", "10.3.0.1"]);
+        let err = with_gateway_failover(requests, |_| async {
+            Err(PortMapError::NatPmp("timeout".into()).into())
+        })
+        .await
+        .unwrap_err();
+        let message = err.to_string();
+        assert!(matches!(
+            err.downcast_ref::<PortMapError>(),
+            Some(PortMapError::AllGatewaysFailed(_))
+        ));
+        assert!(message.contains("10.2.0.1: nat-pmp mapping failed: timeout"));
+        assert!(message.contains("10.3.0.1: nat-pmp mapping failed: timeout"));
+
+        // A single gateway keeps its own error and exit code.
+        let err = with_gateway_failover(via_gateways(&["10.2.0.1"]), |_| async {
+            Err(PortMapError::PcpNotSupported("no".into()).into())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(classify_error(&err), ExitCode::Unsupported);
+
+        // So do several gateways that all fail the same way.
+        let err = with_gateway_failover(via_gateways(&["10.2.0.1", "2001:db8::1"]), |_| async {
+            Err(PortMapError::PcpNotSupported("no".into()).into())
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(classify_error(&err), ExitCode::Unsupported);
+        assert!(err
+            .to_string()
+            .contains("2001:db8::1: pcp not supported: no"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_errors_are_not_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("test", 3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(PortMapError::PcpNotSupported("no".into()).into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_external_ports_are_not_retried() {
+        let attempts = AtomicU32::new(0);
+        let result = with_retries("test", 3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(PortMapError::ExternalPortMismatch {
+                requested: 51820,
+                granted: 40000,
+            }
+            .into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn refused_requests_are_not_retried() {
+        let refusals = [
+            PortMapError::Refused("pcp: not authorized".to_string()),
+            PortMapError::UnacceptablePort {
+                port: 1,
+                min: 1024,
+                max: 65535,
+            },
+            PortMapError::ProtocolPortMismatch {
+                tcp: 51820,
+                udp: 51821,
+            },
+        ];
+        for refusal in refusals {
+            let refusal = Mutex::new(Some(refusal));
+            let attempts = AtomicU32::new(0);
+            let result = with_retries("test", 3, || async {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                Err(refusal.lock().unwrap().take().unwrap().into())
+            })
+            .await;
+            assert!(result.is_err());
+            assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        }
+    }
+
+    #[test]
+    fn natpmp_classifies_ipv6_gateways_as_unsupported() {
+        assert!(ensure_natpmp_gateway("10.2.0.1".parse().unwrap()).is_ok());
+        let err = ensure_natpmp_gateway("2001:db8::1".parse().unwrap()).unwrap_err();
+        assert_eq!(classify_error(&err), ExitCode::Unsupported);
+    }
+
+    #[test]
+    fn both_releases_tcp_and_udp() {
+        assert!(matches!(
+            protocols(Protocol::Both),
+            [Protocol::Tcp, Protocol::Udp]
+        ));
+        assert!(matches!(protocols(Protocol::Udp), [Protocol::Udp]));
+    }
+
+    #[test]
+    fn public_ip_monitor_reports_changes_only() {
+        let mut monitor = PublicIpMonitor::default();
+        let first = "198.51.100.7".parse().unwrap();
+        let second = "203.0.113.9".parse().unwrap();
+        assert_eq!(monitor.observe(first), None);
+        assert_eq!(monitor.observe(first), None);
+        assert_eq!(monitor.observe(second), Some(first));
+        assert_eq!(monitor.observe(second), None);
+    }
+
+    #[test]
+    fn gateway_cache_reuses_discovery_until_invalidated() {
+        let autodiscover: PortMapConfig = toml::from_str("").unwrap();
+        let cached = "10.2.0.1".parse().unwrap();
+        let mut cache = GatewayCache::new(None);
+        cache.cached = Some((cached, std::time::Instant::now()));
+        assert_eq!(cache.resolve(&autodiscover).unwrap(), vec![cached]);
+
+        // Configured gateways never go through the cache.
+        let configured = PortMapConfig {
+            gateway: vec!["10.96.0.1".to_string()],
+            ..autodiscover.clone()
+        };
+        assert_eq!(
+            cache.resolve(&configured).unwrap(),
+            vec!["10.96.0.1".parse::<std::net::IpAddr>().unwrap()]
+        );
+
+        cache.invalidate();
+        assert!(cache.cached.is_none());
+    }
+
+    #[test]
+    fn lease_defaults_to_the_refresh_interval() {
+        let gateway = "10.2.0.1".parse().unwrap();
+        let default: PortMapConfig = toml::from_str("refresh_secs = 120").unwrap();
+        let request = build_request(&default, gateway);
+        assert_eq!((request.lease_secs, request.refresh_secs), (120, 120));
+
+        let long: PortMapConfig = toml::from_str("refresh_secs = 120\nlease_secs = 7200").unwrap();
+        let request = build_request(&long, gateway);
+        assert_eq!((request.lease_secs, request.refresh_secs), (7200, 120));
+    }
+
+    #[test]
+    fn mapped_ports_outside_the_acceptable_range_are_transient_failures() {
+        let any: PortMapConfig = toml::from_str("").unwrap();
+        assert!(ensure_acceptable_port(&any, 1).is_ok());
+        assert!(ensure_acceptable_port(&any, 65535).is_ok());
+
+        let high: PortMapConfig = toml::from_str("acceptable_range = { min = 1024 }").unwrap();
+        assert!(ensure_acceptable_port(&high, 1024).is_ok());
+        let err = ensure_acceptable_port(&high, 1).unwrap_err();
+        assert!(err.to_string().contains("1024-65535"));
+        assert_eq!(classify_error(&err), ExitCode::Transient);
+    }
+
+    #[test]
+    fn exact_external_rejects_a_different_granted_port() {
+        let lenient = MapRequest {
+            external_preference: Some(51820),
+            ..request(Protocol::Tcp)
+        };
+        assert!(ensure_exact_external(&lenient, 40000).is_ok());
+
+        let exact = MapRequest {
+            require_exact_external: true,
+            ..lenient
+        };
+        assert!(ensure_exact_external(&exact, 51820).is_ok());
+        let err = ensure_exact_external(&exact, 40000).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PortMapError>(),
+            Some(PortMapError::ExternalPortMismatch {
+                requested: 51820,
+                granted: 40000
+            })
+        ));
+        assert_eq!(classify_error(&err), ExitCode::Transient);
+
+        let unpinned = MapRequest {
+            require_exact_external: true,
+            ..request(Protocol::Tcp)
+        };
+        assert!(ensure_exact_external(&unpinned, 40000).is_ok());
+    }
+
+    #[tokio::test]
+    async fn port_reachable_reads_the_checker_answer() {
+        let (addr, server) = serve(vec![
+            ("200 OK", &[], "1\n"),
+            ("200 OK", &[], "0\n"),
+            ("200 OK", &[], "<html>"),
+        ])
+        .await;
+        let checker = format!("http://{addr}/{{port}}");
+
+        let timeout = Duration::from_secs(5);
+        assert!(port_reachable(&checker, 51820, timeout).await.unwrap());
+        assert!(!port_reachable(&checker, 51820, timeout).await.unwrap());
+        assert!(port_reachable(&checker, 51820, timeout).await.is_err());
+        let requests = server.await.unwrap();
+        assert!(requests
+            .iter()
+            .all(|request| request.starts_with("GET /51820 ")));
     }
 }
@@ -1,13 +1,68 @@
-use super::{build_result, mapping_protocol, MapRequest, MapResult, Protocol, Strategy};
+use super::{
+    build_result, ensure_exact_external, map_each_protocol, protocols, AddressFamily, MapRequest,
+    MapResult, Protocol, Strategy, PUBLIC_ADDRESS_TIMEOUT,
+};
 use crate::error::{PortMapError, Result};
-use std::{net::IpAddr, thread, time::Duration};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    thread,
+    time::{Duration, Instant},
+};
 use tokio::task;
+use tracing::debug;
 
 pub async fn map(request: MapRequest) -> Result<MapResult> {
-    let protocol = mapping_protocol(request.protocol);
+    let (external_port, ttl) = map_each_protocol(&request, |protocol, external| {
+        map_protocol(&request, protocol, external)
+    })
+    .await?;
+    if let Err(err) = ensure_exact_external(&request, external_port) {
+        if let Err(release_err) = release(&request).await {
+            debug!("failed to release the rejected NAT-PMP mapping: {release_err:#}");
+        }
+        return Err(err);
+    }
+    // Mapping responses carry no address, so ask for it separately.
+    let external_ip = match public_address(request.gateway, PUBLIC_ADDRESS_TIMEOUT).await {
+        Ok(address) => Some(IpAddr::V4(address)),
+        Err(err) => {
+            debug!("gateway did not report a public address: {err:#}");
+            None
+        }
+    };
+
+    Ok(build_result(
+        &request,
+        external_port,
+        ttl,
+        Strategy::NatPmp,
+        AddressFamily::V4,
+        external_ip,
+    ))
+}
+
+/// Deletes the mapping for `request` by asking for it again with a zero
+/// lifetime and no suggested external port (RFC 6886, section 3.4).
+pub async fn release(request: &MapRequest) -> Result<()> {
+    let release = MapRequest {
+        external_preference: None,
+        lease_secs: 0,
+        ..request.clone()
+    };
+    for &protocol in protocols(request.protocol) {
+        map_protocol(&release, protocol, None).await?;
+    }
+    Ok(())
+}
+
+async fn map_protocol(
+    request: &MapRequest,
+    protocol: Protocol,
+    external: Option<u16>,
+) -> Result<(u16, Option<Duration>)> {
     let internal_port = request.internal_port;
-    let external = request.external_preference.unwrap_or(0);
-    let lifetime = request.refresh_secs as u32;
+    let external = external.unwrap_or(0);
+    let lifetime = u32::try_from(request.lease_secs).unwrap_or(u32::MAX);
     let gateway = request.gateway;
 
     let operation = task::spawn_blocking(
@@ -24,8 +79,8 @@ pub async fn map(request: MapRequest) -> Result<MapResult> {
             let mut client = natpmp::Natpmp::new_with(gateway_v4)
                 .map_err(|err| PortMapError::NatPmp(err.to_string()))?;
             let nat_protocol = match protocol {
-                Protocol::Tcp | Protocol::Both => natpmp::Protocol::TCP,
                 Protocol::Udp => natpmp::Protocol::UDP,
+                Protocol::Tcp | Protocol::Both => natpmp::Protocol::TCP,
             };
             let requested_lifetime = if lifetime == 0 { 0 } else { lifetime };
             client
@@ -48,6 +103,11 @@ pub async fn map(request: MapRequest) -> Result<MapResult> {
                         thread::sleep(Duration::from_millis(250));
                         continue;
                     }
+                    Err(
+                        err @ (natpmp::Error::NATPMP_ERR_NOTAUTHORIZED
+                        | natpmp::Error::NATPMP_ERR_INVALIDARGS
+                        | natpmp::Error::NATPMP_ERR_UNSUPPORTEDOPCODE),
+                    ) => return Err(PortMapError::Refused(format!("nat-pmp: {err}"))),
                     Err(err) => return Err(PortMapError::NatPmp(err.to_string())),
                 }
             }
@@ -56,6 +116,46 @@ pub async fn map(request: MapRequest) -> Result<MapResult> {
 
     let (external_port, ttl) = operation.await??;
     let ttl = if ttl.is_zero() { None } else { Some(ttl) };
+    Ok((external_port, ttl))
+}
+
+/// Asks the gateway for its public address, giving up after `timeout`.
+/// This does not create or refresh any mapping.
+pub async fn public_address(gateway: IpAddr, timeout: Duration) -> Result<Ipv4Addr> {
+    let operation = task::spawn_blocking(move || -> std::result::Result<Ipv4Addr, PortMapError> {
+        let gateway_v4 = match gateway {
+            IpAddr::V4(addr) => addr,
+            IpAddr::V6(_) => {
+                return Err(PortMapError::NatPmp(
+                    "NAT-PMP requires an IPv4 gateway address".to_string(),
+                ));
+            }
+        };
+
+        let mut client = natpmp::Natpmp::new_with(gateway_v4)
+            .map_err(|err| PortMapError::NatPmp(err.to_string()))?;
+        client
+            .send_public_address_request()
+            .map_err(|err| PortMapError::NatPmp(err.to_string()))?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            match client.read_response_or_retry() {
+                Ok(natpmp::Response::Gateway(resp)) => return Ok(*resp.public_address()),
+                Ok(_) => continue,
+                Err(natpmp::Error::NATPMP_TRYAGAIN) if Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(250));
+                    continue;
+                }
+                Err(natpmp::Error::NATPMP_TRYAGAIN) => {
+                    return Err(PortMapError::NatPmp(
+                        "timed out waiting for gateway response".to_string(),
+                    ))
+                }
+                Err(err) => return Err(PortMapError::NatPmp(err.to_string())),
+            }
+        }
+    });
 
-    Ok(build_result(external_port, ttl, Strategy::NatPmp))
+    Ok(operation.await??)
 }
@@ -3,53 +3,140 @@ use crate::error::{PortMapError, Result};
 
 #[cfg(feature = "pcp")]
 use {
-    super::{build_result, mapping_protocol, Protocol, Strategy},
+    super::{
+        build_result, ensure_exact_external, map_each_protocol, protocols, AddressFamily, Protocol,
+        Strategy,
+    },
+    crate::config::ensure_same_family,
     anyhow::anyhow,
-    crab_nat::{pcp, InternetProtocol, PortMappingOptions},
+    crab_nat::{pcp, InternetProtocol, PortMapping, PortMappingOptions, PortMappingType},
     std::{
         net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
         num::NonZeroU16,
+        sync::{Mutex, OnceLock},
         time::Duration,
     },
     tokio::net::UdpSocket,
+    tracing::debug,
 };
 
+/// PCP only lets the client that created a mapping delete it, identified by
+/// its nonce, so every request in this process shares one.
+#[cfg(feature = "pcp")]
+fn session_nonce() -> pcp::Nonce {
+    static NONCE: OnceLock<pcp::Nonce> = OnceLock::new();
+    *NONCE.get_or_init(rand::random)
+}
+
 #[cfg(feature = "pcp")]
 pub async fn map(request: MapRequest) -> Result<MapResult> {
-    let protocol = mapping_protocol(request.protocol);
     let internal_port = NonZeroU16::new(request.internal_port)
         .ok_or_else(|| anyhow!("internal port must be non-zero"))?;
-    let client_ip = discover_client_ip(request.gateway).await?;
-    let crab_protocol = to_crab_protocol(protocol);
+    let client_ip = resolve_client_ip(&request).await?;
+
+    let external_ip = Mutex::new(None);
+    let (external_port, ttl) = map_each_protocol(&request, |protocol, external| {
+        let (request, external_ip) = (&request, &external_ip);
+        async move {
+            let mapping =
+                map_protocol(request, client_ip, internal_port, protocol, external).await?;
+            *external_ip.lock().unwrap() = mapping_external_ip(&mapping);
+            Ok((
+                mapping.external_port().get(),
+                to_duration(mapping.lifetime()),
+            ))
+        }
+    })
+    .await?;
+    if let Err(err) = ensure_exact_external(&request, external_port) {
+        if let Err(release_err) = release(&request).await {
+            debug!("failed to release the rejected PCP mapping: {release_err:#}");
+        }
+        return Err(err);
+    }
+
+    Ok(build_result(
+        &request,
+        external_port,
+        ttl,
+        Strategy::Pcp,
+        AddressFamily::of(request.gateway),
+        external_ip.into_inner().unwrap(),
+    ))
+}
 
+#[cfg(feature = "pcp")]
+async fn map_protocol(
+    request: &MapRequest,
+    client_ip: IpAddr,
+    internal_port: NonZeroU16,
+    protocol: Protocol,
+    external: Option<u16>,
+) -> Result<PortMapping> {
+    let crab_protocol = to_crab_protocol(protocol);
     let options = PortMappingOptions {
-        external_port: request.external_preference.and_then(NonZeroU16::new),
-        lifetime_seconds: Some(request.refresh_secs as u32),
+        external_port: external.and_then(NonZeroU16::new),
+        lifetime_seconds: Some(u32::try_from(request.lease_secs).unwrap_or(u32::MAX)),
         timeout_config: None,
     };
 
     match pcp::port_mapping(
         pcp::BaseMapRequest::new(request.gateway, client_ip, crab_protocol, internal_port),
-        None,
+        Some(session_nonce()),
         None,
         options,
     )
     .await
     {
-        Ok(mapping) => {
-            let ttl = to_duration(mapping.lifetime());
-            Ok(build_result(
-                mapping.external_port().get(),
-                ttl,
-                Strategy::Pcp,
-            ))
-        }
+        Ok(mapping) => Ok(mapping),
         Err(pcp::Failure::UnsupportedVersion(_)) => Err(PortMapError::PcpNotSupported(
             "gateway indicates PCP is unsupported".to_string(),
         )
         .into()),
-        Err(err) => Err(PortMapError::Pcp(err.to_string()).into()),
+        Err(err) if is_retryable(&err) => Err(PortMapError::Pcp(err.to_string()).into()),
+        Err(err) => Err(PortMapError::Refused(format!("pcp: {err}")).into()),
+    }
+}
+
+/// Failures that describe a temporary condition on the gateway, the network
+/// or the local socket rather than a refusal of the request itself.
+#[cfg(feature = "pcp")]
+fn is_retryable(failure: &pcp::Failure) -> bool {
+    matches!(
+        failure,
+        pcp::Failure::Socket(_)
+            | pcp::Failure::Timeout
+            | pcp::Failure::NetworkFailure(_)
+            | pcp::Failure::NoResources(_)
+    )
+}
+
+/// Deletes the mapping for `request` with a zero-lifetime PCP request.
+#[cfg(feature = "pcp")]
+pub async fn release(request: &MapRequest) -> Result<()> {
+    let internal_port = NonZeroU16::new(request.internal_port)
+        .ok_or_else(|| anyhow!("internal port must be non-zero"))?;
+    let client_ip = resolve_client_ip(request).await?;
+    for &protocol in protocols(request.protocol) {
+        pcp::try_drop_mapping(
+            request.gateway,
+            client_ip,
+            session_nonce(),
+            pcp::DropMappingRange::Single {
+                protocol: to_crab_protocol(protocol),
+                internal_port,
+            },
+            None,
+        )
+        .await
+        .map_err(|err| PortMapError::Pcp(err.to_string()))?;
     }
+    Ok(())
+}
+
+#[cfg(not(feature = "pcp"))]
+pub async fn release(_request: &MapRequest) -> Result<()> {
+    Err(PortMapError::PcpNotSupported("pcp feature not enabled at compile time".to_string()).into())
 }
 
 #[cfg(not(feature = "pcp"))]
@@ -58,6 +145,18 @@ pub async fn map(_request: MapRequest) -> Result<MapResult> {
     Err(PortMapError::PcpNotSupported("pcp feature not enabled at compile time".to_string()).into())
 }
 
+/// The configured client address, or the one the route to the gateway uses.
+#[cfg(feature = "pcp")]
+async fn resolve_client_ip(request: &MapRequest) -> Result<IpAddr> {
+    match request.client_ip {
+        Some(client_ip) => {
+            ensure_same_family(client_ip, request.gateway)?;
+            Ok(client_ip)
+        }
+        None => discover_client_ip(request.gateway).await,
+    }
+}
+
 #[cfg(feature = "pcp")]
 async fn discover_client_ip(gateway: IpAddr) -> Result<IpAddr> {
     let bind_addr = match gateway {
@@ -77,6 +176,18 @@ fn to_crab_protocol(protocol: Protocol) -> InternetProtocol {
     }
 }
 
+/// External address from the PCP response; an all-zero address means the
+/// gateway did not fill it in.
+#[cfg(feature = "pcp")]
+fn mapping_external_ip(mapping: &PortMapping) -> Option<IpAddr> {
+    match mapping.mapping_type() {
+        PortMappingType::Pcp { external_ip, .. } if !external_ip.is_unspecified() => {
+            Some(external_ip)
+        }
+        _ => None,
+    }
+}
+
 #[cfg(feature = "pcp")]
 fn to_duration(ttl_secs: u32) -> Option<Duration> {
     if ttl_secs == 0 {
@@ -85,3 +196,21 @@ fn to_duration(ttl_secs: u32) -> Option<Duration> {
         Some(Duration::from_secs(ttl_secs as u64))
     }
 }
+
+#[cfg(all(test, feature = "pcp"))]
+mod tests {
+    use super::is_retryable;
+    use crab_nat::pcp::Failure;
+
+    #[test]
+    fn only_temporary_failures_are_retryable() {
+        assert!(is_retryable(&Failure::NoResources(30)));
+        assert!(is_retryable(&Failure::NetworkFailure(30)));
+        assert!(is_retryable(&Failure::Socket(std::io::Error::other(
+            "unreachable"
+        ))));
+        assert!(is_retryable(&Failure::Timeout));
+        assert!(!is_retryable(&Failure::NotAuthorized(0)));
+        assert!(!is_retryable(&Failure::MalformedRequest));
+    }
+}
@@ -0,0 +1,133 @@
+#[cfg(feature = "upnp")]
+use {
+    super::{
+        build_result, map_each_protocol, AddressFamily, MapRequest, MapResult, Protocol, Strategy,
+    },
+    crate::error::{PortMapError, Result},
+    igd_next::{
+        aio::{
+            tokio::{search_gateway, Tokio},
+            Gateway,
+        },
+        AddAnyPortError, AddPortError, PortMappingProtocol, SearchError, SearchOptions,
+    },
+    std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+        time::Duration,
+    },
+    tokio::net::UdpSocket,
+    tracing::debug,
+};
+
+#[cfg(feature = "upnp")]
+const MAPPING_DESCRIPTION: &str = "qb-port-sync";
+
+/// Requests a mapping from the first UPnP IGD that answers SSDP discovery.
+///
+/// The control URL comes from the SSDP response, so the configured gateway
+/// address is not consulted here.
+#[cfg(feature = "upnp")]
+pub async fn map(request: MapRequest) -> Result<MapResult> {
+    let gateway = search_gateway(SearchOptions::default())
+        .await
+        .map_err(|err| match err {
+            // No IGD answered: the network has no UPnP gateway.
+            SearchError::NoResponseWithinTimeout => PortMapError::UpnpNotSupported(err.to_string()),
+            _ => PortMapError::Upnp(err.to_string()),
+        })?;
+    let local_ip = discover_local_ip(gateway.addr).await?;
+    let local_addr = SocketAddr::new(local_ip, request.internal_port);
+    let lease = u32::try_from(request.lease_secs).unwrap_or(u32::MAX);
+
+    let (external_port, ttl) = map_each_protocol(&request, |protocol, external| {
+        map_protocol(&gateway, protocol, external, local_addr, lease)
+    })
+    .await?;
+    Ok(build_result(
+        &request,
+        external_port,
+        ttl,
+        Strategy::Upnp,
+        AddressFamily::V4,
+        None,
+    ))
+}
+
+/// Adds one mapping for `protocol`, on `external` when given. A gateway
+/// that only grants permanent leases gets the request again with lease 0,
+/// and the mapping is reported without a TTL.
+#[cfg(feature = "upnp")]
+async fn map_protocol(
+    gateway: &Gateway<Tokio>,
+    protocol: Protocol,
+    external: Option<u16>,
+    local_addr: SocketAddr,
+    lease: u32,
+) -> Result<(u16, Option<Duration>)> {
+    let protocol = to_igd_protocol(protocol);
+    if let Some(port) = add_mapping(gateway, protocol, external, local_addr, lease).await? {
+        let ttl = (lease != 0).then(|| Duration::from_secs(u64::from(lease)));
+        return Ok((port, ttl));
+    }
+    if lease != 0 {
+        debug!("UPnP gateway only grants permanent leases; retrying with lease 0");
+        if let Some(port) = add_mapping(gateway, protocol, external, local_addr, 0).await? {
+            return Ok((port, None));
+        }
+    }
+    Err(PortMapError::Upnp("gateway rejected a permanent lease".to_string()).into())
+}
+
+/// Sends one `AddPortMapping`/`AddAnyPortMapping` request. `None` means the
+/// gateway only supports permanent leases (UPnP error 725).
+#[cfg(feature = "upnp")]
+async fn add_mapping(
+    gateway: &Gateway<Tokio>,
+    protocol: PortMappingProtocol,
+    external: Option<u16>,
+    local_addr: SocketAddr,
+    lease: u32,
+) -> Result<Option<u16>> {
+    match external {
+        Some(port) => {
+            match gateway
+                .add_port(protocol, port, local_addr, lease, MAPPING_DESCRIPTION)
+                .await
+            {
+                Ok(()) => Ok(Some(port)),
+                Err(AddPortError::OnlyPermanentLeasesSupported) => Ok(None),
+                Err(err) => Err(PortMapError::Upnp(err.to_string()).into()),
+            }
+        }
+        None => {
+            match gateway
+                .add_any_port(protocol, local_addr, lease, MAPPING_DESCRIPTION)
+                .await
+            {
+                Ok(port) => Ok(Some(port)),
+                Err(AddAnyPortError::OnlyPermanentLeasesSupported) => Ok(None),
+                Err(err) => Err(PortMapError::Upnp(err.to_string()).into()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "upnp")]
+async fn discover_local_ip(gateway: SocketAddr) -> Result<IpAddr> {
+    let bind_addr = match gateway.ip() {
+        IpAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        IpAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(gateway).await?;
+    Ok(socket.local_addr()?.ip())
+}
+
+#[cfg(feature = "upnp")]
+fn to_igd_protocol(protocol: Protocol) -> PortMappingProtocol {
+    match protocol {
+        // `map_each_protocol` splits `Both` into TCP and UDP.
+        Protocol::Tcp | Protocol::Both => PortMappingProtocol::TCP,
+        Protocol::Udp => PortMappingProtocol::UDP,
+    }
+}
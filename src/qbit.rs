@@ -1,23 +1,107 @@
-use crate::error::{QbitError, Result};
-use reqwest::{header, Client, Url};
+use crate::config::{HttpVersion, InterfacePattern};
+use crate::error::{ConfigError, QbitError, Result};
+use crate::events::{EventKind, EventLog};
+#[cfg(feature = "metrics")]
+use crate::metrics_server::metric_name;
+use reqwest::{header, Certificate, Client, StatusCode, Url};
 use serde::Deserialize;
 use serde_json::{json, Map, Value};
 use std::convert::TryFrom;
-use std::time::Duration;
-use tracing::{debug, info, warn};
+use std::future::Future;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
 #[derive(Clone)]
 pub struct QbitClient {
     client: Client,
     base_url: Url,
+    credentials: Option<Credentials>,
+    retry_interface_id: bool,
+    parked: Arc<AtomicBool>,
+    dry_run: bool,
+    events: EventLog,
+    extra_port_keys: Vec<String>,
+    login_retries: u32,
+    login_retry_delay: Duration,
+    enforce_port_settings: bool,
+    /// Port-changing preferences forced off with each update.
+    managed_port_settings: Vec<&'static str>,
+    only_if_interface_present: bool,
+    ban_cooldown: Duration,
+    banned_until: Arc<Mutex<Option<Instant>>>,
+}
+
+/// Interface the startup leak protection parks qBittorrent on when no bind
+/// interface is configured.
+#[cfg(target_os = "macos")]
+pub const LOOPBACK_INTERFACE: &str = "lo0";
+#[cfg(not(target_os = "macos"))]
+pub const LOOPBACK_INTERFACE: &str = "lo";
+
+/// Upper bound for the delay between login attempts.
+const MAX_LOGIN_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Part of the 403 body qBittorrent sends to a banned address ("Your IP
+/// address has been banned after too many failed authentication attempts.").
+const BAN_MESSAGE: &str = "has been banned";
+
+#[derive(Clone)]
+struct Credentials {
+    username: String,
+    password: String,
 }
 
 #[derive(Debug)]
 pub struct PortUpdateResult {
     pub detected_port: u16,
+    /// Listen port read just before the update, if the client read one.
+    pub previous_port: Option<u16>,
     pub verified: bool,
     pub random_port: Option<bool>,
     pub upnp: Option<bool>,
+    pub interface_id_missing: bool,
+    /// The update was skipped because `only_if_interface_present` is set and
+    /// the bind interface is not in qBittorrent's interface list;
+    /// `detected_port` is then the port left in place.
+    pub interface_absent: bool,
+    /// `current_interface_address` when qBittorrent is restricted to one
+    /// address (`0.0.0.0` and `::` mean all IPv4 / all IPv6 addresses).
+    pub listen_address: Option<IpAddr>,
+    /// Whether the external checker could connect to the port; `None`
+    /// unless `verify_reachable` is set and the checker answered.
+    pub reachable: Option<bool>,
+}
+
+/// Port-related preferences as qBittorrent currently reports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    pub listen_port: u16,
+    pub random_port: Option<bool>,
+    pub upnp: Option<bool>,
+}
+
+/// Binding applied by [`QbitClient::apply_interface_binding`].
+#[derive(Debug)]
+pub struct InterfaceBinding {
+    pub interface: String,
+    pub interface_id: Option<String>,
+    /// Listen port read before the update and left unchanged.
+    pub listen_port: u16,
+    /// Whether qBittorrent reported the new interface after the update.
+    pub verified: bool,
+}
+
+impl InterfaceBinding {
+    pub fn describe(&self) -> String {
+        match self.interface_id.as_deref() {
+            Some(id) if id != self.interface => format!("{} (id {})", self.interface, id),
+            _ => self.interface.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,40 +114,203 @@ struct NetworkInterfaceItem {
 }
 
 impl QbitClient {
-    pub fn new(mut base_url: Url) -> Result<Self> {
+    /// Builds a client for `base_url`. `timeout` bounds each request;
+    /// `None` disables the timeout. `tls_ca_cert` is a PEM file trusted in
+    /// addition to the built-in roots. `extra_headers` go out with every
+    /// request; `user_agent` replaces the default `qb-port-sync`.
+    pub fn new(
+        mut base_url: Url,
+        timeout: Option<Duration>,
+        http_version: HttpVersion,
+        tls_ca_cert: Option<&Path>,
+        tls_accept_invalid_certs: bool,
+        user_agent: Option<&str>,
+        extra_headers: header::HeaderMap,
+    ) -> Result<Self> {
         if base_url.path().is_empty() {
             base_url.set_path("/");
         }
 
-        let mut headers = header::HeaderMap::new();
+        let mut headers = extra_headers;
         let referer = header::HeaderValue::from_str(base_url.as_str())?;
         headers.insert(header::REFERER, referer);
         let origin_string = origin_from_url(&base_url);
         let origin = header::HeaderValue::from_str(&origin_string)?;
         headers.insert(header::ORIGIN, origin);
 
-        let client = Client::builder()
+        let mut builder = Client::builder()
             .default_headers(headers)
             .cookie_store(true)
-            .timeout(Duration::from_secs(15))
-            .user_agent("qb-port-sync")
-            .build()?;
+            .user_agent(user_agent.unwrap_or("qb-port-sync"));
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        builder = match http_version {
+            HttpVersion::Auto => builder,
+            HttpVersion::Http1 => builder.http1_only(),
+            HttpVersion::Http2 => builder.http2_prior_knowledge(),
+        };
+        if let Some(path) = tls_ca_cert {
+            builder = builder.add_root_certificate(load_ca_cert(path)?);
+        }
+        if tls_accept_invalid_certs {
+            warn!(
+                "TLS certificate verification is DISABLED for {base_url}: any certificate is accepted, so the connection (and the qBittorrent password) can be intercepted"
+            );
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+        let client = builder.build()?;
+
+        Ok(Self {
+            client,
+            base_url,
+            credentials: None,
+            retry_interface_id: true,
+            parked: Arc::new(AtomicBool::new(false)),
+            dry_run: false,
+            events: EventLog::default(),
+            extra_port_keys: Vec::new(),
+            login_retries: 0,
+            login_retry_delay: Duration::from_secs(1),
+            enforce_port_settings: true,
+            managed_port_settings: PORT_SETTINGS.to_vec(),
+            only_if_interface_present: false,
+            ban_cooldown: Duration::from_secs(3600),
+            banned_until: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// How long logins are held back after qBittorrent bans this address;
+    /// logging in during the ban would only extend it.
+    pub fn with_ban_cooldown(mut self, cooldown: Duration) -> Self {
+        self.ban_cooldown = cooldown;
+        self
+    }
+
+    /// Retries `login` up to `retries` times on transport errors and 5xx
+    /// responses, doubling `delay` between attempts (capped at 30s).
+    pub fn with_login_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.login_retries = retries;
+        self.login_retry_delay = delay;
+        self
+    }
+
+    /// Records re-authentication events into the shared event log.
+    pub fn with_event_log(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// In dry-run mode preference updates are logged instead of submitted.
+    pub fn with_dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
 
-        Ok(Self { client, base_url })
+    /// Additional preference keys that receive the same port as `listen_port`.
+    pub fn with_extra_port_keys(mut self, keys: Vec<String>) -> Self {
+        self.extra_port_keys = keys;
+        self
     }
 
-    pub async fn login(&self, user: &str, pass: &str) -> Result<()> {
+    /// Controls whether `random_port` or `upnp` still enabled after an
+    /// update are forced off with a second, corrective update.
+    pub fn with_port_settings_enforcement(mut self, enabled: bool) -> Self {
+        self.enforce_port_settings = enabled;
+        self
+    }
+
+    /// Chooses which of `random_port` and `upnp` are sent as `false` with
+    /// each update. An unmanaged one is left out of the payload and is
+    /// neither enforced nor reported after verification.
+    pub fn with_managed_port_settings(mut self, random_port: bool, upnp: bool) -> Self {
+        self.managed_port_settings = PORT_SETTINGS
+            .into_iter()
+            .zip([random_port, upnp])
+            .filter_map(|(key, managed)| managed.then_some(key))
+            .collect();
+        self
+    }
+
+    /// Disables the managed port-changing preferences in `payload`.
+    fn insert_port_settings(&self, payload: &mut Map<String, Value>) {
+        for key in &self.managed_port_settings {
+            payload.insert((*key).into(), Value::Bool(false));
+        }
+    }
+
+    /// Reads a managed port-changing preference from `prefs`.
+    fn managed_setting(&self, prefs: &Value, key: &str) -> Option<bool> {
+        if !self.managed_port_settings.contains(&key) {
+            return None;
+        }
+        prefs.get(key).and_then(Value::as_bool)
+    }
+
+    /// Skips port updates while the bind interface is missing from
+    /// qBittorrent's interface list, e.g. because the VPN is down.
+    pub fn with_interface_guard(mut self, enabled: bool) -> Self {
+        self.only_if_interface_present = enabled;
+        self
+    }
+
+    /// Controls whether a name-only interface binding triggers a second
+    /// interface lookup to obtain the `network_interface_id`.
+    pub fn with_interface_id_retry(mut self, enabled: bool) -> Self {
+        self.retry_interface_id = enabled;
+        self
+    }
+
+    /// Authenticates with the Web API and remembers the credentials so an
+    /// expired session can be renewed transparently.
+    pub async fn login(&mut self, user: &str, pass: &str) -> Result<()> {
+        let mut delay = self.login_retry_delay;
+        let mut attempt = 0;
+        while let Err(err) = self.authenticate(user, pass).await {
+            if attempt >= self.login_retries || !is_retryable_login_error(&err) {
+                return Err(err);
+            }
+            attempt += 1;
+            warn!(
+                "qBittorrent login failed ({err:#}); retry {attempt}/{} in {delay:?}",
+                self.login_retries
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_LOGIN_RETRY_DELAY);
+        }
+        self.credentials = Some(Credentials {
+            username: user.to_string(),
+            password: pass.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn authenticate(&self, user: &str, pass: &str) -> Result<()> {
+        if let Some(remaining) = self.ban_remaining() {
+            return Err(QbitError::Banned(remaining).into());
+        }
         let url = self.endpoint("api/v2/auth/login")?;
         let response = self
             .client
             .post(url)
             .form(&[("username", user), ("password", pass)])
             .send()
-            .await?;
+            .await
+            .map_err(QbitError::Transport)?;
 
         let status = response.status();
         let body = response.text().await.unwrap_or_default();
 
+        if is_ban_response(status, &body) {
+            *self.banned_until.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some(Instant::now() + self.ban_cooldown);
+            error!(
+                "qBittorrent banned this address after too many failed logins; check the credentials. No login is attempted for {}s",
+                self.ban_cooldown.as_secs()
+            );
+            return Err(QbitError::Banned(self.ban_cooldown).into());
+        }
+
         if !status.is_success() {
             return Err(QbitError::UnexpectedResponse {
                 status,
@@ -80,6 +327,14 @@ impl QbitClient {
         Ok(())
     }
 
+    /// Time left before logins may be attempted again after a ban.
+    fn ban_remaining(&self) -> Option<Duration> {
+        let banned_until = *self.banned_until.lock().unwrap_or_else(|e| e.into_inner());
+        banned_until
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
     pub async fn set_listen_port(
         &self,
         port: u16,
@@ -87,32 +342,106 @@ impl QbitClient {
     ) -> Result<PortUpdateResult> {
         let mut payload = Map::new();
         payload.insert("listen_port".to_string(), json!(port));
-        payload.insert("random_port".into(), Value::Bool(false));
-        payload.insert("upnp".into(), Value::Bool(false));
+        for key in &self.extra_port_keys {
+            payload.insert(key.clone(), json!(port));
+        }
+        self.insert_port_settings(&mut payload);
 
-        if let Some(interface) = bind_interface.map(str::trim).filter(|s| !s.is_empty()) {
+        // Read first: the port set since the last update shows changes made
+        // by other programs, and the binding shows a park left behind by an
+        // earlier run that crashed or was restarted.
+        let current = self.get_preferences().await?;
+        let previous_port = listen_port(&current)?;
+
+        let requested_interface = bind_interface.map(str::trim).filter(|s| !s.is_empty());
+        let mut name_only_binding = false;
+        if let Some(interface) = requested_interface {
             if let Some(selection) = self.resolve_interface(interface).await? {
                 payload.insert("network_interface".into(), Value::String(selection.name));
-                if let Some(id) = selection.id {
-                    payload.insert("network_interface_id".into(), Value::String(id));
+                match selection.id {
+                    Some(id) => {
+                        payload.insert("network_interface_id".into(), Value::String(id));
+                    }
+                    None => name_only_binding = true,
                 }
+            } else if self.only_if_interface_present {
+                warn!(
+                    "bind interface '{}' not found on qBittorrent; leaving listen port {} in place",
+                    interface, previous_port
+                );
+                return Ok(PortUpdateResult {
+                    detected_port: previous_port,
+                    previous_port: Some(previous_port),
+                    verified: false,
+                    random_port: None,
+                    upnp: None,
+                    interface_id_missing: false,
+                    interface_absent: true,
+                    listen_address: None,
+                    reachable: None,
+                });
             } else {
                 warn!("requested bind interface '{}' not found on qBittorrent; continuing without binding", interface);
             }
+        } else if self.parked.load(Ordering::Relaxed) || is_parked(&current) {
+            payload.insert("network_interface".into(), Value::String(String::new()));
+            payload.insert("network_interface_id".into(), Value::String(String::new()));
         }
 
-        self.post_preferences(payload).await?;
-        let prefs = self.get_preferences().await?;
-        let detected_port = prefs
-            .get("listen_port")
-            .and_then(Value::as_u64)
-            .and_then(|v| u16::try_from(v).ok())
-            .ok_or_else(|| anyhow::anyhow!("qBittorrent preferences missing listen_port"))?;
-        let random_port = prefs.get("random_port").and_then(Value::as_bool);
-        let upnp = prefs.get("upnp").and_then(Value::as_bool);
-
-        let verified = detected_port == port;
-        if verified {
+        if self.dry_run {
+            let payload = Value::Object(payload);
+            info!("dry run: would set listen port {} with {}", port, payload);
+            return Ok(PortUpdateResult {
+                detected_port: port,
+                previous_port: Some(previous_port),
+                verified: false,
+                random_port: None,
+                upnp: None,
+                interface_id_missing: false,
+                interface_absent: false,
+                listen_address: None,
+                reachable: None,
+            });
+        }
+
+        self.with_reauth(|| self.post_preferences(payload.clone()))
+            .await?;
+        self.parked.store(false, Ordering::Relaxed);
+        // Verification reads the full preferences: `sync/maindata` would be
+        // smaller per cycle, but its `server_state` carries no `listen_port`
+        // and the initial (rid 0) response lists every torrent.
+        let mut prefs = self.get_preferences().await?;
+        if self.enforce_port_settings {
+            prefs = self.reassert_port_settings(prefs).await?;
+        }
+        let detected_port = listen_port(&prefs)?;
+        let random_port = self.managed_setting(&prefs, "random_port");
+        let upnp = self.managed_setting(&prefs, "upnp");
+        let listen_address = listen_address(&prefs);
+
+        let interface_id_missing = match requested_interface {
+            Some(interface) if name_only_binding && !has_interface_id(&prefs) => {
+                !self.complete_interface_binding(interface).await
+            }
+            _ => false,
+        };
+
+        let mismatched_keys: Vec<&str> = self
+            .extra_port_keys
+            .iter()
+            .filter(|key| prefs.get(key.as_str()).and_then(port_value) != Some(port))
+            .map(String::as_str)
+            .collect();
+        if !mismatched_keys.is_empty() {
+            warn!(
+                "qBittorrent did not echo port {} for {}",
+                port,
+                mismatched_keys.join(", ")
+            );
+        }
+
+        let verified = detected_port == port && mismatched_keys.is_empty();
+        if detected_port == port {
             info!("qBittorrent listen port verified at {}", detected_port);
         } else {
             warn!(
@@ -123,26 +452,219 @@ impl QbitClient {
 
         Ok(PortUpdateResult {
             detected_port,
+            previous_port: Some(previous_port),
             verified,
             random_port,
             upnp,
+            interface_id_missing,
+            interface_absent: false,
+            listen_address,
+            reachable: None,
         })
     }
 
+    /// Some qBittorrent builds turn `random_port` or `upnp` back on (e.g.
+    /// after a restart), which would move the listen port away again. When
+    /// `prefs` shows either enabled, forces both off and returns a fresh read.
+    async fn reassert_port_settings(&self, prefs: Value) -> Result<Value> {
+        let enabled = enabled_port_settings(&prefs, &self.managed_port_settings);
+        if enabled.is_empty() {
+            return Ok(prefs);
+        }
+        warn!(
+            "qBittorrent still has {} enabled after the update; forcing it off",
+            enabled.join(" and ")
+        );
+        let mut payload = Map::new();
+        self.insert_port_settings(&mut payload);
+        self.with_reauth(|| self.post_preferences(payload.clone()))
+            .await?;
+
+        let prefs = self.get_preferences().await?;
+        let still_enabled = enabled_port_settings(&prefs, &self.managed_port_settings);
+        if still_enabled.is_empty() {
+            info!("corrected qBittorrent {}", enabled.join(" and "));
+        } else {
+            warn!(
+                "qBittorrent kept {} enabled after the correction",
+                still_enabled.join(" and ")
+            );
+        }
+        Ok(prefs)
+    }
+
+    /// Called when qBittorrent accepted a binding by name but reports no
+    /// interface ID. Returns `true` once the ID has been applied.
+    async fn complete_interface_binding(&self, requested: &str) -> bool {
+        if self.retry_interface_id {
+            match self.resolve_interface(requested).await {
+                Ok(Some(InterfaceSelection { name, id: Some(id) })) => {
+                    let mut payload = Map::new();
+                    payload.insert("network_interface".into(), Value::String(name));
+                    payload.insert("network_interface_id".into(), Value::String(id));
+                    match self
+                        .with_reauth(|| self.post_preferences(payload.clone()))
+                        .await
+                    {
+                        Ok(()) => {
+                            info!("bound qBittorrent to interface '{}' by ID", requested);
+                            return true;
+                        }
+                        Err(err) => {
+                            warn!("failed to apply network_interface_id for '{requested}': {err:#}")
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => debug!("interface ID lookup retry failed: {err:#}"),
+            }
+        }
+
+        warn!(
+            "qBittorrent did not report a network_interface_id for '{}'; this qBittorrent version likely requires the interface ID and binding may not take effect",
+            requested
+        );
+        false
+    }
+
+    /// Restricts qBittorrent to a single interface without touching the
+    /// listen port. Used to park the client while the VPN tunnel is down;
+    /// the next `set_listen_port` without a bind interface clears it again.
+    /// `interface` may be a pattern; when nothing qBittorrent lists matches
+    /// it, loopback is used instead. The interface ID is sent only when
+    /// qBittorrent lists the interface. Returns the interface bound.
+    pub async fn apply_safe_binding(&self, interface: &str) -> Result<String> {
+        let mut selection = self.resolve_interface(interface).await?;
+        if selection.is_none() && interface != LOOPBACK_INTERFACE {
+            debug!("no interface matches '{interface}'; parking on '{LOOPBACK_INTERFACE}'");
+            selection = self.resolve_interface(LOOPBACK_INTERFACE).await?;
+        }
+        let InterfaceSelection { name, id } = selection.unwrap_or(InterfaceSelection {
+            name: LOOPBACK_INTERFACE.to_string(),
+            id: None,
+        });
+        let mut payload = Map::new();
+        payload.insert("network_interface".into(), Value::String(name.clone()));
+        if let Some(id) = id {
+            payload.insert("network_interface_id".into(), Value::String(id));
+        }
+        self.insert_port_settings(&mut payload);
+        if self.dry_run {
+            let payload = Value::Object(payload);
+            info!("dry run: would apply safe binding {}", payload);
+            return Ok(name);
+        }
+        self.with_reauth(|| self.post_preferences(payload.clone()))
+            .await?;
+        self.parked.store(true, Ordering::Relaxed);
+        Ok(name)
+    }
+
+    /// Re-resolves `interface` and posts only `network_interface` and
+    /// `network_interface_id`, preserving the current listen port.
+    pub async fn apply_interface_binding(&self, interface: &str) -> Result<InterfaceBinding> {
+        let prefs = self.get_preferences().await?;
+        let listen_port = listen_port(&prefs)?;
+        let selection = self
+            .resolve_interface(interface)
+            .await?
+            .ok_or_else(|| QbitError::InterfaceNotFound(interface.to_string()))?;
+
+        let mut payload = Map::new();
+        payload.insert(
+            "network_interface".into(),
+            Value::String(selection.name.clone()),
+        );
+        if let Some(id) = selection.id.as_ref() {
+            payload.insert("network_interface_id".into(), Value::String(id.clone()));
+        }
+        let mut binding = InterfaceBinding {
+            interface: selection.name,
+            interface_id: selection.id,
+            listen_port,
+            verified: false,
+        };
+
+        if self.dry_run {
+            let payload = Value::Object(payload);
+            info!("dry run: would apply interface binding {}", payload);
+            return Ok(binding);
+        }
+
+        self.with_reauth(|| self.post_preferences(payload.clone()))
+            .await?;
+        self.parked.store(false, Ordering::Relaxed);
+        let prefs = self.get_preferences().await?;
+        binding.verified = prefs.get("network_interface").and_then(Value::as_str)
+            == Some(binding.interface.as_str());
+        if !binding.verified {
+            warn!(
+                "qBittorrent did not report interface '{}' after rebinding",
+                binding.interface
+            );
+        }
+        Ok(binding)
+    }
+
+    /// Reads the current listen port without changing anything.
+    #[allow(dead_code)]
+    pub async fn get_listen_port(&self) -> Result<u16> {
+        listen_port(&self.get_preferences().await?)
+    }
+
+    /// Reads the listen port, as [`get_listen_port`](Self::get_listen_port)
+    /// does, with the `random_port` and `upnp` flags.
+    pub async fn get_port_status(&self) -> Result<PortStatus> {
+        port_status(&self.get_preferences().await?)
+    }
+
     pub async fn get_preferences(&self) -> Result<Value> {
+        self.with_reauth(|| self.fetch_preferences()).await
+    }
+
+    /// Runs `op`, and if qBittorrent rejects it because the session expired,
+    /// logs in again with the stored credentials and retries exactly once.
+    async fn with_reauth<T, F, Fut>(&self, op: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        match op().await {
+            Err(err) if is_session_expired(&err) => {
+                let Some(creds) = self.credentials.as_ref() else {
+                    return Err(err);
+                };
+                warn!("qBittorrent session expired; re-authenticating");
+                self.events.record(
+                    EventKind::Relogin,
+                    format!("session expired at {}", self.base_url),
+                );
+                self.authenticate(&creds.username, &creds.password).await?;
+                op().await
+            }
+            other => other,
+        }
+    }
+
+    async fn fetch_preferences(&self) -> Result<Value> {
         let url = self.endpoint("api/v2/app/preferences")?;
-        let response = self.client.get(url).send().await?;
+        let started = Instant::now();
+        let response = self.client.get(url).send().await;
+        record_request_duration("preferences", started);
+        let response = response.map_err(QbitError::Transport)?;
         let status = response.status();
         if !status.is_success() {
             let message = response.text().await.unwrap_or_default();
             return Err(QbitError::UnexpectedResponse { status, message }.into());
         }
+        expect_json(&response)?;
         let value = response.json::<Value>().await?;
         Ok(value)
     }
 
     async fn post_preferences(&self, payload: Map<String, Value>) -> Result<()> {
         let url = self.endpoint("api/v2/app/setPreferences")?;
+        let started = Instant::now();
         let response = self
             .client
             .post(url)
@@ -152,7 +674,9 @@ impl QbitClient {
             )
             .form(&[("json", Value::Object(payload).to_string())])
             .send()
-            .await?;
+            .await;
+        record_request_duration("setPreferences", started);
+        let response = response.map_err(QbitError::Transport)?;
 
         if !response.status().is_success() {
             let status = response.status();
@@ -165,34 +689,99 @@ impl QbitClient {
     }
 
     async fn resolve_interface(&self, requested: &str) -> Result<Option<InterfaceSelection>> {
-        let items = match self.fetch_interfaces().await {
+        let items = match self.with_reauth(|| self.fetch_interfaces()).await {
             Ok(items) => items,
             Err(err) => {
                 warn!("failed to fetch qBittorrent network interfaces: {err:?}");
                 return Ok(None);
             }
         };
-        let matches: Vec<NetworkInterfaceItem> = items
-            .into_iter()
-            .filter(|item| matches_interface(item, requested))
-            .collect();
-        if let Some(item) = matches.first() {
+        let pattern = InterfacePattern::parse(requested)?;
+        if let Some(item) = items.iter().find(|item| matches_interface(item, &pattern)) {
             return Ok(Some(InterfaceSelection {
                 name: item.name.clone(),
                 id: item.id.clone().or_else(|| item.interface.clone()),
             }));
         }
+        if let Ok(addr) = requested.trim().parse::<IpAddr>() {
+            return self.resolve_interface_by_address(&items, addr).await;
+        }
+        Ok(None)
+    }
+
+    /// Finds the interface currently holding `addr`, for VPN interfaces whose
+    /// name changes between sessions while the address stays stable.
+    async fn resolve_interface_by_address(
+        &self,
+        items: &[NetworkInterfaceItem],
+        addr: IpAddr,
+    ) -> Result<Option<InterfaceSelection>> {
+        for item in items {
+            let key = item
+                .id
+                .as_deref()
+                .or(item.interface.as_deref())
+                .unwrap_or(&item.name);
+            let addresses = match self
+                .with_reauth(|| self.fetch_interface_addresses(key))
+                .await
+            {
+                Ok(addresses) => addresses,
+                Err(err) => {
+                    debug!(
+                        "failed to fetch addresses for interface {}: {err:?}",
+                        item.name
+                    );
+                    continue;
+                }
+            };
+            if addresses
+                .iter()
+                .any(|candidate| address_matches(candidate, addr))
+            {
+                debug!("bind address {} belongs to interface {}", addr, item.name);
+                return Ok(Some(InterfaceSelection {
+                    name: item.name.clone(),
+                    id: item.id.clone().or_else(|| item.interface.clone()),
+                }));
+            }
+        }
         Ok(None)
     }
 
+    async fn fetch_interface_addresses(&self, iface: &str) -> Result<Vec<String>> {
+        let url = self.endpoint("api/v2/app/networkInterfaceAddressList")?;
+        let response = self
+            .client
+            .get(url)
+            .query(&[("iface", iface)])
+            .send()
+            .await
+            .map_err(QbitError::Transport)?;
+        let status = response.status();
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(QbitError::UnexpectedResponse { status, message }.into());
+        }
+        expect_json(&response)?;
+        let list = response.json::<Vec<String>>().await?;
+        Ok(list)
+    }
+
     async fn fetch_interfaces(&self) -> Result<Vec<NetworkInterfaceItem>> {
         let url = self.endpoint("api/v2/app/networkInterfaceList")?;
-        let response = self.client.get(url).send().await?;
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(QbitError::Transport)?;
         let status = response.status();
         if !status.is_success() {
             let message = response.text().await.unwrap_or_default();
             return Err(QbitError::UnexpectedResponse { status, message }.into());
         }
+        expect_json(&response)?;
         let list = response.json::<Vec<NetworkInterfaceItem>>().await?;
         Ok(list)
     }
@@ -204,24 +793,135 @@ impl QbitClient {
     }
 }
 
-fn matches_interface(item: &NetworkInterfaceItem, requested: &str) -> bool {
-    let requested = requested.trim();
-    if requested.is_empty() {
-        return false;
-    }
-    item.name == requested
+fn load_ca_cert(path: &Path) -> Result<Certificate> {
+    let invalid = |err: &dyn std::fmt::Display| {
+        ConfigError::InvalidCaCert(path.display().to_string(), err.to_string())
+    };
+    let pem = std::fs::read(path).map_err(|err| invalid(&err))?;
+    Ok(Certificate::from_pem(&pem).map_err(|err| invalid(&err))?)
+}
+
+fn matches_interface(item: &NetworkInterfaceItem, pattern: &InterfacePattern) -> bool {
+    pattern.matches(&item.name)
         || item
             .interface
             .as_deref()
-            .map(|iface| iface == requested)
+            .map(|iface| pattern.matches(iface))
             .unwrap_or(false)
         || item
             .id
             .as_deref()
-            .map(|id| id == requested)
+            .map(|id| pattern.matches(id))
             .unwrap_or(false)
 }
 
+/// Compares an address reported by qBittorrent, which may carry an IPv6
+/// zone suffix (`fe80::1%wg0`), with the requested bind address.
+fn address_matches(candidate: &str, addr: IpAddr) -> bool {
+    let candidate = candidate.trim();
+    let without_zone = candidate.split('%').next().unwrap_or(candidate);
+    without_zone
+        .parse::<IpAddr>()
+        .map(|parsed| parsed == addr)
+        .unwrap_or(false)
+}
+
+/// Reads `listen_port`, which some qBittorrent versions report as a numeric
+/// string instead of a number.
+fn listen_port(prefs: &Value) -> Result<u16> {
+    let value = prefs
+        .get("listen_port")
+        .ok_or_else(|| anyhow::anyhow!("qBittorrent preferences missing listen_port"))?;
+    port_value(value)
+        .ok_or_else(|| anyhow::anyhow!("qBittorrent reported an invalid listen_port: {value}"))
+}
+
+/// Reads a port preference, which some qBittorrent builds echo as a numeric
+/// string.
+fn port_value(value: &Value) -> Option<u16> {
+    match value {
+        Value::Number(number) => number.as_u64().and_then(|v| u16::try_from(v).ok()),
+        Value::String(text) => text.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Whether `prefs` show the loopback binding of the startup leak protection.
+fn is_parked(prefs: &Value) -> bool {
+    let parked = prefs.get("network_interface").and_then(Value::as_str) == Some(LOOPBACK_INTERFACE);
+    if parked {
+        info!("qBittorrent is still bound to '{LOOPBACK_INTERFACE}'; clearing the binding");
+    }
+    parked
+}
+
+fn port_status(prefs: &Value) -> Result<PortStatus> {
+    Ok(PortStatus {
+        listen_port: listen_port(prefs)?,
+        random_port: prefs.get("random_port").and_then(Value::as_bool),
+        upnp: prefs.get("upnp").and_then(Value::as_bool),
+    })
+}
+
+fn listen_address(prefs: &Value) -> Option<IpAddr> {
+    prefs
+        .get("current_interface_address")
+        .and_then(Value::as_str)
+        .and_then(|addr| addr.trim().parse().ok())
+}
+
+/// Preferences that make qBittorrent move the listen port on its own.
+const PORT_SETTINGS: [&str; 2] = ["random_port", "upnp"];
+
+/// Which of `keys` `prefs` reports as enabled.
+fn enabled_port_settings(prefs: &Value, keys: &[&'static str]) -> Vec<&'static str> {
+    keys.iter()
+        .copied()
+        .filter(|key| prefs.get(*key).and_then(Value::as_bool) == Some(true))
+        .collect()
+}
+
+fn has_interface_id(prefs: &Value) -> bool {
+    prefs
+        .get("network_interface_id")
+        .and_then(Value::as_str)
+        .map(|id| !id.trim().is_empty())
+        .unwrap_or(false)
+}
+
+/// qBittorrent answers every request from a banned address with a 403 and
+/// a fixed message, which sets it apart from an expired session.
+fn is_ban_response(status: StatusCode, body: &str) -> bool {
+    status == StatusCode::FORBIDDEN && body.contains(BAN_MESSAGE)
+}
+
+/// Records a Web API round trip, labeled by `endpoint`, in the
+/// `qbit_request_duration_seconds` histogram.
+fn record_request_duration(endpoint: &'static str, started: Instant) {
+    #[cfg(feature = "metrics")]
+    metrics::histogram!(metric_name("qbit_request_duration_seconds"), "endpoint" => endpoint)
+        .record(started.elapsed().as_secs_f64());
+    #[cfg(not(feature = "metrics"))]
+    let _ = (endpoint, started);
+}
+
+fn is_session_expired(err: &anyhow::Error) -> bool {
+    matches!(
+        err.downcast_ref::<QbitError>(),
+        Some(QbitError::UnexpectedResponse { status, .. }) if *status == StatusCode::FORBIDDEN
+    )
+}
+
+/// Transport failures (qBittorrent not listening yet, timeouts) and 5xx
+/// responses are worth retrying; a rejected login is not.
+fn is_retryable_login_error(err: &anyhow::Error) -> bool {
+    match err.downcast_ref::<QbitError>() {
+        Some(QbitError::UnexpectedResponse { status, .. }) => status.is_server_error(),
+        Some(QbitError::Transport(err)) => err.is_connect() || err.is_timeout() || err.is_request(),
+        _ => false,
+    }
+}
+
 fn origin_from_url(url: &Url) -> String {
     url.origin().unicode_serialization()
 }
@@ -231,10 +931,44 @@ struct InterfaceSelection {
     id: Option<String>,
 }
 
+/// Rejects HTML and plain-text bodies before deserializing, so a proxy login
+/// page served with 200 is reported as such rather than as a serde error.
+/// Responses without a `Content-Type` are given the benefit of the doubt.
+fn expect_json(response: &reqwest::Response) -> Result<()> {
+    let Some(content_type) = response.headers().get(header::CONTENT_TYPE) else {
+        return Ok(());
+    };
+    let mime = content_type
+        .to_str()
+        .unwrap_or_default()
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if mime.starts_with("text/") || mime == "application/xhtml+xml" {
+        return Err(QbitError::NotJson(mime).into());
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use super::matches_interface;
-    use super::NetworkInterfaceItem;
+    use super::{
+        address_matches, enabled_port_settings, has_interface_id, is_ban_response,
+        is_retryable_login_error, is_session_expired, listen_address, listen_port,
+        matches_interface, port_status, port_value,
+    };
+    use super::{
+        InterfaceBinding, NetworkInterfaceItem, PortStatus, QbitClient, LOOPBACK_INTERFACE,
+        PORT_SETTINGS,
+    };
+    use crate::config::{HttpVersion, InterfacePattern};
+    use crate::error::{classify_error, ExitCode, QbitError};
+    use crate::test_support::{serve, JSON};
+    use reqwest::header::HeaderMap;
+    use reqwest::StatusCode;
+    use serde_json::json;
 
     #[test]
     fn interface_match_handles_aliases() {
@@ -243,8 +977,531 @@ mod tests {
             interface: Some("tun0".into()),
             id: Some("{1234}".into()),
         };
-        assert!(matches_interface(&item, "tun0"));
-        assert!(matches_interface(&item, "{1234}"));
-        assert!(!matches_interface(&item, "eth0"));
+        let pattern = |raw: &str| InterfacePattern::parse(raw).unwrap();
+        assert!(matches_interface(&item, &pattern("tun0")));
+        assert!(matches_interface(&item, &pattern("{1234}")));
+        assert!(!matches_interface(&item, &pattern("eth0")));
+        assert!(!matches_interface(&item, &pattern("")));
+    }
+
+    #[test]
+    fn interface_match_accepts_globs_and_regexes() {
+        let item = |name: &str| NetworkInterfaceItem {
+            name: name.into(),
+            interface: None,
+            id: None,
+        };
+        let pattern = |raw: &str| InterfacePattern::parse(raw).unwrap();
+        assert!(matches_interface(&item("proton0"), &pattern("proton*")));
+        assert!(matches_interface(&item("proton"), &pattern("proton*")));
+        assert!(!matches_interface(&item("wg-proton"), &pattern("proton*")));
+        assert!(matches_interface(&item("wg-proton"), &pattern("*proton")));
+        assert!(matches_interface(&item("tun1"), &pattern("tun?")));
+        assert!(!matches_interface(&item("tun10"), &pattern("tun?")));
+        assert!(matches_interface(&item("pvpnksintrf0"), &pattern("*ks*f?")));
+
+        let regex = pattern(r"re:^(proton|pvpn)\w*\d$");
+        assert!(matches_interface(&item("pvpnksintrf0"), &regex));
+        assert!(matches_interface(&item("proton0"), &regex));
+        assert!(!matches_interface(&item("wg-proton"), &regex));
+        // Without metacharacters the name must match exactly.
+        assert!(!matches_interface(&item("proton0"), &pattern("proton")));
+        assert!(InterfacePattern::parse("re:proton(").is_err());
+    }
+
+    #[test]
+    fn binding_description_includes_distinct_id() {
+        let mut binding = InterfaceBinding {
+            interface: "tun0".into(),
+            interface_id: Some("tun0".into()),
+            listen_port: 51820,
+            verified: true,
+        };
+        assert_eq!(binding.describe(), "tun0");
+        binding.interface_id = Some("{4d36e972}".into());
+        assert_eq!(binding.describe(), "tun0 (id {4d36e972})");
+    }
+
+    #[test]
+    fn listen_address_ignores_unrestricted_binding() {
+        assert_eq!(
+            listen_address(&json!({"current_interface_address": ""})),
+            None
+        );
+        assert_eq!(listen_address(&json!({})), None);
+        assert_eq!(
+            listen_address(&json!({"current_interface_address": "::"})),
+            Some("::".parse().unwrap())
+        );
+        assert_eq!(
+            listen_address(&json!({"current_interface_address": "10.2.0.2"})),
+            Some("10.2.0.2".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn address_match_ignores_ipv6_zone() {
+        assert!(address_matches("10.2.0.2", "10.2.0.2".parse().unwrap()));
+        assert!(address_matches("fe80::1%wg0", "fe80::1".parse().unwrap()));
+        assert!(!address_matches("10.2.0.3", "10.2.0.2".parse().unwrap()));
+        assert!(!address_matches("garbage", "10.2.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn interface_id_requires_non_empty_value() {
+        assert!(has_interface_id(
+            &json!({ "network_interface_id": "{1234}" })
+        ));
+        assert!(!has_interface_id(&json!({ "network_interface_id": "" })));
+        assert!(!has_interface_id(&json!({ "network_interface": "tun0" })));
+    }
+
+    #[test]
+    fn unreadable_or_invalid_ca_cert_is_a_config_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let url = reqwest::Url::parse("https://qbittorrent.lan/").unwrap();
+        let client = |ca: &std::path::Path| {
+            QbitClient::new(
+                url.clone(),
+                None,
+                HttpVersion::Auto,
+                Some(ca),
+                false,
+                None,
+                HeaderMap::new(),
+            )
+        };
+
+        let missing = dir.path().join("missing.pem");
+        let err = client(&missing).err().unwrap();
+        assert_eq!(classify_error(&err), ExitCode::Config);
+        assert!(format!("{err:#}").contains("missing.pem"));
+
+        let garbage = dir.path().join("garbage.pem");
+        std::fs::write(
+            &garbage,
+            "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n",
+        )
+        .unwrap();
+        let err = client(&garbage).err().unwrap();
+        assert_eq!(classify_error(&err), ExitCode::Config);
+
+        assert!(QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            true,
+            None,
+            HeaderMap::new()
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn enabled_port_settings_lists_only_true_flags() {
+        assert_eq!(
+            enabled_port_settings(
+                &json!({ "random_port": true, "upnp": true }),
+                &PORT_SETTINGS
+            ),
+            ["random_port", "upnp"]
+        );
+        assert_eq!(
+            enabled_port_settings(
+                &json!({ "random_port": false, "upnp": true }),
+                &PORT_SETTINGS
+            ),
+            ["upnp"]
+        );
+        assert!(enabled_port_settings(&json!({ "listen_port": 51820 }), &PORT_SETTINGS).is_empty());
+        assert_eq!(
+            enabled_port_settings(
+                &json!({ "random_port": true, "upnp": true }),
+                &["random_port"]
+            ),
+            ["random_port"]
+        );
+    }
+
+    #[test]
+    fn listen_port_accepts_numbers_and_numeric_strings() {
+        assert_eq!(
+            listen_port(&json!({ "listen_port": 51820 })).unwrap(),
+            51820
+        );
+        assert_eq!(
+            listen_port(&json!({ "listen_port": "51820" })).unwrap(),
+            51820
+        );
+
+        let missing = listen_port(&json!({ "upnp": false })).unwrap_err();
+        assert!(missing.to_string().contains("missing listen_port"));
+        for invalid in [json!("tun0"), json!(70000), json!(null)] {
+            let err = listen_port(&json!({ "listen_port": invalid })).unwrap_err();
+            assert!(err.to_string().contains("invalid listen_port"), "{err}");
+        }
+
+        // `also_set_keys` are read back the same way.
+        assert_eq!(port_value(&json!(" 51820 ")), Some(51820));
+        assert_eq!(port_value(&json!(true)), None);
+    }
+
+    #[test]
+    fn port_status_reads_port_and_flags() {
+        let prefs = json!({ "listen_port": 51820, "random_port": false, "upnp": true });
+        assert_eq!(
+            port_status(&prefs).unwrap(),
+            PortStatus {
+                listen_port: 51820,
+                random_port: Some(false),
+                upnp: Some(true),
+            }
+        );
+        let status = port_status(&json!({ "listen_port": 51820 })).unwrap();
+        assert_eq!((status.random_port, status.upnp), (None, None));
+        assert!(port_status(&json!({ "upnp": false })).is_err());
+    }
+
+    #[test]
+    fn forbidden_response_is_session_expiry() {
+        let forbidden = anyhow::Error::from(QbitError::UnexpectedResponse {
+            status: StatusCode::FORBIDDEN,
+            message: "Forbidden".into(),
+        });
+        let server_error = anyhow::Error::from(QbitError::UnexpectedResponse {
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            message: String::new(),
+        });
+        assert!(is_session_expired(&forbidden));
+        assert!(!is_session_expired(&server_error));
+        assert!(!is_session_expired(&anyhow::anyhow!("other")));
+    }
+
+    #[test]
+    fn ban_is_told_apart_from_session_expiry() {
+        let body = "Your IP address has been banned after too many failed authentication attempts.";
+        assert!(is_ban_response(StatusCode::FORBIDDEN, body));
+        assert!(!is_ban_response(StatusCode::FORBIDDEN, "Forbidden"));
+        assert!(!is_ban_response(StatusCode::OK, body));
+    }
+
+    #[tokio::test]
+    async fn ban_holds_back_further_logins() {
+        // The server stops after one answer, so a retry or a second login
+        // would fail with a transport error instead of the ban.
+        let (addr, server) = serve(vec![(
+            "403 Forbidden",
+            &[],
+            "Your IP address has been banned after too many failed authentication attempts.",
+        )])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let mut client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap()
+        .with_login_retries(3, std::time::Duration::from_millis(1));
+        for _ in 0..2 {
+            let err = client.login("admin", "wrong").await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<QbitError>(),
+                Some(QbitError::Banned(_))
+            ));
+            assert_eq!(classify_error(&err), ExitCode::Transient);
+        }
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn only_server_errors_and_transport_failures_retry_login() {
+        let server_error = anyhow::Error::from(QbitError::UnexpectedResponse {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            message: String::new(),
+        });
+        let banned = anyhow::Error::from(QbitError::UnexpectedResponse {
+            status: StatusCode::FORBIDDEN,
+            message: String::new(),
+        });
+        let rejected = anyhow::Error::from(QbitError::Auth("Fails.".into()));
+        assert!(is_retryable_login_error(&server_error));
+        assert!(!is_retryable_login_error(&banned));
+        assert!(!is_retryable_login_error(&rejected));
+    }
+
+    #[tokio::test]
+    async fn connection_refused_is_retryable() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+        let err = reqwest::get(format!("http://{addr}/")).await.unwrap_err();
+        let err = anyhow::Error::from(QbitError::Transport(err)).context("failed to log in");
+        assert!(is_retryable_login_error(&err));
+        assert_eq!(classify_error(&err), ExitCode::Unreachable);
+
+        // Only qBittorrent's own transport failures mean it is unreachable.
+        let other = reqwest::get(format!("http://{addr}/")).await.unwrap_err();
+        assert_eq!(classify_error(&other.into()), ExitCode::Transient);
+    }
+
+    #[tokio::test]
+    async fn html_login_page_is_reported_as_not_json() {
+        let (addr, _) = serve(vec![(
+            "200 OK",
+            &["Content-Type: text/html; charset=utf-8"],
+            "<html><body>Please sign in</body></html>",
+        )])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap();
+        let err = client.fetch_preferences().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<QbitError>(),
+            Some(QbitError::NotJson(mime)) if mime == "text/html"
+        ));
+        assert!(err.to_string().contains("not a login redirect"));
+    }
+
+    #[tokio::test]
+    async fn user_agent_and_extra_headers_are_sent_with_referer() {
+        let (addr, server) = serve(vec![("200 OK", JSON, r#"{"listen_port":6881}"#)]).await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Basic cHJveHk6cHc=".parse().unwrap());
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            Some("curl/8.0"),
+            headers,
+        )
+        .unwrap();
+        client.fetch_preferences().await.unwrap();
+
+        let request = server.await.unwrap()[0].to_ascii_lowercase();
+        assert!(request.contains("user-agent: curl/8.0"));
+        assert!(request.contains(
+            "authorization: basic cHJveHk6cHc="
+                .to_ascii_lowercase()
+                .as_str()
+        ));
+        assert!(request.contains(&format!("referer: http://{addr}/")));
+    }
+
+    #[tokio::test]
+    async fn absent_interface_skips_the_update_when_guarded() {
+        let (addr, server) = serve(vec![
+            ("200 OK", JSON, r#"{"listen_port":6881}"#),
+            ("200 OK", JSON, r#"[{"name":"eth0","id":"eth0"}]"#),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap()
+        .with_interface_guard(true);
+        let update = client.set_listen_port(51820, Some("tun0")).await.unwrap();
+        assert!(update.interface_absent);
+        assert!(!update.verified);
+        assert_eq!(update.detected_port, 6881);
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("networkInterfaceList"));
+        assert!(requests
+            .iter()
+            .all(|request| !request.contains("setPreferences")));
+    }
+
+    #[tokio::test]
+    async fn unmanaged_upnp_is_left_out_of_the_update() {
+        let prefs = r#"{"listen_port":51820,"random_port":false,"upnp":true}"#;
+        let (addr, server) = serve(vec![
+            ("200 OK", JSON, prefs),
+            ("200 OK", JSON, prefs),
+            ("200 OK", JSON, prefs),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap()
+        .with_managed_port_settings(true, false);
+        let update = client.set_listen_port(51820, None).await.unwrap();
+        assert!(update.verified);
+        assert_eq!((update.random_port, update.upnp), (Some(false), None));
+
+        // Only the read before the update, the update and the verification
+        // read: upnp=true is not enforced.
+        let requests = server.await.unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].contains("setPreferences"));
+        assert!(requests[1].contains("random_port"));
+        assert!(!requests[1].contains("upnp"));
+    }
+
+    #[tokio::test]
+    async fn listen_port_is_read_without_posting() {
+        let (addr, server) = serve(vec![(
+            "200 OK",
+            JSON,
+            r#"{"listen_port":"51820","upnp":false}"#,
+        )])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap();
+        assert_eq!(client.get_listen_port().await.unwrap(), 51820);
+        let requests = server.await.unwrap();
+        assert!(requests[0].starts_with("GET /api/v2/app/preferences"));
+    }
+
+    #[tokio::test]
+    async fn updates_read_the_port_first_and_clear_a_leftover_loopback_park() {
+        let parked = match LOOPBACK_INTERFACE {
+            "lo0" => r#"{"listen_port":51820,"network_interface":"lo0"}"#,
+            _ => r#"{"listen_port":51820,"network_interface":"lo"}"#,
+        };
+        let cleared =
+            r#"{"listen_port":51820,"random_port":false,"upnp":false,"network_interface":""}"#;
+        let drifted = r#"{"listen_port":40000,"network_interface":""}"#;
+        let (addr, server) = serve(vec![
+            ("200 OK", JSON, parked),
+            ("200 OK", &[], ""),
+            ("200 OK", JSON, cleared),
+            ("200 OK", JSON, drifted),
+            ("200 OK", &[], ""),
+            ("200 OK", JSON, cleared),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap();
+        let first = client.set_listen_port(51820, None).await.unwrap();
+        assert!(first.verified);
+        assert_eq!(first.previous_port, Some(51820));
+        // Another program moved the port between the two updates.
+        let second = client.set_listen_port(51820, None).await.unwrap();
+        assert!(second.verified);
+        assert_eq!(second.previous_port, Some(40000));
+
+        // Only the first update finds the park and unbinds.
+        let requests = server.await.unwrap();
+        let unbinds = |request: &String| request.contains("%22network_interface%22%3A%22%22");
+        assert!(requests[0].contains("GET /api/v2/app/preferences"));
+        assert!(requests[1].contains("setPreferences") && unbinds(&requests[1]));
+        assert!(requests[3].contains("GET /api/v2/app/preferences"));
+        assert!(requests[4].contains("setPreferences") && !unbinds(&requests[4]));
+    }
+
+    #[tokio::test]
+    async fn safe_binding_sends_the_listed_interface_id() {
+        let (addr, server) = serve(vec![
+            ("200 OK", JSON, r#"[{"name":"tun0","id":"{5f1c}"}]"#),
+            ("200 OK", &[], ""),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap();
+        assert_eq!(client.apply_safe_binding("tun0").await.unwrap(), "tun0");
+
+        let requests = server.await.unwrap();
+        assert!(requests[1].contains("setPreferences"));
+        assert!(requests[1].contains("%22network_interface_id%22%3A%22%7B5f1c%7D%22"));
+    }
+
+    #[tokio::test]
+    async fn safe_binding_falls_back_to_loopback_when_no_interface_matches() {
+        let interfaces = match LOOPBACK_INTERFACE {
+            "lo0" => r#"[{"name":"en0","id":"en0"},{"name":"lo0","id":"lo0"}]"#,
+            _ => r#"[{"name":"eth0","id":"eth0"},{"name":"lo","id":"lo"}]"#,
+        };
+        let (addr, server) = serve(vec![
+            ("200 OK", JSON, interfaces),
+            ("200 OK", JSON, interfaces),
+            ("200 OK", &[], ""),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/")).unwrap();
+        let client = QbitClient::new(
+            url,
+            None,
+            HttpVersion::Auto,
+            None,
+            false,
+            None,
+            HeaderMap::new(),
+        )
+        .unwrap();
+        let bound = client.apply_safe_binding("re:^(tun|wg)").await.unwrap();
+        assert_eq!(bound, LOOPBACK_INTERFACE);
+
+        let requests = server.await.unwrap();
+        let name = format!("%22network_interface%22%3A%22{LOOPBACK_INTERFACE}%22");
+        assert!(requests[2].contains("setPreferences") && requests[2].contains(&name));
     }
 }
@@ -1,4 +1,10 @@
+use crate::error::{classify_error, ExitCode};
 use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::error;
 
 #[derive(Serialize, Default, Debug, Clone)]
 pub struct JsonReport {
@@ -10,6 +16,133 @@ pub struct JsonReport {
     pub note: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub error: Option<String>,
+    /// `config`, `transient`, `unsupported` or `unreachable`, following the
+    /// exit code, so scripts can tell whether to retry without parsing
+    /// `error`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error_kind: Option<String>,
+    /// Interface applied by `--apply-interface-only`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub binding: Option<String>,
+    /// Public address the port mapping applies to, as reported by the PCP or
+    /// NAT-PMP gateway (or queried when `portmap.external_ip` is enabled).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub external_ip: Option<String>,
+    /// Result of the `verify_reachable` external port check.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<bool>,
+    /// Timing and mapping details, only with `--verbose-json`.
+    #[serde(flatten)]
+    pub diagnostics: Option<Diagnostics>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub instances: Vec<InstanceReport>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hooks: Vec<HookReport>,
+    /// qBittorrent listens only on the IP family the port was not mapped for.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub family_mismatch: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub dry_run: bool,
+    /// Daemon cycle the status line belongs to, counting from 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cycle: Option<u64>,
+    /// Set on daemon-mode status lines only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+}
+
+/// Fields `--verbose-json` adds to a report. Unknown values are left out.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct Diagnostics {
+    /// Gateway the port mapping was requested from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gateway: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub internal_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Time the gateway took to grant the mapping.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mapping_duration_ms: Option<u64>,
+    /// Time spent setting and verifying the port in the torrent clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qbit_duration_ms: Option<u64>,
+}
+
+/// Per-instance result, only populated when several qBittorrent instances
+/// are configured.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct InstanceReport {
+    pub name: String,
+    pub applied: bool,
+    pub verified: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detected_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reachable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Outcome of one post-change hook.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct HookReport {
+    pub hook: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Port settings of one instance in `qb-port-sync status --json`.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct InstanceStatus {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listen_port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub random_port: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upnp: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Daemon totals emitted as the last status line on shutdown.
+#[derive(Serialize, Debug, Clone)]
+pub struct SummaryReport {
+    /// Always `summary`, telling this line apart from the status lines.
+    pub kind: &'static str,
+    pub cycles: u64,
+    pub updates: u64,
+    pub failures: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_port: Option<u16>,
+    pub uptime_secs: u64,
+    pub timestamp: String,
+}
+
+/// Structured output of `qb-port-sync status --json`.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct StatusReport {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub instances: Vec<InstanceStatus>,
+}
+
+/// One pass/fail line of `qb-port-sync validate`.
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckReport {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+}
+
+/// Structured output of `qb-port-sync validate --json`.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct ValidationReport {
+    pub ok: bool,
+    pub checks: Vec<CheckReport>,
 }
 
 impl JsonReport {
@@ -21,10 +154,221 @@ impl JsonReport {
             verified: false,
             note: String::new(),
             error: None,
+            error_kind: None,
+            binding: None,
+            external_ip: None,
+            reachable: None,
+            diagnostics: None,
+            instances: Vec::new(),
+            hooks: Vec::new(),
+            family_mismatch: false,
+            dry_run: false,
+            cycle: None,
+            timestamp: None,
         }
     }
 
+    /// Records `err` as the report's error, classified like the exit code.
+    pub fn set_error(&mut self, err: &anyhow::Error) {
+        self.set_error_message(format!("{err:#}"), classify_error(err));
+    }
+
+    /// Records an error that has no `anyhow::Error`, under the kind of `code`.
+    pub fn set_error_message(&mut self, message: impl Into<String>, code: ExitCode) {
+        self.error = Some(message.into());
+        self.error_kind = code.error_kind().map(str::to_string);
+    }
+
     pub fn line(&self) -> serde_json::Result<String> {
         serde_json::to_string(self)
     }
+
+    /// Writes the report line to `path` with [`write_atomic`].
+    pub fn write_to(&self, path: &Path) -> io::Result<()> {
+        let line = self.line().map_err(io::Error::other)?;
+        write_atomic(path, &format!("{line}\n"))
+    }
+
+    /// Appends the report line to `path` with [`append_line`].
+    pub fn append_to(&self, path: &Path) -> io::Result<()> {
+        let line = self.line().map_err(io::Error::other)?;
+        append_line(path, &line)
+    }
+}
+
+/// Appends `line` and a newline to `path`, creating it if needed. The line
+/// goes out in one write so concurrent readers see whole lines.
+pub fn append_line(path: &Path, line: &str) -> io::Result<()> {
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?
+        .write_all(format!("{line}\n").as_bytes())
+}
+
+/// Replaces `path` via a temporary sibling and a rename so readers never see
+/// a partially written file.
+pub fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = std::ffi::OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+    fs::write(&tmp_path, contents)?;
+    fs::rename(&tmp_path, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp_path);
+    })
+}
+
+/// Formats a timestamp as RFC 3339 in UTC with second precision.
+pub fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    let (hour, minute, second) = (rem / 3600, (rem % 3600) / 60, rem % 60);
+
+    // Civil-from-days conversion (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!("{year:04}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+/// Serializes `report` for stdout. A serialization failure is logged and
+/// replaced by a line carrying only an `error` field, so automation sees
+/// what went wrong instead of an empty object.
+pub fn json_line(report: &impl Serialize) -> String {
+    serde_json::to_string(report).unwrap_or_else(|err| {
+        error!("failed to serialize JSON report: {err}");
+        serde_json::json!({ "error": format!("failed to serialize JSON report: {err}") })
+            .to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_line, rfc3339, Diagnostics, JsonReport, SummaryReport};
+    use crate::error::{ConfigError, PortMapError};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn errors_carry_their_kind() {
+        let mut report = JsonReport::new("file");
+        report.set_error(&ConfigError::MissingConfig.into());
+        assert_eq!(report.error_kind.as_deref(), Some("config"));
+
+        report.set_error(&PortMapError::PcpNotSupported("no".into()).into());
+        assert_eq!(report.error_kind.as_deref(), Some("unsupported"));
+        let line: serde_json::Value = serde_json::from_str(&report.line().unwrap()).unwrap();
+        assert_eq!(line["error_kind"], "unsupported");
+        assert_eq!(line["error"], "pcp not supported: no");
+
+        report.set_error(&anyhow::anyhow!("connection refused"));
+        assert_eq!(report.error_kind.as_deref(), Some("transient"));
+    }
+
+    #[test]
+    fn diagnostics_are_flattened_only_when_present() {
+        let mut report = JsonReport::new("pcp");
+        report.detected_port = Some(51820);
+        let compact = report.line().unwrap();
+
+        report.diagnostics = Some(Diagnostics {
+            gateway: Some("10.2.0.1".to_string()),
+            ttl_secs: Some(60),
+            mapping_duration_ms: Some(12),
+            ..Diagnostics::default()
+        });
+        let line: serde_json::Value = serde_json::from_str(&report.line().unwrap()).unwrap();
+        assert_eq!(line["gateway"], "10.2.0.1");
+        assert_eq!(line["ttl_secs"], 60);
+        assert_eq!(line["mapping_duration_ms"], 12);
+        assert!(line.get("internal_port").is_none());
+        assert!(line.get("diagnostics").is_none());
+
+        report.diagnostics = None;
+        assert_eq!(report.line().unwrap(), compact);
+    }
+
+    #[test]
+    fn write_to_replaces_file_with_one_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        std::fs::write(&path, "stale contents that are longer than the report\n").unwrap();
+
+        let mut report = JsonReport::new("file");
+        report.detected_port = Some(51820);
+        report.write_to(&path).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written, format!("{}\n", report.line().unwrap()));
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn summary_line_is_marked_by_its_kind() {
+        let summary = SummaryReport {
+            kind: "summary",
+            cycles: 3,
+            updates: 2,
+            failures: 1,
+            last_port: None,
+            uptime_secs: 60,
+            timestamp: "2024-02-29T12:34:56Z".to_string(),
+        };
+        let line: serde_json::Value = serde_json::from_str(&json_line(&summary)).unwrap();
+        assert_eq!(line["kind"], "summary");
+        assert_eq!(line["failures"], 1);
+        assert!(line.get("last_port").is_none());
+    }
+
+    #[test]
+    fn append_to_adds_one_line_per_report() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.ndjson");
+
+        let first = JsonReport::new("natpmp");
+        let mut second = JsonReport::new("natpmp");
+        second.detected_port = Some(51820);
+        first.append_to(&path).unwrap();
+        second.append_to(&path).unwrap();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            written,
+            format!("{}\n{}\n", first.line().unwrap(), second.line().unwrap())
+        );
+    }
+
+    #[test]
+    fn serialization_failure_yields_error_line() {
+        // serde_json cannot serialize maps with non-string keys.
+        let unserializable = std::collections::HashMap::from([((1u8, 2u8), 3u8)]);
+        let line: serde_json::Value = serde_json::from_str(&json_line(&unserializable)).unwrap();
+        assert!(line["error"]
+            .as_str()
+            .unwrap()
+            .starts_with("failed to serialize JSON report"));
+
+        let report = JsonReport::new("file");
+        assert_eq!(json_line(&report), report.line().unwrap());
+    }
+
+    #[test]
+    fn formats_rfc3339_utc() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_210_096);
+        assert_eq!(rfc3339(leap_day), "2024-02-29T12:34:56Z");
+    }
 }
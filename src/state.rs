@@ -0,0 +1,92 @@
+use crate::portmap::AddressFamily;
+use crate::report::{rfc3339, write_atomic};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+use tracing::{debug, warn};
+
+/// Last port applied and verified on every instance, persisted in
+/// `daemon.state_file` so a restarted daemon can restore it right away.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PortState {
+    pub port: u16,
+    /// Family the port was mapped for; files written before it was recorded
+    /// load as IPv4.
+    #[serde(default)]
+    pub family: AddressFamily,
+    pub updated_at: String,
+}
+
+/// Reads the state file. A missing or unreadable file yields `None` so the
+/// daemon simply starts without a last-known-good port.
+pub fn load_state(path: &Path) -> Option<PortState> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!("no state file at {}", path.display());
+            return None;
+        }
+        Err(err) => {
+            warn!("ignoring unreadable state file {}: {err}", path.display());
+            return None;
+        }
+    };
+    match serde_json::from_str::<PortState>(&contents) {
+        Ok(state) if state.port != 0 => Some(state),
+        Ok(_) => {
+            warn!("ignoring state file {} with port 0", path.display());
+            None
+        }
+        Err(err) => {
+            warn!("ignoring corrupt state file {}: {err}", path.display());
+            None
+        }
+    }
+}
+
+/// Records `port` as the last-known-good port, replacing the file atomically.
+pub fn save_state(path: &Path, port: u16, family: AddressFamily) -> io::Result<()> {
+    let state = PortState {
+        port,
+        family,
+        updated_at: rfc3339(SystemTime::now()),
+    };
+    let json = serde_json::to_string(&state).map_err(io::Error::other)?;
+    write_atomic(path, &format!("{json}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_state, save_state};
+    use crate::portmap::AddressFamily;
+
+    #[test]
+    fn saved_state_loads_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        save_state(&path, 51820, AddressFamily::V6).unwrap();
+        let state = load_state(&path).unwrap();
+        assert_eq!(state.port, 51820);
+        assert_eq!(state.family, AddressFamily::V6);
+    }
+
+    #[test]
+    fn state_without_family_loads_as_ipv4() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "{\"port\": 51820, \"updated_at\": \"\"}").unwrap();
+        assert_eq!(load_state(&path).unwrap().family, AddressFamily::V4);
+    }
+
+    #[test]
+    fn missing_or_corrupt_state_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        assert_eq!(load_state(&path), None);
+        std::fs::write(&path, "{\"port\": 51").unwrap();
+        assert_eq!(load_state(&path), None);
+        std::fs::write(&path, "{\"port\": 0, \"updated_at\": \"\"}").unwrap();
+        assert_eq!(load_state(&path), None);
+    }
+}
@@ -0,0 +1,122 @@
+use std::future;
+use std::time::Duration;
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+#[cfg(all(target_os = "linux", feature = "sd-notify"))]
+use {
+    std::os::linux::net::SocketAddrExt,
+    std::os::unix::net::{SocketAddr, UnixDatagram},
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::{env, io},
+    tracing::{debug, info, warn},
+};
+
+/// Tells systemd (`Type=notify`) the service is up. Only the first call sends
+/// anything, so it can be made after every successful sync. Like every
+/// notification, it is a no-op unless built with `sd-notify` on Linux and
+/// started with `NOTIFY_SOCKET` set.
+pub fn notify_ready() {
+    #[cfg(all(target_os = "linux", feature = "sd-notify"))]
+    {
+        static READY: AtomicBool = AtomicBool::new(false);
+        if !READY.swap(true, Ordering::Relaxed) && notify("READY=1") {
+            info!("notified systemd that the service is ready");
+        }
+    }
+}
+
+/// Resets the systemd watchdog timer.
+pub fn notify_watchdog() {
+    #[cfg(all(target_os = "linux", feature = "sd-notify"))]
+    if notify("WATCHDOG=1") {
+        debug!("sent systemd watchdog ping");
+    }
+}
+
+/// Timer for watchdog pings at half of `WatchdogSec`, or one that never
+/// fires when the watchdog is not enabled for this process.
+#[derive(Debug)]
+pub struct Watchdog {
+    interval: Option<Interval>,
+}
+
+impl Watchdog {
+    pub fn from_env() -> Self {
+        let interval = watchdog_period().map(|period| {
+            let mut interval = time::interval(period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        Watchdog { interval }
+    }
+
+    /// Completes when the next ping is due.
+    pub async fn tick(&mut self) {
+        match self.interval.as_mut() {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => future::pending().await,
+        }
+    }
+}
+
+/// Half of `WATCHDOG_USEC`, provided `WATCHDOG_PID` (when set) is this process.
+#[cfg(all(target_os = "linux", feature = "sd-notify"))]
+fn watchdog_period() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.trim().parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = env::var("WATCHDOG_USEC").ok()?.trim().parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
+
+#[cfg(not(all(target_os = "linux", feature = "sd-notify")))]
+fn watchdog_period() -> Option<Duration> {
+    None
+}
+
+/// Sends `state` to `NOTIFY_SOCKET`. Returns whether it was delivered.
+#[cfg(all(target_os = "linux", feature = "sd-notify"))]
+fn notify(state: &str) -> bool {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    let send = || -> io::Result<usize> {
+        let socket = UnixDatagram::unbound()?;
+        let bytes = socket_path.as_encoded_bytes();
+        // A leading '@' names a socket in the abstract namespace.
+        let addr = match bytes.strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&socket_path)?,
+        };
+        socket.send_to_addr(state.as_bytes(), &addr)
+    };
+    match send() {
+        Ok(_) => true,
+        Err(err) => {
+            warn!("failed to notify systemd ({state}): {err}");
+            false
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux", feature = "sd-notify"))]
+mod tests {
+    use super::notify;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn notify_sends_state_to_notify_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify.sock");
+        let server = UnixDatagram::bind(&path).unwrap();
+        std::env::set_var("NOTIFY_SOCKET", &path);
+        assert!(notify("WATCHDOG=1"));
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+    }
+}
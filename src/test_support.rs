@@ -0,0 +1,63 @@
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// A canned answer: status line (e.g. `"200 OK"`), extra header lines and
+/// body. `Content-Length` and `Connection: close` are added.
+pub type Response = (&'static str, &'static [&'static str], &'static str);
+
+/// JSON content type for [`Response`] headers.
+pub const JSON: &[&str] = &["Content-Type: application/json"];
+
+/// Answers one connection per entry of `responses`, in order, then stops
+/// listening. The task resolves to the raw requests received.
+pub async fn serve(responses: Vec<Response>) -> (SocketAddr, JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let mut requests = Vec::new();
+        for (status, headers, body) in responses {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            requests.push(read_request(&mut stream).await);
+            let mut response = format!("HTTP/1.1 {status}\r\n");
+            for header in headers {
+                response.push_str(header);
+                response.push_str("\r\n");
+            }
+            response.push_str(&format!(
+                "Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            ));
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+        requests
+    });
+    (addr, server)
+}
+
+/// Reads the request head and as much body as its `Content-Length` announces.
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = stream.read(&mut buf).await.unwrap_or(0);
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+        let text = String::from_utf8_lossy(&request);
+        if let Some(head_end) = text.find("\r\n\r\n") {
+            let length = text[..head_end]
+                .lines()
+                .filter_map(|line| line.split_once(':'))
+                .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+                .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+                .unwrap_or(0);
+            if request.len() >= head_end + 4 + length {
+                break;
+            }
+        }
+    }
+    String::from_utf8_lossy(&request).into_owned()
+}
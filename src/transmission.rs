@@ -0,0 +1,326 @@
+use crate::client::TorrentClient;
+use crate::error::{Result, TransmissionError, UnsupportedError};
+use crate::qbit::{InterfaceBinding, PortStatus, PortUpdateResult};
+use async_trait::async_trait;
+use reqwest::{Client, Response, StatusCode, Url};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Header carrying Transmission's anti-CSRF session token.
+const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+
+/// Session fields read back after an update.
+const PORT_FIELDS: [&str; 3] = [
+    "peer-port",
+    "peer-port-random-on-start",
+    "port-forwarding-enabled",
+];
+
+/// Client for Transmission's RPC endpoint (`/transmission/rpc`).
+pub struct TransmissionClient {
+    client: Client,
+    endpoint: Url,
+    credentials: Option<(String, String)>,
+    session_id: Mutex<Option<String>>,
+    dry_run: bool,
+}
+
+impl TransmissionClient {
+    /// Builds a client for the RPC endpoint at `endpoint`. `timeout` bounds
+    /// each request; `None` disables the timeout.
+    pub fn new(endpoint: Url, timeout: Option<Duration>) -> Result<Self> {
+        let mut builder = Client::builder().user_agent("qb-port-sync");
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+        Ok(Self {
+            client: builder.build()?,
+            endpoint,
+            credentials: None,
+            session_id: Mutex::new(None),
+            dry_run: false,
+        })
+    }
+
+    /// Basic-auth credentials sent with every request.
+    pub fn with_credentials(mut self, credentials: Option<(String, String)>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// In dry-run mode session updates are logged instead of submitted.
+    pub fn with_dry_run(mut self, enabled: bool) -> Self {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Obtains a session ID and checks the credentials with a `session-get`.
+    pub async fn connect(&self) -> Result<()> {
+        self.call("session-get", json!({ "fields": ["version"] }))
+            .await?;
+        info!("connected to Transmission RPC");
+        Ok(())
+    }
+
+    async fn call(&self, method: &str, arguments: Value) -> Result<Value> {
+        let body = json!({ "method": method, "arguments": arguments });
+        let response = self.post_with_session(&body).await?;
+        let status = response.status();
+        if status == StatusCode::UNAUTHORIZED {
+            return Err(TransmissionError::Auth.into());
+        }
+        if !status.is_success() {
+            let message = response.text().await.unwrap_or_default();
+            return Err(TransmissionError::Rpc {
+                method: method.to_string(),
+                result: format!("{status} {message}"),
+            }
+            .into());
+        }
+        rpc_arguments(method, response.json::<Value>().await?)
+    }
+
+    /// Sends `body`, repeating it once with the session ID Transmission
+    /// hands out in a 409 response when the current one is missing or stale.
+    async fn post_with_session(&self, body: &Value) -> Result<Response> {
+        let response = self.post(body).await?;
+        if response.status() != StatusCode::CONFLICT {
+            return Ok(response);
+        }
+        let session_id = session_id(&response).ok_or(TransmissionError::MissingSessionId)?;
+        debug!("Transmission issued a new session id");
+        *self.session_id.lock().unwrap_or_else(|e| e.into_inner()) = Some(session_id);
+        self.post(body).await
+    }
+
+    async fn post(&self, body: &Value) -> Result<Response> {
+        let mut request = self.client.post(self.endpoint.clone()).json(body);
+        let session_id = self
+            .session_id
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone();
+        if let Some(session_id) = session_id {
+            request = request.header(SESSION_ID_HEADER, session_id);
+        }
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        Ok(request.send().await?)
+    }
+
+    async fn read_port_status(&self) -> Result<PortStatus> {
+        let session = self
+            .call("session-get", json!({ "fields": PORT_FIELDS }))
+            .await?;
+        port_status(&session)
+    }
+}
+
+#[async_trait]
+impl TorrentClient for TransmissionClient {
+    async fn set_listen_port(
+        &self,
+        port: u16,
+        bind_interface: Option<&str>,
+    ) -> Result<PortUpdateResult> {
+        if bind_interface.is_some() {
+            return Err(
+                UnsupportedError::new("Transmission does not support bind_interface").into(),
+            );
+        }
+        let update = json!({
+            "peer-port": port,
+            "peer-port-random-on-start": false,
+            "port-forwarding-enabled": false,
+        });
+        if self.dry_run {
+            info!("dry run: would set Transmission session {}", update);
+            return Ok(PortUpdateResult {
+                detected_port: port,
+                previous_port: None,
+                verified: false,
+                random_port: None,
+                upnp: None,
+                interface_id_missing: false,
+                interface_absent: false,
+                listen_address: None,
+                reachable: None,
+            });
+        }
+
+        // Read first, so a port another program set since the last update
+        // shows up.
+        let previous_port = self.read_port_status().await?.listen_port;
+        self.call("session-set", update).await?;
+        let status = self.read_port_status().await?;
+        let verified = status.listen_port == port;
+        if verified {
+            info!("Transmission peer port verified at {}", status.listen_port);
+        } else {
+            warn!(
+                "Transmission peer port mismatch after update: expected {}, reported {}",
+                port, status.listen_port
+            );
+        }
+        Ok(PortUpdateResult {
+            detected_port: status.listen_port,
+            previous_port: Some(previous_port),
+            verified,
+            random_port: status.random_port,
+            upnp: status.upnp,
+            interface_id_missing: false,
+            interface_absent: false,
+            listen_address: None,
+            reachable: None,
+        })
+    }
+
+    async fn get_port_status(&self) -> Result<PortStatus> {
+        self.read_port_status().await
+    }
+
+    async fn apply_interface_binding(&self, _interface: &str) -> Result<InterfaceBinding> {
+        Err(UnsupportedError::new("Transmission does not support binding to an interface").into())
+    }
+
+    async fn apply_safe_binding(&self, _interface: &str) -> Result<String> {
+        Err(UnsupportedError::new("Transmission does not support binding to an interface").into())
+    }
+}
+
+fn session_id(response: &Response) -> Option<String> {
+    response
+        .headers()
+        .get(SESSION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Returns the `arguments` of a response whose `result` is `success`.
+fn rpc_arguments(method: &str, mut response: Value) -> Result<Value> {
+    match response.get("result").and_then(Value::as_str) {
+        Some("success") => Ok(response
+            .get_mut("arguments")
+            .map(Value::take)
+            .unwrap_or(Value::Null)),
+        other => Err(TransmissionError::Rpc {
+            method: method.to_string(),
+            result: other.unwrap_or("no result").to_string(),
+        }
+        .into()),
+    }
+}
+
+/// `port-forwarding-enabled` is Transmission's UPnP/NAT-PMP switch, so it is
+/// reported as `upnp`.
+fn port_status(session: &Value) -> Result<PortStatus> {
+    let listen_port = session
+        .get("peer-port")
+        .and_then(Value::as_u64)
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or_else(|| anyhow::anyhow!("Transmission session missing peer-port"))?;
+    Ok(PortStatus {
+        listen_port,
+        random_port: session
+            .get("peer-port-random-on-start")
+            .and_then(Value::as_bool),
+        upnp: session
+            .get("port-forwarding-enabled")
+            .and_then(Value::as_bool),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{port_status, rpc_arguments, TransmissionClient};
+    use crate::client::TorrentClient;
+    use crate::error::TransmissionError;
+    use crate::qbit::PortStatus;
+    use crate::test_support::serve;
+    use serde_json::json;
+
+    #[test]
+    fn rpc_arguments_requires_success() {
+        let ok = json!({ "result": "success", "arguments": { "peer-port": 51820 } });
+        assert_eq!(
+            rpc_arguments("session-get", ok).unwrap(),
+            json!({ "peer-port": 51820 })
+        );
+        let failed = json!({ "result": "invalid argument", "arguments": {} });
+        let err = rpc_arguments("session-set", failed).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Transmission session-set failed: invalid argument"
+        );
+    }
+
+    #[test]
+    fn port_status_maps_transmission_fields() {
+        let session = json!({
+            "peer-port": 51820,
+            "peer-port-random-on-start": false,
+            "port-forwarding-enabled": true
+        });
+        assert_eq!(
+            port_status(&session).unwrap(),
+            PortStatus {
+                listen_port: 51820,
+                random_port: Some(false),
+                upnp: Some(true),
+            }
+        );
+        assert!(port_status(&json!({})).is_err());
+    }
+
+    /// Transmission answers every request with its session ID.
+    const SESSION: &[&str] = &[
+        "X-Transmission-Session-Id: abc123",
+        "Content-Type: application/json",
+    ];
+
+    #[tokio::test]
+    async fn session_id_handshake_retries_after_409() {
+        // A 409 handing out a session ID, then the answers to the first
+        // `session-get`, `session-set` and the verifying `session-get`.
+        let session = r#"{"result":"success","arguments":{"peer-port":51820,"peer-port-random-on-start":false,"port-forwarding-enabled":false}}"#;
+        let (addr, server) = serve(vec![
+            ("409 Conflict", SESSION, ""),
+            ("200 OK", SESSION, session),
+            ("200 OK", SESSION, r#"{"result":"success","arguments":{}}"#),
+            ("200 OK", SESSION, session),
+        ])
+        .await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/transmission/rpc")).unwrap();
+        let client = TransmissionClient::new(url, None).unwrap();
+        let update = client.set_listen_port(51820, None).await.unwrap();
+        assert!(update.verified);
+        assert_eq!(update.detected_port, 51820);
+
+        let requests = server.await.unwrap();
+        let with_session = |request: &String| {
+            request
+                .to_ascii_lowercase()
+                .contains("x-transmission-session-id: abc123")
+        };
+        assert!(!with_session(&requests[0]));
+        assert!(requests[1..].iter().all(with_session));
+        assert!(requests[2].contains("session-set"));
+    }
+
+    #[tokio::test]
+    async fn conflict_without_session_id_is_an_error() {
+        let (addr, _) = serve(vec![("409 Conflict", &[], "")]).await;
+
+        let url = reqwest::Url::parse(&format!("http://{addr}/transmission/rpc")).unwrap();
+        let client = TransmissionClient::new(url, None).unwrap();
+        let err = client.connect().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<TransmissionError>(),
+            Some(TransmissionError::MissingSessionId)
+        ));
+    }
+}
@@ -1,21 +1,41 @@
-use crate::{config::Config, error::Result};
+use crate::{
+    config::{Config, PortFileFormat},
+    error::{ConfigError, Result},
+};
 use anyhow::Context;
 use notify::{
     Config as NotifyConfig, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
 };
+use std::io::Read;
 use std::path::{Path, PathBuf};
 use std::time::Duration;
-use tokio::{fs, sync::mpsc, time};
-use tracing::{debug, warn};
+use tokio::{sync::mpsc, task, time};
+use tracing::{debug, info, warn};
 
 pub fn read_forwarded_port_once(config: &Config) -> Result<u16> {
     let path = config
         .resolved_forwarded_port_path()
         .context("forwarded port path not configured")?;
-    read_port_sync(&path)
+    read_port_sync(
+        &path,
+        config.protonvpn.port_file_format,
+        config.protonvpn.max_file_bytes,
+    )
 }
 
-pub async fn watch_forwarded_port<F>(path: PathBuf, on_change: F) -> Result<()>
+/// Reads per change notification; the second only when the first read
+/// found a half-written file.
+const EVENT_READ_ATTEMPTS: u32 = 2;
+
+pub async fn watch_forwarded_port<F>(
+    path: PathBuf,
+    format: PortFileFormat,
+    max_bytes: u64,
+    apply_on_start: bool,
+    initial_read_timeout: Duration,
+    debounce: Duration,
+    on_change: F,
+) -> Result<()>
 where
     F: Fn(u16) + Send + 'static,
 {
@@ -35,15 +55,9 @@ where
     )?;
     watcher.watch(&target_dir, RecursiveMode::NonRecursive)?;
 
-    let mut last_port: Option<u16> = None;
-    if path.exists() {
-        match read_port_sync(&path) {
-            Ok(port) => {
-                on_change(port);
-                last_port = Some(port);
-            }
-            Err(err) => debug!("failed to read initial forwarded port: {err:?}"),
-        }
+    let mut last_port = read_initial_port(&path, format, max_bytes, initial_read_timeout).await;
+    if let Some(port) = last_port {
+        report_initial_port(port, apply_on_start, &on_change);
     }
 
     while let Some(event) = rx.recv().await {
@@ -57,7 +71,7 @@ where
                     EventKind::Remove(_) | EventKind::Modify(_) | EventKind::Create(_)
                 ) || event.paths.is_empty()
                 {
-                    if let Some(port) = handle_event(&path).await {
+                    if let Some(port) = handle_event(&path, format, max_bytes, debounce).await {
                         if last_port != Some(port) {
                             debug!("forwarded port file update detected: {:?}", event.kind);
                             on_change(port);
@@ -73,29 +87,194 @@ where
     Ok(())
 }
 
-pub fn parse_port(contents: &str) -> Result<u16> {
+/// Reads the existing forwarded-port file on the blocking pool so a hung
+/// filesystem cannot stop the watcher from arming. Gives up after `timeout`;
+/// a stuck read is left to finish in the background.
+async fn read_initial_port(
+    path: &Path,
+    format: PortFileFormat,
+    max_bytes: u64,
+    timeout: Duration,
+) -> Option<u16> {
+    let read_path = path.to_path_buf();
+    let read = task::spawn_blocking(move || {
+        if !read_path.exists() {
+            return None;
+        }
+        match read_port_sync(&read_path, format, max_bytes) {
+            Ok(port) => Some(port),
+            Err(err) => {
+                log_read_error("failed to read initial forwarded port", &err);
+                None
+            }
+        }
+    });
+    match time::timeout(timeout, read).await {
+        Ok(Ok(port)) => port,
+        Ok(Err(err)) => {
+            warn!("initial forwarded port read panicked: {err}");
+            None
+        }
+        Err(_) => {
+            warn!(
+                "initial read of {:?} did not finish within {:?}; watching for changes anyway",
+                path, timeout
+            );
+            None
+        }
+    }
+}
+
+/// Outcome of [`probe_watch`] for the forwarded-port file.
+#[derive(Debug)]
+pub enum WatchProbe {
+    /// A watcher was registered on the parent directory.
+    Watchable,
+    /// The parent directory does not exist (yet).
+    MissingParent(PathBuf),
+    /// The parent exists but the filesystem rejected the watch.
+    Unwatchable(String),
+}
+
+/// Registers a `RecommendedWatcher` on the parent of `path` and drops it,
+/// to catch filesystems (NFS, some overlayfs setups) that never deliver
+/// change events.
+pub fn probe_watch(path: &Path) -> WatchProbe {
+    let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) else {
+        return WatchProbe::MissingParent(PathBuf::new());
+    };
+    if !parent.is_dir() {
+        return WatchProbe::MissingParent(parent.to_path_buf());
+    }
+    let watcher = RecommendedWatcher::new(|_: notify::Result<Event>| {}, NotifyConfig::default());
+    match watcher.and_then(|mut watcher| watcher.watch(parent, RecursiveMode::NonRecursive)) {
+        Ok(()) => WatchProbe::Watchable,
+        Err(err) => WatchProbe::Unwatchable(err.to_string()),
+    }
+}
+
+/// Re-reads `path` every `interval` and calls `on_change` whenever the parsed
+/// port differs from the last one seen. Used where filesystem notifications
+/// never arrive (some network filesystems and containers).
+pub async fn poll_forwarded_port<F>(
+    path: PathBuf,
+    format: PortFileFormat,
+    max_bytes: u64,
+    apply_on_start: bool,
+    interval: Duration,
+    on_change: F,
+) -> Result<()>
+where
+    F: Fn(u16) + Send + 'static,
+{
+    let mut ticker = time::interval(interval);
+    ticker.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+    let mut last_port: Option<u16> = None;
+    let mut first_read = true;
+    loop {
+        ticker.tick().await;
+        match read_port_sync(&path, format, max_bytes) {
+            Ok(port) if first_read => {
+                report_initial_port(port, apply_on_start, &on_change);
+                last_port = Some(port);
+            }
+            Ok(port) if last_port != Some(port) => {
+                debug!("forwarded port poll found port {port}");
+                on_change(port);
+                last_port = Some(port);
+            }
+            Ok(_) => {}
+            Err(err) => log_read_error("failed to poll forwarded port", &err),
+        }
+        first_read = false;
+    }
+}
+
+/// Hands the port found at startup to `on_change`, or with `apply_on_start`
+/// off only keeps it as the baseline so the next change is applied.
+pub(crate) fn report_initial_port<F: Fn(u16)>(port: u16, apply_on_start: bool, on_change: &F) {
+    if apply_on_start {
+        on_change(port);
+    } else {
+        info!("forwarded port {port} present at startup; waiting for it to change (apply_on_start = false)");
+    }
+}
+
+pub fn parse_port_as(contents: &str, format: PortFileFormat) -> Result<u16> {
     let trimmed = contents.trim();
-    let port: u16 = trimmed
+    match format {
+        PortFileFormat::Plain => parse_plain_port(trimmed),
+        PortFileFormat::Json => parse_json_port(trimmed),
+        PortFileFormat::Auto => parse_plain_port(trimmed)
+            .or_else(|plain_err| parse_json_port(trimmed).map_err(|_| plain_err)),
+    }
+}
+
+/// Accepts a bare port, optionally in `host:port` form (the last
+/// colon-separated token is used) and followed by a `# comment`.
+fn parse_plain_port(trimmed: &str) -> Result<u16> {
+    let value = trimmed.split('#').next().unwrap_or_default();
+    let value = value.rsplit(':').next().unwrap_or_default().trim();
+    let port: u16 = value
         .parse()
         .map_err(|err| anyhow::anyhow!("invalid forwarded port value {trimmed:?}: {err}"))?;
     Ok(port)
 }
 
-async fn handle_event(path: &Path) -> Option<u16> {
-    time::sleep(Duration::from_millis(250)).await;
-    match fs::read_to_string(path).await {
-        Ok(contents) => match parse_port(&contents) {
-            Ok(port) => Some(port),
+fn parse_json_port(trimmed: &str) -> Result<u16> {
+    let value: serde_json::Value = serde_json::from_str(trimmed)
+        .map_err(|err| anyhow::anyhow!("invalid forwarded port JSON {trimmed:?}: {err}"))?;
+    let port = value
+        .get("port")
+        .and_then(serde_json::Value::as_u64)
+        .ok_or_else(|| anyhow::anyhow!("forwarded port JSON has no numeric \"port\" field"))?;
+    u16::try_from(port).map_err(|_| anyhow::anyhow!("forwarded port {port} is out of range"))
+}
+
+/// Reads after a change notification once `debounce` has passed. Contents
+/// that do not parse are read again after another `debounce`, for writers
+/// that truncate the file before writing the new port.
+async fn handle_event(
+    path: &Path,
+    format: PortFileFormat,
+    max_bytes: u64,
+    debounce: Duration,
+) -> Option<u16> {
+    for attempt in 1..=EVENT_READ_ATTEMPTS {
+        time::sleep(debounce).await;
+        let read_path = path.to_path_buf();
+        match task::spawn_blocking(move || read_port_file(&read_path, max_bytes)).await {
+            Ok(Ok(contents)) => match parse_port_as(&contents, format) {
+                Ok(port) => return Some(port),
+                Err(err) if attempt < EVENT_READ_ATTEMPTS => {
+                    debug!("forwarded port file not parseable yet, reading again: {err:?}");
+                }
+                Err(err) => debug!("failed to parse forwarded port contents: {err:?}"),
+            },
+            Ok(Err(err)) => {
+                log_read_error("failed to read forwarded port file", &err);
+                return None;
+            }
             Err(err) => {
-                debug!("failed to parse forwarded port contents: {err:?}");
-                None
+                warn!("forwarded port file read panicked: {err}");
+                return None;
             }
-        },
-        Err(err) => {
-            debug!("failed to read forwarded port file: {err:?}");
-            None
         }
     }
+    None
+}
+
+/// An oversized file means the path is misconfigured, so it is reported at
+/// warn level; other read errors are usually transient mid-write states.
+fn log_read_error(context: &str, err: &anyhow::Error) {
+    if matches!(
+        err.downcast_ref::<ConfigError>(),
+        Some(ConfigError::PortFileTooLarge(..))
+    ) {
+        warn!("{context}: {err:#}");
+    } else {
+        debug!("{context}: {err:?}");
+    }
 }
 
 fn is_relevant(event: &Event, watched_path: &Path) -> bool {
@@ -108,18 +287,47 @@ fn is_relevant(event: &Event, watched_path: &Path) -> bool {
         .any(|candidate| candidate == watched_path || candidate.parent() == watched_path.parent())
 }
 
-fn read_port_sync(path: &Path) -> Result<u16> {
-    let contents = std::fs::read_to_string(path)?;
-    parse_port(&contents)
+fn read_port_sync(path: &Path, format: PortFileFormat, max_bytes: u64) -> Result<u16> {
+    let contents = read_port_file(path, max_bytes)?;
+    parse_port_as(&contents, format)
+}
+
+/// Reads at most `max_bytes` of the forwarded-port file, failing with
+/// [`ConfigError::PortFileTooLarge`] instead of loading anything bigger.
+fn read_port_file(path: &Path, max_bytes: u64) -> Result<String> {
+    let mut contents = Vec::new();
+    std::fs::File::open(path)?
+        .take(max_bytes.saturating_add(1))
+        .read_to_end(&mut contents)?;
+    if contents.len() as u64 > max_bytes {
+        return Err(ConfigError::PortFileTooLarge(path.display().to_string(), max_bytes).into());
+    }
+    String::from_utf8(contents).context("forwarded port file is not valid UTF-8")
 }
 
 #[cfg(test)]
 mod tests {
-    use super::parse_port;
+    use super::{
+        handle_event, parse_port_as, poll_forwarded_port, probe_watch, read_initial_port,
+        read_port_sync, WatchProbe,
+    };
+    use crate::config::PortFileFormat;
+    use crate::error::{classify_error, ExitCode};
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    /// Parses a forwarded port written either as a bare integer or as JSON.
+    fn parse_port(contents: &str) -> crate::error::Result<u16> {
+        parse_port_as(contents, PortFileFormat::Auto)
+    }
 
     #[test]
     fn parses_valid_ports() {
         assert_eq!(parse_port("51820").unwrap(), 51820);
+        assert_eq!(parse_port("0.0.0.0:51820").unwrap(), 51820);
+        assert_eq!(parse_port("[::]:51820").unwrap(), 51820);
+        assert_eq!(parse_port("51820 # proton").unwrap(), 51820);
+        assert_eq!(parse_port("\n\n  51820  \n\n").unwrap(), 51820);
     }
 
     #[test]
@@ -127,5 +335,150 @@ mod tests {
         assert!(parse_port("").is_err());
         assert!(parse_port("not-a-port").is_err());
         assert!(parse_port("70000").is_err());
+        assert!(parse_port("0.0.0.0:70000").is_err());
+        assert!(parse_port("0.0.0.0:").is_err());
+        assert!(parse_port("# 51820").is_err());
+    }
+
+    #[test]
+    fn auto_accepts_plain_and_json() {
+        assert_eq!(parse_port("51820\n").unwrap(), 51820);
+        assert_eq!(parse_port(r#"{"port": 51820}"#).unwrap(), 51820);
+        assert!(parse_port(r#"{"port": 70000}"#).is_err());
+        assert!(parse_port(r#"{"status": "ok"}"#).is_err());
+    }
+
+    #[test]
+    fn forced_formats_reject_the_other_shape() {
+        let json = r#"{"port": 51820}"#;
+        assert_eq!(parse_port_as(json, PortFileFormat::Json).unwrap(), 51820);
+        assert!(parse_port_as(json, PortFileFormat::Plain).is_err());
+        assert_eq!(
+            parse_port_as("51820", PortFileFormat::Plain).unwrap(),
+            51820
+        );
+        assert!(parse_port_as("51820", PortFileFormat::Json).is_err());
+    }
+
+    #[test]
+    fn probe_reports_missing_parent_and_watchable_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            probe_watch(&dir.path().join("forwarded_port")),
+            WatchProbe::Watchable
+        ));
+        assert!(matches!(
+            probe_watch(&dir.path().join("missing/forwarded_port")),
+            WatchProbe::MissingParent(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn polling_reports_only_changed_ports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwarded_port");
+        std::fs::write(&path, "51820\n").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(poll_forwarded_port(
+            path.clone(),
+            PortFileFormat::Auto,
+            4096,
+            true,
+            Duration::from_millis(20),
+            move |port| {
+                let _ = tx.send(port);
+            },
+        ));
+
+        assert_eq!(rx.recv().await, Some(51820));
+        std::fs::write(&path, "51821\n").unwrap();
+        assert_eq!(rx.recv().await, Some(51821));
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(rx.try_recv().is_err());
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn polling_without_apply_on_start_waits_for_a_change() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwarded_port");
+        std::fs::write(&path, "51820\n").unwrap();
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let task = tokio::spawn(poll_forwarded_port(
+            path.clone(),
+            PortFileFormat::Auto,
+            4096,
+            false,
+            Duration::from_millis(20),
+            move |port| {
+                let _ = tx.send(port);
+            },
+        ));
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(rx.try_recv().is_err());
+        std::fs::write(&path, "51821\n").unwrap();
+        assert_eq!(rx.recv().await, Some(51821));
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn initial_read_returns_existing_port_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwarded_port");
+        let timeout = Duration::from_secs(1);
+        assert_eq!(
+            read_initial_port(&path, PortFileFormat::Auto, 4096, timeout).await,
+            None
+        );
+        std::fs::write(&path, "51820\n").unwrap();
+        assert_eq!(
+            read_initial_port(&path, PortFileFormat::Auto, 4096, timeout).await,
+            Some(51820)
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated_port_file_is_read_again_after_debounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwarded_port");
+        std::fs::write(&path, "").unwrap();
+        let debounce = Duration::from_millis(100);
+
+        let writer_path = path.clone();
+        let writer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            std::fs::write(&writer_path, "51820\n").unwrap();
+        });
+        assert_eq!(
+            handle_event(&path, PortFileFormat::Auto, 4096, debounce).await,
+            Some(51820)
+        );
+        writer.await.unwrap();
+
+        std::fs::write(&path, "garbage\n").unwrap();
+        assert_eq!(
+            handle_event(&path, PortFileFormat::Auto, 4096, debounce).await,
+            None
+        );
+    }
+
+    #[test]
+    fn oversized_port_file_is_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("forwarded_port");
+        std::fs::write(&path, format!("51820\n{}", "log line\n".repeat(1000))).unwrap();
+
+        let err = read_port_sync(&path, PortFileFormat::Auto, 4096).unwrap_err();
+        assert!(format!("{err:#}").contains("larger than 4096 bytes"));
+        assert_eq!(classify_error(&err), ExitCode::Config);
+
+        std::fs::write(&path, "51820\n").unwrap();
+        assert_eq!(
+            read_port_sync(&path, PortFileFormat::Auto, 6).unwrap(),
+            51820
+        );
     }
 }
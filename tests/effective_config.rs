@@ -0,0 +1,561 @@
+use qb_port_sync::config::{ClientKind, HttpVersion, PortProtocol, ValueSource};
+use qb_port_sync::error::Result;
+use qb_port_sync::portmap::resolve_gateways;
+use qb_port_sync::Config;
+use std::io::Write;
+use std::time::Duration;
+
+/// The qBittorrent instance a config gets unless `extra` brings its own
+/// client section.
+const QBITTORRENT: &str = r#"
+[qbittorrent]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+"#;
+
+/// Loads a config file made of `extra`. Bare keys at the top of `extra` go
+/// to the default qBittorrent instance, and empty `[protonvpn]` and
+/// `[portmap]` sections are added when `extra` leaves them out.
+fn load_with(extra: &str) -> Result<Config> {
+    let mut contents = String::new();
+    if !extra.contains("qbittorrent]") && !extra.contains("[client]") {
+        contents.push_str(QBITTORRENT);
+    }
+    contents.push_str(extra);
+    for section in ["[protonvpn]", "[portmap]"] {
+        if !extra.contains(section) {
+            contents.push_str(&format!("\n{section}\n"));
+        }
+    }
+    let mut file = tempfile::NamedTempFile::new().expect("create temp config");
+    file.write_all(contents.as_bytes())
+        .expect("write temp config");
+    Config::load(Some(file.path().to_path_buf()))
+}
+
+#[test]
+fn effective_config_tracks_value_sources() {
+    let mut config = load_with(
+        r#"
+[portmap]
+refresh_secs = 120
+gateway = ""
+"#,
+    )
+    .expect("load config");
+    assert_eq!(
+        config.value_source("qbittorrent.base_url"),
+        ValueSource::File
+    );
+    assert_eq!(
+        config.value_source("qbittorrent.password"),
+        ValueSource::File
+    );
+    assert_eq!(
+        config.value_source("portmap.refresh_secs"),
+        ValueSource::File
+    );
+    let long_ttl = Some(Duration::from_secs(7200));
+    assert_eq!(
+        config.portmap_refresh_delay(long_ttl),
+        Duration::from_secs(3600)
+    );
+    assert_eq!(config.value_source("portmap.gateway"), ValueSource::Default);
+    assert_eq!(
+        config.value_source("portmap.protocol"),
+        ValueSource::Default
+    );
+
+    let rendered = config.render_effective();
+    assert!(rendered.contains("<redacted>"));
+    assert!(!rendered.contains("secret"));
+
+    config.override_refresh_secs(30);
+    assert_eq!(config.portmap.refresh_secs, 30);
+    assert_eq!(
+        config.value_source("portmap.refresh_secs"),
+        ValueSource::Cli
+    );
+    assert_eq!(
+        config.portmap_refresh_delay(long_ttl),
+        Duration::from_secs(30)
+    );
+    assert_eq!(
+        config.portmap_refresh_delay(Some(Duration::from_secs(40))),
+        Duration::from_secs(30)
+    );
+
+    assert_eq!(config.daemon.startup_delay_secs, 0);
+    config.override_startup_delay_secs(15);
+    assert_eq!(config.daemon.startup_delay_secs, 15);
+    assert_eq!(
+        config.value_source("daemon.startup_delay_secs"),
+        ValueSource::Cli
+    );
+
+    config.override_protocol(PortProtocol::UDP);
+    assert_eq!(config.portmap.protocol, PortProtocol::UDP);
+    assert_eq!(config.value_source("portmap.protocol"), ValueSource::Cli);
+}
+
+#[test]
+fn multiple_instances_render_indexed_keys() {
+    let config = load_with(
+        r#"
+[[qbittorrent]]
+name = "movies"
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "one"
+
+[[qbittorrent]]
+base_url = "http://127.0.0.1:8081"
+username = "admin"
+password = "two"
+"#,
+    )
+    .expect("load config");
+    assert_eq!(config.qbittorrent.len(), 2);
+    assert_eq!(config.qbittorrent[0].label(), "movies");
+    assert_eq!(config.qbittorrent[1].label(), "http://127.0.0.1:8081");
+    assert_eq!(
+        config.value_source("qbittorrent[1].base_url"),
+        ValueSource::File
+    );
+    assert_eq!(
+        config.value_source("qbittorrent[1].name"),
+        ValueSource::Default
+    );
+}
+
+#[test]
+fn base_url_falls_back_to_service_env() {
+    let service_env = r#"
+[qbittorrent]
+username = "admin"
+password = "secret"
+service_host_env = "QBPS_TEST_SVC_HOST"
+service_port_env = "QBPS_TEST_SVC_PORT"
+"#;
+
+    std::env::remove_var("QBPS_TEST_SVC_HOST");
+    std::env::remove_var("QBPS_TEST_SVC_PORT");
+    let config = load_with(service_env).expect("load config");
+    assert!(config.qbittorrent[0].resolved_base_url().is_err());
+
+    std::env::set_var("QBPS_TEST_SVC_HOST", "10.43.0.12");
+    std::env::set_var("QBPS_TEST_SVC_PORT", "8080");
+    let config = load_with(service_env).expect("load config");
+    let url = config.qbittorrent[0]
+        .resolved_base_url()
+        .expect("url from service env");
+    assert_eq!(url.as_str(), "http://10.43.0.12:8080/");
+    assert_eq!(
+        config.value_source("qbittorrent.base_url"),
+        ValueSource::Env
+    );
+
+    std::env::set_var("QBPS_TEST_SVC_PORT", "not-a-port");
+    assert!(config.qbittorrent[0].resolved_base_url().is_err());
+}
+
+#[test]
+fn qbittorrent_timeout_defaults_and_disables() {
+    let config = load_with(
+        r#"
+[[qbittorrent]]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+
+[[qbittorrent]]
+base_url = "http://127.0.0.1:8081"
+username = "admin"
+password = "secret"
+timeout_secs = 0
+"#,
+    )
+    .expect("load config");
+    assert_eq!(
+        config.qbittorrent[0].timeout(),
+        Some(std::time::Duration::from_secs(15))
+    );
+    assert_eq!(config.qbittorrent[1].timeout(), None);
+}
+
+#[test]
+fn http_version_defaults_to_auto() {
+    let config = load_with(
+        r#"
+[[qbittorrent]]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+
+[[qbittorrent]]
+base_url = "https://qb.example.com"
+username = "admin"
+password = "secret"
+http_version = "http1"
+"#,
+    )
+    .expect("load config");
+    assert_eq!(config.qbittorrent[0].http_version, HttpVersion::Auto);
+    assert_eq!(config.qbittorrent[1].http_version, HttpVersion::Http1);
+}
+
+#[test]
+fn also_set_keys_are_checked_against_allowlist() {
+    let config = load_with(r#"also_set_keys = ["dht_port"]"#).expect("load config");
+    assert_eq!(config.qbittorrent[0].also_set_keys, vec!["dht_port"]);
+
+    let err = load_with(r#"also_set_keys = ["web_ui_port"]"#).expect_err("reject key");
+    assert!(format!("{err:#}").contains("web_ui_port"));
+}
+
+#[test]
+fn metrics_namespace_is_validated_on_load() {
+    let config = load_with("[metrics]\nnamespace = \"acme_vpn\"").expect("load config");
+    assert_eq!(config.metrics.namespace, "acme_vpn");
+
+    let err = load_with("[metrics]\nnamespace = \"acme-vpn\"").expect_err("reject namespace");
+    assert!(format!("{err:#}").contains("acme-vpn"));
+}
+
+#[test]
+fn metrics_bind_address_must_be_an_ip() {
+    let config = load_with("[metrics]").expect("load config");
+    assert_eq!(config.metrics.bind_ip().unwrap().to_string(), "0.0.0.0");
+
+    let config = load_with("[metrics]\nbind_address = \"::1\"").expect("load config");
+    assert_eq!(config.metrics.bind_ip().unwrap().to_string(), "::1");
+
+    let err = load_with("[metrics]\nbind_address = \"localhost\"").expect_err("reject hostname");
+    assert!(format!("{err:#}").contains("localhost"));
+}
+
+#[test]
+fn ttl_refresh_delay_is_clamped() {
+    let config = load_with("").expect("load config");
+    let delay = |ttl| config.portmap_refresh_delay(Some(Duration::from_secs(ttl)));
+    // The floor never outlives the mapping itself.
+    assert_eq!(delay(4), Duration::from_secs(3));
+    assert_eq!(delay(100), Duration::from_secs(60));
+    assert_eq!(delay(60), Duration::from_secs(45));
+    assert_eq!(delay(600), Duration::from_secs(300));
+    assert_eq!(delay(86_400), Duration::from_secs(3600));
+    assert_eq!(config.portmap_refresh_delay(None), Duration::from_secs(300));
+
+    // A separate lease keeps refresh_secs as the cadence.
+    let leased =
+        load_with("[portmap]\nrefresh_secs = 120\nlease_secs = 86400").expect("load config");
+    assert_eq!(leased.portmap.lease_secs(), 86_400);
+    assert_eq!(
+        leased.portmap_refresh_delay(Some(Duration::from_secs(86_400))),
+        Duration::from_secs(120)
+    );
+    assert_eq!(
+        leased.portmap_refresh_delay(Some(Duration::from_secs(180))),
+        Duration::from_secs(90)
+    );
+
+    let backoff = |failures| config.portmap_error_delay(failures).as_secs();
+    assert_eq!(backoff(1), 300);
+    assert_eq!(backoff(2), 600);
+    assert_eq!(backoff(3), 1200);
+    assert_eq!(backoff(4), 1800);
+    assert_eq!(backoff(100), 1800);
+
+    let config = load_with("[portmap]\nmax_backoff_secs = 0").expect("load config");
+    assert_eq!(config.portmap_error_delay(5), Duration::from_secs(300));
+}
+
+#[test]
+fn gateway_accepts_a_string_or_a_list() {
+    let config = load_with("[portmap]\ngateway = \"10.2.0.1\"").expect("load config");
+    assert_eq!(config.portmap.gateway, ["10.2.0.1"]);
+
+    let config = load_with("[portmap]\ngateway = [\"10.2.0.1\", \" 10.96.0.1 \", \"\"]")
+        .expect("load config");
+    assert_eq!(config.portmap.gateway, ["10.2.0.1", "10.96.0.1"]);
+    let gateways = resolve_gateways(&config.portmap).expect("parse gateways");
+    assert_eq!(gateways.len(), 2);
+    assert!(config
+        .render_effective()
+        .contains(r#"["10.2.0.1", "10.96.0.1"]"#));
+
+    let config =
+        load_with("[portmap]\ngateway = [\"10.2.0.1\", \"vpn-gateway\"]").expect("load config");
+    let err = resolve_gateways(&config.portmap).expect_err("reject hostname");
+    assert!(format!("{err:#}").contains("vpn-gateway"));
+}
+
+#[test]
+fn resolved_config_redacts_the_password_and_loads_back() {
+    let config = load_with(
+        r#"
+[protonvpn]
+forwarded_port_path = "/tmp/forwarded_port"
+
+[portmap]
+gateway = "10.2.0.1"
+"#,
+    )
+    .expect("load config");
+    let rendered = toml::to_string_pretty(&config.resolved()).expect("serialize config");
+    assert!(rendered.contains(r#"password = "<redacted>""#));
+    assert!(!rendered.contains("secret"));
+
+    let reloaded = load_with(&rendered).expect("reload config");
+    assert_eq!(reloaded.qbittorrent[0].base_url, "http://127.0.0.1:8080/");
+    assert_eq!(reloaded.portmap.gateway, ["10.2.0.1"]);
+    assert_eq!(
+        reloaded.protonvpn.forwarded_port_path.as_deref(),
+        Some(std::path::Path::new("/tmp/forwarded_port"))
+    );
+}
+
+#[test]
+fn deluge_client_needs_its_section_and_no_interface_binding() {
+    let deluge = "[client]\nkind = \"deluge\"\n";
+
+    let err = load_with(deluge).expect_err("reject missing section");
+    assert!(format!("{err:#}").contains("[deluge]"));
+
+    let config =
+        load_with(&format!("{deluge}\n[deluge]\npassword = \"deluge\"")).expect("load config");
+    assert_eq!(config.client.kind, ClientKind::Deluge);
+    assert!(config.qbittorrent.is_empty());
+    let deluge_config = config.deluge.as_ref().expect("deluge section");
+    assert_eq!(
+        deluge_config.resolved_base_url().unwrap().as_str(),
+        "http://127.0.0.1:8112/"
+    );
+    assert!(config
+        .render_effective()
+        .lines()
+        .any(|line| line.starts_with("deluge.password") && line.contains("<redacted>")));
+
+    let err = load_with(&format!(
+        "{deluge}\n[deluge]\npassword = \"deluge\"\n\n[net]\nbind_interface = \"tun0\""
+    ))
+    .expect_err("reject bind_interface");
+    assert!(format!("{err:#}").contains("net.bind_interface"));
+}
+
+#[test]
+fn transmission_credentials_are_optional_but_paired() {
+    let transmission = "[client]\nkind = \"transmission\"\n\n[transmission]\n";
+
+    let config = load_with(transmission).expect("load config");
+    assert_eq!(config.client.kind, ClientKind::Transmission);
+    let section = config.transmission.as_ref().expect("transmission section");
+    assert_eq!(
+        section.resolved_base_url().unwrap().as_str(),
+        "http://127.0.0.1:9091/transmission/rpc"
+    );
+    assert_eq!(section.credentials().unwrap(), None);
+
+    let config = load_with(&format!("{transmission}username = \"admin\"")).expect("load config");
+    let err = config.transmission.unwrap().credentials().unwrap_err();
+    assert!(format!("{err:#}").contains("transmission password"));
+}
+
+#[test]
+fn interface_guard_requires_a_bind_interface() {
+    let err = load_with("only_if_interface_present = true").expect_err("reject guard");
+    assert!(format!("{err:#}").contains("net.bind_interface"));
+
+    let config = load_with("only_if_interface_present = true\n\n[net]\nbind_interface = \"tun0\"")
+        .expect("load config");
+    assert!(config.qbittorrent[0].only_if_interface_present);
+}
+
+#[test]
+fn extra_headers_are_validated_and_redacted() {
+    let with_headers = |headers: &str| {
+        load_with(&format!(
+            "user_agent = \"curl/8.0\"\n\n[qbittorrent.extra_headers]\n{headers}"
+        ))
+    };
+
+    let config = with_headers(r#"Authorization = "Basic cHJveHk6cHc=""#).expect("load config");
+    let headers = config.qbittorrent[0]
+        .extra_header_map()
+        .expect("header map");
+    assert_eq!(headers["authorization"], "Basic cHJveHk6cHc=");
+    assert_eq!(
+        config.qbittorrent[0].user_agent.as_deref(),
+        Some("curl/8.0")
+    );
+    let rendered = toml::to_string_pretty(&config.resolved()).expect("serialize config");
+    assert!(rendered.contains(r#"Authorization = "<redacted>""#));
+    assert!(!rendered.contains("cHJveHk6cHc="));
+
+    let err = with_headers(r#""X Bad" = "1""#).expect_err("reject name");
+    assert!(format!("{err:#}").contains("X Bad"));
+
+    let err = with_headers(r#"X-Token = "a\nb""#).expect_err("reject value");
+    assert!(format!("{err:#}").contains("X-Token"));
+
+    let err = with_headers(r#"Referer = "http://elsewhere/""#).expect_err("reject referer");
+    assert!(format!("{err:#}").contains("Referer"));
+}
+
+#[test]
+fn client_ip_must_match_the_gateway_family() {
+    let config = load_with("").expect("load config");
+    assert_eq!(config.portmap.client_ip_addr().unwrap(), None);
+
+    let config = load_with("[portmap]\ngateway = \"10.2.0.1\"\nclient_ip = \"10.2.0.2\"")
+        .expect("load config");
+    assert_eq!(
+        config
+            .portmap
+            .client_ip_addr()
+            .unwrap()
+            .unwrap()
+            .to_string(),
+        "10.2.0.2"
+    );
+
+    let err = load_with("[portmap]\nclient_ip = \"tun0\"").expect_err("reject name");
+    assert!(format!("{err:#}").contains("tun0"));
+
+    let err = load_with("[portmap]\ngateway = \"10.2.0.1\"\nclient_ip = \"fd00::2\"")
+        .expect_err("reject family");
+    assert!(format!("{err:#}").contains("address family"));
+}
+
+#[test]
+fn acceptable_range_fills_in_the_missing_bound() {
+    let config = load_with("[portmap]\nacceptable_range = { min = 1024 }").expect("load config");
+    assert_eq!(config.portmap.acceptable_range.min, 1024);
+    assert_eq!(config.portmap.acceptable_range.max, 65535);
+    assert_eq!(
+        config.value_source("portmap.acceptable_range"),
+        ValueSource::File
+    );
+
+    let err = load_with("[portmap]\nacceptable_range = { min = 0 }").expect_err("reject range");
+    assert!(format!("{err:#}").contains("acceptable_range"));
+}
+
+#[test]
+fn first_existing_forwarded_port_path_wins() {
+    let dir = tempfile::tempdir().expect("create temp dir");
+    let missing = dir.path().join("missing/forwarded_port");
+    let waiting = dir.path().join("forwarded_port");
+    let present = dir.path().join("present_port");
+
+    let load = |paths: &[&std::path::Path]| {
+        load_with(&format!(
+            "[protonvpn]\nforwarded_port_path = {:?}\nforwarded_port_paths = {:?}\n",
+            paths[0],
+            &paths[1..]
+        ))
+        .expect("load config")
+    };
+
+    // Nothing exists yet: the first candidate with an existing directory.
+    let config = load(&[&missing, &waiting, &present]);
+    assert_eq!(config.resolved_forwarded_port_path(), Some(waiting.clone()));
+    assert_eq!(
+        config.forwarded_port_candidates()[..3],
+        [missing.clone(), waiting.clone(), present.clone()]
+    );
+
+    std::fs::write(&present, "51820\n").expect("write port file");
+    let config = load(&[&missing, &waiting, &present]);
+    assert_eq!(config.resolved_forwarded_port_path(), Some(present.clone()));
+
+    // The singular field alone keeps working.
+    let config = load(&[&present]);
+    assert_eq!(config.resolved_forwarded_port_path(), Some(present));
+}
+
+#[test]
+fn health_check_url_follows_the_enabled_endpoint() {
+    let load = |extra: &str| load_with(extra).expect("load config");
+
+    let err = load("").health_check_url().unwrap_err();
+    assert!(err.to_string().contains("no health endpoint"));
+
+    let config = load("[metrics]\nenabled = true\nport = 9100\n");
+    assert_eq!(
+        config.health_check_url().unwrap().as_str(),
+        "http://127.0.0.1:9100/healthz"
+    );
+
+    let config = load(
+        "[metrics]\nenabled = true\nport = 9100\nbind_address = \"::\"\n\n[health]\nenabled = true\nport = 9000\n",
+    );
+    assert_eq!(
+        config.health_check_url().unwrap().as_str(),
+        "http://[::1]:9000/healthz"
+    );
+}
+
+#[test]
+fn invalid_bind_interface_regex_is_a_config_error() {
+    let err = load_with("[net]\nbind_interface = \"re:proton(\"").unwrap_err();
+    assert!(err
+        .to_string()
+        .contains("invalid net.bind_interface regex proton("));
+}
+
+#[test]
+fn reload_applies_live_settings_and_flags_the_rest() {
+    let mut config = load_with("[portmap]\nrefresh_secs = 120").expect("load config");
+
+    let fresh = load_with(
+        r#"
+[qbittorrent]
+base_url = "http://127.0.0.1:9090"
+username = "admin"
+password = "secret"
+
+[portmap]
+refresh_secs = 60
+gateway = "10.2.0.1"
+
+[net]
+bind_interface = "tun0"
+"#,
+    )
+    .expect("load config");
+
+    let diff = config.apply_reload(fresh);
+    assert_eq!(
+        diff.applied,
+        [
+            "net.bind_interface",
+            "portmap.gateway",
+            "portmap.refresh_secs"
+        ]
+    );
+    assert_eq!(diff.restart_required, ["qbittorrent.base_url"]);
+    assert_eq!(config.portmap.refresh_secs, 60);
+    assert_eq!(config.bind_interface(), Some("tun0"));
+    assert_eq!(config.qbittorrent[0].base_url, "http://127.0.0.1:8080");
+    assert_eq!(config.value_source("portmap.gateway"), ValueSource::File);
+}
+
+#[test]
+fn readiness_staleness_follows_the_scheduled_delay() {
+    let mut config = load_with("").expect("load config");
+
+    let long_lease = config.portmap_refresh_delay(Some(Duration::from_secs(7200)));
+    assert_eq!(
+        config.health_max_staleness(Some(long_lease)),
+        Some(Duration::from_secs(7200))
+    );
+    assert_eq!(config.health_max_staleness(None), None);
+
+    config.health.max_staleness_secs = Some(600);
+    assert_eq!(
+        config.health_max_staleness(Some(long_lease)),
+        Some(Duration::from_secs(600))
+    );
+}
@@ -0,0 +1,80 @@
+// Environment variables are process-wide, so everything that sets them lives
+// in this one test to keep it from racing other tests.
+use qb_port_sync::config::ValueSource;
+use qb_port_sync::Config;
+use std::io::Write;
+use std::path::Path;
+
+#[test]
+fn environment_overrides_config_values() {
+    let mut file = tempfile::NamedTempFile::new().expect("create temp config");
+    write!(
+        file,
+        r#"
+[qbittorrent]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+
+[protonvpn]
+
+[portmap]
+gateway = "10.2.0.1"
+"#
+    )
+    .expect("write temp config");
+
+    std::env::set_var("QB_PORT_SYNC_QB_USERNAME", "container");
+    std::env::set_var("QB_PORT_SYNC_QB_PASSWORD", "from-env");
+    std::env::set_var("QB_PORT_SYNC_QB_ALSO_SET_KEYS", "dht_port, announce_port");
+    std::env::set_var("QB_PORT_SYNC_PORTMAP_GATEWAY", "10.3.0.1");
+    std::env::set_var("QB_PORT_SYNC_METRICS_ENABLED", "true");
+    std::env::set_var("QB_PORT_SYNC_DAEMON_MIN_APPLY_INTERVAL_SECS", "15");
+
+    let config = Config::load(Some(file.path().to_path_buf())).expect("load config");
+    let instance = &config.qbittorrent[0];
+    assert_eq!(instance.username, "container");
+    assert_eq!(instance.also_set_keys, ["dht_port", "announce_port"]);
+    // The password in the file still wins over QB_PORT_SYNC_QB_PASSWORD.
+    assert_eq!(instance.resolved_password().unwrap(), "secret");
+    assert_eq!(config.portmap.gateway, ["10.3.0.1"]);
+    assert!(config.metrics.enabled);
+    assert_eq!(config.daemon.min_apply_interval_secs, 15);
+    assert_eq!(
+        config.value_source("qbittorrent.username"),
+        ValueSource::Env
+    );
+    assert_eq!(config.value_source("portmap.gateway"), ValueSource::Env);
+    assert_eq!(
+        config.value_source("qbittorrent.base_url"),
+        ValueSource::File
+    );
+
+    std::env::set_var("QB_PORT_SYNC_DAEMON_MIN_APPLY_INTERVAL_SECS", "soon");
+    let err = Config::load(Some(file.path().to_path_buf())).expect_err("reject bad int");
+    assert!(format!("{err:#}").contains("QB_PORT_SYNC_DAEMON_MIN_APPLY_INTERVAL_SECS"));
+    std::env::remove_var("QB_PORT_SYNC_DAEMON_MIN_APPLY_INTERVAL_SECS");
+
+    // Without any config file, base_url and username from the environment
+    // are enough to start.
+    if Path::new("/etc/qb-port-sync/config.toml").exists() {
+        return;
+    }
+    let config_home = tempfile::tempdir().expect("create config home");
+    std::env::set_var("XDG_CONFIG_HOME", config_home.path());
+    std::env::remove_var("QB_PORT_SYNC_QB_BASE_URL");
+    assert!(Config::load(None).is_err());
+
+    std::env::set_var("QB_PORT_SYNC_QB_BASE_URL", "http://qbittorrent:8080");
+    let config = Config::load(None).expect("load from environment");
+    assert!(config.source_path().is_none());
+    assert_eq!(
+        config.qbittorrent[0].resolved_base_url().unwrap().as_str(),
+        "http://qbittorrent:8080/"
+    );
+    assert_eq!(
+        config.qbittorrent[0].resolved_password().unwrap(),
+        "from-env"
+    );
+    assert_eq!(config.portmap.refresh_secs, 300);
+}
@@ -11,4 +11,5 @@ fn json_report_renders_single_line() {
     assert!(line.contains("\"strategy\":\"file\""));
     assert!(line.contains("\"detected_port\":51820"));
     assert!(!line.contains("error"));
+    assert!(!line.contains("timestamp"));
 }
@@ -0,0 +1,53 @@
+use qb_port_sync::config::ValueSource;
+use qb_port_sync::Config;
+use std::io::Write;
+
+const PROFILED_CONFIG: &str = r#"
+[qbittorrent]
+base_url = "http://127.0.0.1:8080"
+username = "admin"
+password = "secret"
+
+[protonvpn]
+
+[portmap]
+refresh_secs = 300
+gateway = "192.168.1.1"
+
+[profiles.travel.portmap]
+gateway = "10.2.0.1"
+
+[profiles.travel.net]
+bind_interface = "tun0"
+"#;
+
+fn write_config() -> tempfile::NamedTempFile {
+    let mut file = tempfile::NamedTempFile::new().expect("create temp config");
+    file.write_all(PROFILED_CONFIG.as_bytes())
+        .expect("write temp config");
+    file
+}
+
+#[test]
+fn profile_deep_merges_over_base() {
+    let file = write_config();
+    let config = Config::load_with_profile(Some(file.path().to_path_buf()), Some("travel"))
+        .expect("load travel profile");
+    assert_eq!(config.active_profile(), Some("travel"));
+    assert_eq!(config.portmap.gateway, ["10.2.0.1"]);
+    assert_eq!(config.portmap.refresh_secs, 300);
+    assert_eq!(config.bind_interface(), Some("tun0"));
+    assert_eq!(config.value_source("portmap.gateway"), ValueSource::Profile);
+    assert_eq!(
+        config.value_source("portmap.refresh_secs"),
+        ValueSource::File
+    );
+}
+
+#[test]
+fn unknown_profile_is_rejected() {
+    let file = write_config();
+    let err = Config::load_with_profile(Some(file.path().to_path_buf()), Some("office"))
+        .expect_err("unknown profile must fail");
+    assert!(err.to_string().contains("office"));
+}
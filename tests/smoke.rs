@@ -5,7 +5,67 @@ use qb_port_sync::Config;
 fn config_example_deserializes() {
     let raw = std::fs::read_to_string("config/config.example.toml").expect("read example config");
     let config: Config = toml::from_str(&raw).expect("parse config example");
-    assert_eq!(config.qbittorrent.base_url, "http://127.0.0.1:8080");
+    assert_eq!(config.qbittorrent.len(), 1);
+    assert_eq!(config.qbittorrent[0].base_url, "http://127.0.0.1:8080");
     assert_eq!(config.portmap.protocol, PortProtocol::BOTH);
     assert!(config.net.bind_interface.is_none());
 }
+
+/// Dotted paths of every key, with arrays of tables merged into one path.
+fn key_paths(value: &toml::Value, prefix: &str, out: &mut std::collections::BTreeSet<String>) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table {
+                let path = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                out.insert(path.clone());
+                key_paths(value, &path, out);
+            }
+        }
+        toml::Value::Array(items) => {
+            for item in items.iter().filter(|item| item.is_table()) {
+                key_paths(item, prefix, out);
+            }
+        }
+        _ => {}
+    }
+}
+
+#[test]
+fn dumped_schema_deserializes_and_covers_the_example() {
+    let dumped = Config::render_example().expect("render example");
+    let config: Config = toml::from_str(&dumped).expect("parse dumped schema");
+    assert_eq!(config.qbittorrent[0].base_url, "http://127.0.0.1:8080");
+    assert!(config.deluge.is_none());
+    assert!(config.post_change_hooks.is_empty());
+
+    // Past the header paragraph every comment is a commented-out setting.
+    let uncommented: String = dumped
+        .lines()
+        .skip_while(|line| !line.is_empty())
+        .map(|line| line.strip_prefix("# ").unwrap_or(line))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut schema_keys = std::collections::BTreeSet::new();
+    key_paths(
+        &toml::from_str(&uncommented).expect("parse uncommented schema"),
+        "",
+        &mut schema_keys,
+    );
+
+    let raw = std::fs::read_to_string("config/config.example.toml").expect("read example config");
+    let mut example_keys = std::collections::BTreeSet::new();
+    key_paths(
+        &toml::from_str(&raw).expect("parse config example"),
+        "",
+        &mut example_keys,
+    );
+    let missing: Vec<_> = example_keys.difference(&schema_keys).collect();
+    assert!(
+        missing.is_empty(),
+        "missing from --dump-schema: {missing:?}"
+    );
+}